@@ -0,0 +1,239 @@
+use crate::{settings::AnnouncementSettings, store::QueueStore};
+use chrono::{DateTime, Duration, Local};
+use serde::Serialize;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Announcement {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub settings: AnnouncementSettings,
+}
+
+/// The store setting announcements are saved under once they've been changed.
+const STORE_KEY: &str = "announcements";
+
+// Posting more than once a minute would only flood chat
+fn interval(settings: &AnnouncementSettings) -> Duration {
+    Duration::minutes(settings.interval_mins.max(1) as i64)
+}
+
+struct Entry {
+    announcement: Announcement,
+    next_at: DateTime<Local>,
+    /// Chat messages seen since this was last posted.
+    lines: u32,
+    /// Set once the interval has passed but not enough has been said in chat yet.
+    waiting: bool,
+}
+
+impl Entry {
+    fn new(announcement: Announcement, now: DateTime<Local>) -> Entry {
+        Entry {
+            next_at: now + interval(&announcement.settings),
+            announcement,
+            lines: 0,
+            waiting: false,
+        }
+    }
+}
+
+/// Messages posted to chat over and over, owned by the state task.
+#[derive(Default)]
+pub struct Announcements {
+    entries: Vec<Entry>,
+}
+
+impl Announcements {
+    pub fn new(settings: &[AnnouncementSettings]) -> Announcements {
+        let mut announcements = Announcements::default();
+        for settings in settings {
+            announcements.add(settings.clone());
+        }
+        announcements
+    }
+
+    /// The announcements saved in `store`, or the configured ones if they were never changed.
+    pub fn load(settings: &[AnnouncementSettings], store: &dyn QueueStore) -> Announcements {
+        let saved = store.setting(STORE_KEY).and_then(|saved| {
+            serde_json::from_str::<Vec<AnnouncementSettings>>(&saved)
+                .inspect_err(|e| tracing::warn!("Could not read the saved announcements: {}", e))
+                .ok()
+        });
+        Announcements::new(saved.as_deref().unwrap_or(settings))
+    }
+
+    /// Saves the announcements to `store`, where they take the place of the configured ones.
+    pub fn save(&self, store: &mut dyn QueueStore) {
+        let settings: Vec<&AnnouncementSettings> = self
+            .entries
+            .iter()
+            .map(|entry| &entry.announcement.settings)
+            .collect();
+        store.set_setting(STORE_KEY, &serde_json::to_string(&settings).unwrap());
+    }
+
+    pub fn list(&self) -> Vec<Announcement> {
+        self.entries
+            .iter()
+            .map(|entry| entry.announcement.clone())
+            .collect()
+    }
+
+    /// Adds an announcement, first posted one interval from now.
+    pub fn add(&mut self, settings: AnnouncementSettings) -> Uuid {
+        let id = Uuid::new_v4();
+        self.entries
+            .push(Entry::new(Announcement { id, settings }, Local::now()));
+        id
+    }
+
+    /// Replaces the announcement `id`, restarting its interval. Returns false if there is none.
+    pub fn replace(&mut self, id: Uuid, settings: AnnouncementSettings) -> bool {
+        match self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.announcement.id == id)
+        {
+            Some(entry) => {
+                *entry = Entry::new(Announcement { id, settings }, Local::now());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the announcement `id`. Returns false if there is none.
+    pub fn remove(&mut self, id: Uuid) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.announcement.id != id);
+        self.entries.len() != before
+    }
+
+    /// Counts a message in chat towards every announcement's minimum.
+    pub fn chat_line(&mut self) {
+        for entry in &mut self.entries {
+            entry.lines = entry.lines.saturating_add(1);
+        }
+    }
+
+    /// The next time an announcement is due. Ones waiting on chat are left to [`chat_line`].
+    ///
+    /// [`chat_line`]: Announcements::chat_line
+    pub fn next_deadline(&self) -> Option<DateTime<Local>> {
+        self.entries
+            .iter()
+            .filter(|entry| !entry.waiting)
+            .map(|entry| entry.next_at)
+            .min()
+    }
+
+    /// The messages that should be posted now. An announcement only for an open queue skips its
+    /// turn while the queue is closed, and one with a minimum waits until enough has been said.
+    pub fn poll(&mut self, now: DateTime<Local>, is_open: bool) -> Vec<String> {
+        let mut due = Vec::new();
+        for entry in self.entries.iter_mut().filter(|entry| entry.next_at <= now) {
+            let settings = &entry.announcement.settings;
+            let interval = interval(settings);
+            if settings.only_while_open && !is_open {
+                entry.next_at = now + interval;
+                entry.waiting = false;
+            } else if entry.lines < settings.min_lines {
+                entry.waiting = true;
+            } else {
+                due.push(settings.message.clone());
+                entry.next_at = now + interval;
+                entry.lines = 0;
+                entry.waiting = false;
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn settings(min_lines: u32, only_while_open: bool) -> AnnouncementSettings {
+        AnnouncementSettings {
+            message: "Type !join".to_owned(),
+            interval_mins: 10,
+            min_lines,
+            only_while_open,
+        }
+    }
+
+    #[test]
+    fn posts_every_interval() {
+        let mut announcements = Announcements::new(&[settings(0, false)]);
+        let first = announcements.next_deadline().unwrap();
+
+        assert!(announcements
+            .poll(first - Duration::seconds(1), true)
+            .is_empty());
+        assert_eq!(announcements.poll(first, true), ["Type !join"]);
+        assert_eq!(
+            announcements.next_deadline(),
+            Some(first + Duration::minutes(10))
+        );
+    }
+
+    #[test]
+    fn waits_for_chat_lines() {
+        let mut announcements = Announcements::new(&[settings(2, false)]);
+        let first = announcements.next_deadline().unwrap();
+
+        announcements.chat_line();
+        assert!(announcements.poll(first, true).is_empty());
+        assert_eq!(announcements.next_deadline(), None);
+
+        announcements.chat_line();
+        let later = first + Duration::minutes(3);
+        assert_eq!(announcements.poll(later, true), ["Type !join"]);
+        assert_eq!(
+            announcements.next_deadline(),
+            Some(later + Duration::minutes(10))
+        );
+    }
+
+    #[test]
+    fn skips_while_closed() {
+        let mut announcements = Announcements::new(&[settings(0, true)]);
+        let first = announcements.next_deadline().unwrap();
+
+        assert!(announcements.poll(first, false).is_empty());
+        assert_eq!(
+            announcements.next_deadline(),
+            Some(first + Duration::minutes(10))
+        );
+    }
+
+    #[test]
+    fn changes_are_saved() {
+        let mut store = MemoryStore::default();
+        let configured = [settings(0, false)];
+        assert_eq!(Announcements::load(&configured, &store).list().len(), 1);
+
+        let mut announcements = Announcements::load(&configured, &store);
+        announcements.add(settings(5, true));
+        announcements.save(&mut store);
+
+        let loaded = Announcements::load(&configured, &store).list();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[1].settings.min_lines, 5);
+    }
+
+    #[test]
+    fn manages_announcements() {
+        let mut announcements = Announcements::default();
+        let id = announcements.add(settings(0, false));
+
+        assert!(announcements.replace(id, settings(5, true)));
+        assert_eq!(announcements.list()[0].settings.min_lines, 5);
+        assert!(announcements.remove(id));
+        assert!(!announcements.remove(id));
+        assert!(announcements.list().is_empty());
+    }
+}
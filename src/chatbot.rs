@@ -1,16 +1,41 @@
-use crate::{StateCommand, StateTx, Token};
+use crate::{
+    client::{ClientError, QueueClient},
+    cooldown::Cooldowns,
+    custom_commands::{CustomCommandError, WriteMode},
+    metrics,
+    settings::{CommandSettings, ResponseMode},
+    templates::Templates,
+    JoinError, Token, UserInfo,
+};
 use async_trait::async_trait;
-use futures::prelude::*;
-mod irc {
-    pub use irc::client::prelude::*;
-    pub use irc::error::*;
-}
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::time::Instant;
+use tracing::Instrument;
+
+pub mod context;
+pub mod discord;
+pub mod memory;
+pub mod outbound;
+pub mod transport;
+pub mod twitch;
+pub mod whisper;
+use context::Context;
+use outbound::{Delivery, Outbound, Priority, Writer};
+use transport::{ChatEvent, ChatTransport, IncomingMessage};
 
 #[derive(Debug)]
 pub enum Commands {
     SendMessage(String),
     Token(Token),
+    GetCommands(tokio::sync::oneshot::Sender<Vec<CommandInfo>>),
+    /// Registers a custom command, or unregisters it when there is no response.
+    CustomCommand {
+        name: String,
+        response: Option<String>,
+    },
 }
 
 pub type Tx = tokio::sync::mpsc::Sender<Commands>;
@@ -19,29 +44,84 @@ pub type Rx = tokio::sync::mpsc::Receiver<Commands>;
 pub struct Message<'a> {
     pub target: &'a str,
     pub sender: &'a str,
+    /// How to show the sender in chat, when `sender` is only an id.
+    pub display_name: Option<&'a str>,
     pub message: &'a str,
+    /// Twitch's id for this message, which replies can thread under.
+    pub id: Option<&'a str>,
+    /// Twitch's id for the sender, which whispers are addressed to.
+    pub sender_id: Option<&'a str>,
+    pub role: Role,
+}
+
+/// Who may use a command. Each role may also use the commands of the roles before it.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Everyone,
+    /// Moderators and the broadcaster.
+    Moderator,
 }
 
-pub struct Args<'a> {
-    pub msg: Message<'a>,
-    pub writer: &'a irc::Sender,
-    pub rx: &'a Rx,
-    pub state_tx: &'a StateTx,
+/// A command as viewers see it, for `!help` and the dashboard.
+#[derive(Clone, Debug, Serialize)]
+pub struct CommandInfo {
+    /// The name the command was registered with.
+    pub name: String,
+    /// The name with the bot's first prefix, e.g. `!join`.
+    pub command: String,
+    /// Other names for the command, with the prefix.
+    pub aliases: Vec<String>,
+    /// How to use the command, e.g. `!setign <in-game name>`.
+    pub usage: String,
+    pub description: String,
+    pub role: Role,
+    /// Whether the command was added at runtime rather than built in.
+    pub custom: bool,
+}
+
+impl CommandInfo {
+    /// Whether `name`, without a prefix, invokes this command.
+    pub fn is_called(&self, name: &str) -> bool {
+        // Aliases carry the same prefix as the command
+        let prefix = self.command.len() - self.name.len();
+        self.name == name
+            || self
+                .aliases
+                .iter()
+                .any(|alias| alias.get(prefix..) == Some(name))
+    }
 }
 
 #[async_trait]
 pub trait Handler: Send + Sync {
-    async fn handle(&mut self, args: Args<'_>);
+    async fn handle(&self, ctx: Context<'_>) -> anyhow::Result<()>;
+
+    /// What the command does, in the language of `templates`.
+    fn description<'a>(&'a self, _templates: &'a Templates) -> &'a str {
+        ""
+    }
+
+    /// The arguments the command takes, like `<name>` or `[count]`.
+    fn usage(&self) -> &str {
+        ""
+    }
+
+    /// Who may use the command.
+    fn role(&self) -> Role {
+        Role::Everyone
+    }
 }
 
 #[async_trait]
 impl<F> Handler for F
 where
-    F: Fn(Args<'_>),
+    F: Fn(Context<'_>),
     F: Send + Sync,
 {
-    async fn handle(&mut self, args: Args<'_>) {
-        (self)(args)
+    async fn handle(&self, ctx: Context<'_>) -> anyhow::Result<()> {
+        (self)(ctx);
+        Ok(())
     }
 }
 
@@ -52,95 +132,267 @@ struct QueuePos<'a> {
     wait_per_group: usize, // TODO this should be a chrono::Duration
 }
 
-impl std::fmt::Display for QueuePos<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl QueuePos<'_> {
+    fn render(&self, templates: &Templates) -> String {
+        let user = self.user_nickname;
         match self.index {
             Some(index) => {
                 let leading_groups = index / self.group_size;
                 let wait_time = leading_groups * self.wait_per_group;
-                let leading_groups_preamble = match leading_groups {
-                    0 => "You're on deck".to_owned(),
-                    _ => format!("There are {} groups ahead of you", leading_groups),
+                let ahead = match leading_groups {
+                    0 => templates.render(&templates.on_deck, &[("user", &user)]),
+                    _ => templates.render(
+                        &templates.groups_ahead,
+                        &[("user", &user), ("groups", &leading_groups)],
+                    ),
                 };
-                write!(
-                    f,
-                    "{} is #{}. {}, wait time is approximately {}-{} minutes",
-                    self.user_nickname,
-                    index + 1,
-                    leading_groups_preamble,
-                    wait_time,
-                    wait_time + 5
+                templates.render(
+                    &templates.place,
+                    &[
+                        ("user", &user),
+                        ("position", &(index + 1)),
+                        ("ahead", &ahead),
+                        ("wait", &wait_time),
+                        ("wait_max", &(wait_time + 5)),
+                    ],
                 )
             }
-            None => {
-                write!(f, "{} is not in the queue", self.user_nickname)
-            }
+            None => templates.render(&templates.not_in_queue, &[("user", &user)]),
         }
     }
 }
 
+/// The commands a bot answers to and the names they go by.
+pub struct Registry {
+    handlers: HashMap<String, Box<dyn Handler>>,
+    /// Alternative names for commands, mapped to the name they were registered with.
+    aliases: HashMap<String, String>,
+    /// What a chat message must start with to be treated as a command. The first is the one shown
+    /// to viewers.
+    prefixes: Vec<String>,
+    /// The commands that were added at runtime.
+    custom: HashSet<String>,
+}
+
+impl Registry {
+    /// The prefix commands are shown with.
+    pub fn prefix(&self) -> &str {
+        self.prefixes.first().map_or("", String::as_str)
+    }
+
+    /// Whether `name` is a built-in command or an alias, which custom commands can't replace.
+    pub fn is_taken(&self, name: &str) -> bool {
+        self.aliases.contains_key(name)
+            || (self.handlers.contains_key(name) && !self.custom.contains(name))
+    }
+
+    /// The command registered as `name` as viewers see it, described in the language of
+    /// `templates`.
+    pub fn info(&self, name: &str, templates: &Templates) -> Option<CommandInfo> {
+        let handler = self.handlers.get(name)?;
+        let command = format!("{}{}", self.prefix(), name);
+        let mut aliases: Vec<String> = self
+            .aliases
+            .iter()
+            .filter(|(_, target)| *target == name)
+            .map(|(alias, _)| format!("{}{}", self.prefix(), alias))
+            .collect();
+        aliases.sort();
+        Some(CommandInfo {
+            name: name.to_owned(),
+            usage: format!("{} {}", command, handler.usage())
+                .trim_end()
+                .to_owned(),
+            command,
+            aliases,
+            description: handler.description(templates).to_owned(),
+            role: handler.role(),
+            custom: self.custom.contains(name),
+        })
+    }
+
+    /// Every registered command, sorted by name.
+    pub fn command_info(&self, templates: &Templates) -> Vec<CommandInfo> {
+        let mut info: Vec<CommandInfo> = self
+            .handlers
+            .keys()
+            .filter_map(|name| self.info(name, templates))
+            .collect();
+        info.sort_by(|a, b| a.name.cmp(&b.name));
+        info
+    }
+}
+
 pub struct Bot {
-    channel: String,
-    client: irc::Client,
-    commands: HashMap<String, Box<dyn Handler>>,
+    transport: Box<dyn ChatTransport>,
+    registry: Registry,
+    cooldowns: Cooldowns,
+    /// How commands respond, for those that don't just respond in the channel.
+    responses: HashMap<String, ResponseMode>,
+    outbound: Mutex<Outbound>,
     rx: Rx,
 }
 
 impl Bot {
-    pub async fn new(user_config: irc::Config, rx: Rx) -> Result<Bot, irc::Error> {
-        let channel = user_config.channels.iter().take(1).cloned().collect();
-        let client = irc::Client::from_config(user_config).await?;
-        client.identify()?;
-        Ok(Bot {
-            channel,
-            client,
-            commands: HashMap::new(),
+    pub fn new(transport: impl ChatTransport + 'static, rx: Rx) -> Bot {
+        let outbound = Outbound::new(transport.limits());
+        Bot {
+            transport: Box::new(transport),
+            registry: Registry {
+                handlers: HashMap::new(),
+                aliases: HashMap::new(),
+                prefixes: vec!["!".to_owned()],
+                custom: HashSet::new(),
+            },
+            cooldowns: Cooldowns::default(),
+            responses: HashMap::new(),
+            outbound: Mutex::new(outbound),
             rx,
-        })
+        }
     }
 
     // add this command to the bot
     pub fn with_command(&mut self, name: impl Into<String>, cmd: impl Handler + 'static) {
-        self.commands.insert(name.into(), Box::new(cmd));
+        self.registry
+            .handlers
+            .insert(name.into().to_lowercase(), Box::new(cmd));
+    }
+
+    // let `alias` invoke the command registered as `name`
+    pub fn with_alias(&mut self, alias: impl Into<String>, name: impl Into<String>) {
+        self.registry
+            .aliases
+            .insert(alias.into().to_lowercase(), name.into().to_lowercase());
+    }
+
+    pub fn with_prefixes(&mut self, mut prefixes: Vec<String>) {
+        prefixes.retain(|prefix| !prefix.is_empty());
+        self.registry.prefixes = prefixes;
+    }
+
+    /// Every registered command, described in the language of the bot's channel.
+    fn command_info<'a>(
+        &'a self,
+        client: &'a QueueClient,
+    ) -> impl Future<Output = Result<Vec<CommandInfo>, ClientError>> + 'a {
+        // Doesn't hold on to the bot, as the transport can't be shared between threads
+        let (registry, channel) = (&self.registry, self.transport.channel());
+        async move {
+            let templates = client.templates(Some(channel)).await?;
+            Ok(registry.command_info(&templates))
+        }
+    }
+
+    // add or, without a response, remove a custom command. Built in commands and aliases can't
+    // be replaced.
+    fn register_custom(&mut self, name: String, response: Option<String>) {
+        let registry = &mut self.registry;
+        match response {
+            Some(response) => {
+                if registry.is_taken(&name) {
+                    tracing::warn!("Not adding custom command {}, the name is taken", name);
+                    return;
+                }
+                registry
+                    .handlers
+                    .insert(name.clone(), Box::new(CustomCommand { response }));
+                registry.custom.insert(name);
+            }
+            None => {
+                if registry.custom.remove(&name) {
+                    registry.handlers.remove(&name);
+                }
+            }
+        }
     }
 
     // run the bot until its done
-    pub async fn run(&mut self, tx: StateTx) -> anyhow::Result<()> {
-        tracing::debug!("starting main loop");
-        let mut stream = self.client.stream()?;
-        let sender = self.client.sender();
+    pub async fn run(&mut self, client: QueueClient) -> anyhow::Result<()> {
+        match client.custom_commands().await {
+            Ok(custom) => {
+                for (name, response) in custom {
+                    self.register_custom(name, Some(response));
+                }
+            }
+            Err(e) => tracing::warn!("Could not load the custom commands: {}", e),
+        }
+        let registered = match self.command_info(&client).await {
+            Ok(commands) => self.transport.set_commands(&commands).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = registered {
+            tracing::warn!("Could not register the platform's command list: {}", e);
+        }
 
+        tracing::debug!("starting main loop");
         loop {
+            let next_send = self.outbound.lock().unwrap().next_send(Instant::now());
             tokio::select! {
-                // Oh wow that Option<Result<_>> nesting is pretty gnarly
-                Some(Ok(message)) = stream.next() => {
-                    tracing::debug!("{}", message);
-                    if let irc::Command::PRIVMSG(ref _target, ref msg) = message.command {
-                        // see if its a command and do stuff with it
-                        if let Some(cmd) = Self::parse_command(msg) {
-                            if let Some(command) = self.commands.get_mut(cmd) {
-                                tracing::trace!("dispatching to: {}", cmd.escape_debug());
-
-                                let args = Args {
-                                    msg: Message {
-                                        target: message.response_target().unwrap(),
-                                        sender: message.source_nickname().unwrap(),
-                                        message: msg,
-                                    },
-                                    writer: &sender,
-                                    rx: &self.rx,
-                                    state_tx: &tx,
-                                };
-
-                                command.handle(args).await;
+                Some(event) = self.transport.recv() => match event {
+                    ChatEvent::Message(message) => {
+                        if let Err(e) = client.chat_line() {
+                            tracing::debug!("Not counting a chat line towards announcements: {}", e);
+                        }
+                        self.dispatch(message, &client).await;
+                    }
+                    ChatEvent::Joined(users) => {
+                        if let Err(e) = client.users_joined(users) {
+                            tracing::warn!("Dropped who joined the channel: {}", e);
+                        }
+                    }
+                    ChatEvent::Parted(user) => {
+                        if let Err(e) = client.user_parted(user) {
+                            tracing::warn!("Dropped who left the channel: {}", e);
+                        }
+                    }
+                    ChatEvent::BotIsModerator(moderator) => {
+                        self.outbound.lock().unwrap().set_moderator(moderator);
+                    }
+                    ChatEvent::NotWhispered(pending) => {
+                        self.outbound.lock().unwrap().push_in_channel(pending);
+                    }
+                    ChatEvent::WrongChannel { reply_to, channel } => {
+                        let target = self.transport.channel().to_owned();
+                        match client.templates(Some(&target)).await {
+                            Ok(templates) => {
+                                let text = templates.render(&templates.wrong_channel, &[("channel", &channel)]);
+                                self.outbound.lock().unwrap().push(&target, text, Priority::High, Delivery::Reply(reply_to));
                             }
+                            Err(e) => tracing::warn!("Could not say where commands work: {}", e),
                         }
                     }
                 },
                 Some(command) = self.rx.recv() => {
                     match command {
-                        Commands::SendMessage(message) => {sender.send_privmsg(&self.channel, &message).unwrap();},
+                        Commands::SendMessage(message) => {
+                            let channel = self.transport.channel();
+                            self.outbound.lock().unwrap().push(channel, message, Priority::High, Delivery::Channel);
+                        }
                         Commands::Token(_) => tracing::debug!("Already handled token"),
+                        Commands::GetCommands(tx) => match self.command_info(&client).await {
+                            Ok(commands) => {
+                                let _ = tx.send(commands);
+                            }
+                            Err(e) => tracing::warn!("Could not list the commands: {}", e),
+                        },
+                        Commands::CustomCommand { name, response } => {
+                            self.register_custom(name, response);
+                            let updated = match self.command_info(&client).await {
+                                Ok(commands) => self.transport.set_commands(&commands).await,
+                                Err(e) => Err(e.into()),
+                            };
+                            if let Err(e) = updated {
+                                tracing::warn!("Could not update the platform's command list: {}", e);
+                            }
+                        }
+                    }
+                }
+                _ = outbound::sleep_until(next_send), if next_send.is_some() => {
+                    let pending = self.outbound.lock().unwrap().pop(Instant::now());
+                    if let Some(pending) = pending {
+                        if let Err(e) = self.transport.send(pending).await {
+                            tracing::warn!("Could not send to chat: {}", e);
+                        }
                     }
                 }
                 else => break,
@@ -151,11 +403,97 @@ impl Bot {
         Ok(())
     }
 
-    pub fn parse_command(input: &str) -> Option<&str> {
-        if !input.starts_with('!') {
-            return None;
+    // see if a chat message is a command and do stuff with it
+    async fn dispatch(&mut self, message: IncomingMessage, client: &QueueClient) {
+        let cmd = match Self::parse_command(&self.registry.prefixes, &message.text) {
+            Some(cmd) => cmd,
+            None => return,
+        };
+        let cmd = self.registry.aliases.get(&cmd).cloned().unwrap_or(cmd);
+        let command = match self.registry.handlers.get(&cmd) {
+            Some(command) => command,
+            None => return,
+        };
+
+        let moderator = message.role >= Role::Moderator;
+        if command.role() > message.role {
+            return;
+        }
+        if !moderator
+            && !self
+                .cooldowns
+                .try_use(&cmd, &message.sender, chrono::Local::now())
+        {
+            tracing::trace!(
+                "{} is cooling down for {}",
+                cmd.escape_debug(),
+                message.sender
+            );
+            return;
+        }
+        tracing::trace!("dispatching to: {}", cmd.escape_debug());
+        metrics::command_run(&cmd);
+
+        let delivery = match self.responses.get(&cmd) {
+            Some(ResponseMode::Whisper) => message.sender_id.clone().map(Delivery::Whisper),
+            Some(ResponseMode::Reply) => message.id.clone().map(Delivery::Reply),
+            _ if message.must_reply => message.id.clone().map(Delivery::Reply),
+            _ => None,
+        };
+        let ctx = Context::new(
+            Message {
+                target: &message.channel,
+                sender: &message.sender,
+                display_name: message.display_name.as_deref(),
+                message: &message.text,
+                id: message.id.as_deref(),
+                sender_id: message.sender_id.as_deref(),
+                role: message.role,
+            },
+            Writer::new(
+                &self.outbound,
+                if moderator {
+                    Priority::High
+                } else {
+                    Priority::Normal
+                },
+                delivery.unwrap_or(Delivery::Channel),
+            ),
+            client,
+            &self.registry,
+            &cmd,
+        );
+
+        let span = tracing::info_span!(
+            "chat command",
+            command = %cmd,
+            user = %message.sender,
+            channel = %message.channel,
+        );
+        if let Err(e) = command.handle(ctx).instrument(span).await {
+            tracing::warn!("{} failed: {}", cmd.escape_debug(), e);
         }
-        input.splitn(2, ' ').next()
+    }
+
+    /// The lowercased name of the command `input` invokes, without its prefix. The longest
+    /// matching prefix wins.
+    pub fn parse_command(prefixes: &[String], input: &str) -> Option<String> {
+        let rest = prefixes
+            .iter()
+            .filter_map(|prefix| input.strip_prefix(prefix.as_str()))
+            .min_by_key(|rest| rest.len())?;
+        rest.split(' ')
+            .next()
+            .filter(|name| !name.is_empty())
+            .map(str::to_lowercase)
+    }
+
+    /// Everything after the command name, if anything was given.
+    pub fn parse_argument(input: &str) -> Option<&str> {
+        input
+            .split_once(' ')
+            .map(|(_, rest)| rest.trim())
+            .filter(|rest| !rest.is_empty())
     }
 }
 
@@ -165,32 +503,30 @@ struct Peek;
 
 #[async_trait]
 impl Handler for Peek {
-    async fn handle(&mut self, args: Args<'_>) {
-        let (state_tx, state_rx) = tokio::sync::oneshot::channel();
-        args.state_tx
-            .send(StateCommand::PeekQueue {
-                count: 4,
-                tx: state_tx,
-            })
-            .await
-            .unwrap();
-
-        let first_n: Vec<String> = state_rx
-            .await
-            .unwrap()
+    async fn handle(&self, ctx: Context<'_>) -> anyhow::Result<()> {
+        let first_n: Vec<String> = ctx
+            .state()
+            .peek(4)
+            .await?
             .into_iter()
-            .map(|u| u.nickname)
+            .map(|u| u.name().to_owned())
             .collect();
 
+        let templates = ctx.templates().await?;
         if !first_n.is_empty() {
-            args.writer
-                .send_privmsg(args.msg.target, &first_n.join(", "))
-                .unwrap();
+            let message = templates.render(
+                &templates.peek,
+                &[("users", &first_n.join(", ")), ("count", &first_n.len())],
+            );
+            ctx.reply(message);
         } else {
-            args.writer
-                .send_privmsg(args.msg.target, "The queue is empty")
-                .unwrap();
+            ctx.reply(&templates.peek_empty);
         }
+        Ok(())
+    }
+
+    fn description<'a>(&'a self, templates: &'a Templates) -> &'a str {
+        &templates.about_next
     }
 }
 
@@ -198,38 +534,49 @@ struct Join;
 
 #[async_trait]
 impl Handler for Join {
-    async fn handle(&mut self, args: Args<'_>) {
-        let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
-        args.state_tx
-            .send(StateCommand::GetQueueStatus(resp_tx))
-            .await
-            .unwrap();
-        let status = resp_rx.await.unwrap();
-
-        if !status {
-            return;
+    async fn handle(&self, ctx: Context<'_>) -> anyhow::Result<()> {
+        let templates = ctx.templates().await?;
+        let ign = ctx.argument().map(str::to_owned);
+        if ign.as_ref().map_or(0, |ign| ign.chars().count()) > MAX_INFO_LEN {
+            ctx.reply(templates.render(
+                &templates.info_too_long,
+                &[
+                    ("user", &ctx.name()),
+                    ("field", &templates.field_ign),
+                    ("max", &MAX_INFO_LEN),
+                ],
+            ));
+            return Ok(());
         }
-
-        let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
-        args.state_tx
-            .send(StateCommand::AddUser {
-                user: args.msg.sender.to_string(),
-                tx: resp_tx,
-            })
-            .await
-            .unwrap();
-        let index = resp_rx.await.unwrap();
+        let result = ctx
+            .state()
+            .add_user(ctx.sender(), ctx.msg().display_name, ign)
+            .await?;
+        let index = match result {
+            Ok(index) => index,
+            Err(JoinError::Full) => {
+                ctx.reply(templates.render(&templates.queue_full, &[("user", &ctx.name())]));
+                return Ok(());
+            }
+            Err(JoinError::Closed) | Err(JoinError::Banned) => return Ok(()),
+        };
 
         let queue_pos = QueuePos {
             index: Some(index),
-            user_nickname: args.msg.sender,
+            user_nickname: ctx.name(),
             group_size: 4,
             wait_per_group: 5,
         };
+        ctx.reply(queue_pos.render(&templates));
+        Ok(())
+    }
 
-        args.writer
-            .send_privmsg(&args.msg.target, &format!("{}", queue_pos))
-            .unwrap();
+    fn description<'a>(&'a self, templates: &'a Templates) -> &'a str {
+        &templates.about_join
+    }
+
+    fn usage(&self) -> &str {
+        "[in-game name]"
     }
 }
 
@@ -237,56 +584,541 @@ struct Place;
 
 #[async_trait]
 impl Handler for Place {
-    async fn handle(&mut self, args: Args<'_>) {
-        let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
-        args.state_tx
-            .send(StateCommand::FindUser {
-                name: args.msg.sender.to_string(),
-                tx: resp_tx,
-            })
-            .await
-            .unwrap();
-        let index = resp_rx.await.unwrap();
+    async fn handle(&self, ctx: Context<'_>) -> anyhow::Result<()> {
+        let index = ctx.state().find(ctx.sender()).await?;
+        let templates = ctx.templates().await?;
 
         let queue_pos = QueuePos {
             index,
-            user_nickname: args.msg.sender,
+            user_nickname: ctx.name(),
             group_size: 4,
             wait_per_group: 5,
         };
-        args.writer
-            .send_privmsg(args.msg.target, &format!("{}", queue_pos))
-            .unwrap();
+        ctx.reply(queue_pos.render(&templates));
+        Ok(())
+    }
+
+    fn description<'a>(&'a self, templates: &'a Templates) -> &'a str {
+        &templates.about_place
     }
 }
 
 struct Leave;
 #[async_trait]
 impl Handler for Leave {
-    async fn handle(&mut self, args: Args<'_>) {
-        let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
-        args.state_tx
-            .send(StateCommand::RemoveUser {
-                user: args.msg.sender.to_string(),
-                tx: resp_tx,
-            })
-            .await
-            .unwrap();
-        let user = resp_rx.await.unwrap();
-        if user.is_some() {
-            args.writer
-                .send_privmsg(
-                    args.msg.target,
-                    &format!("{} has been removed from the queue.", args.msg.sender),
-                )
-                .unwrap();
+    async fn handle(&self, ctx: Context<'_>) -> anyhow::Result<()> {
+        if ctx.state().remove(ctx.sender()).await?.is_some() {
+            let templates = ctx.templates().await?;
+            ctx.reply(templates.render(&templates.leave, &[("user", &ctx.name())]));
+        }
+        Ok(())
+    }
+
+    fn description<'a>(&'a self, templates: &'a Templates) -> &'a str {
+        &templates.about_leave
+    }
+}
+
+const MAX_INFO_LEN: usize = 64;
+
+/// Sets a field on the sender's queue entry from the command's argument.
+enum SetInfo {
+    Ign,
+    Note,
+}
+
+impl SetInfo {
+    fn name<'a>(&self, templates: &'a Templates) -> &'a str {
+        match self {
+            SetInfo::Ign => &templates.field_ign,
+            SetInfo::Note => &templates.field_note,
+        }
+    }
+
+    fn info(&self, value: String) -> UserInfo {
+        match self {
+            SetInfo::Ign => UserInfo {
+                ign: Some(value),
+                ..Default::default()
+            },
+            SetInfo::Note => UserInfo {
+                note: Some(value),
+                ..Default::default()
+            },
         }
     }
 }
 
-pub fn build_bot(bot: &mut Bot) {
-    bot.with_command("!join", Join {});
-    bot.with_command("!next", Peek {});
-    bot.with_command("!place", Place {});
-    bot.with_command("!leave", Leave {});
+#[async_trait]
+impl Handler for SetInfo {
+    async fn handle(&self, ctx: Context<'_>) -> anyhow::Result<()> {
+        let value = match ctx.args::<String>().await? {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+        let templates = ctx.templates().await?;
+        let field = self.name(&templates);
+        let user = ctx.name();
+        if value.chars().count() > MAX_INFO_LEN {
+            ctx.reply(templates.render(
+                &templates.info_too_long,
+                &[("user", &user), ("field", &field), ("max", &MAX_INFO_LEN)],
+            ));
+            return Ok(());
+        }
+
+        let reply = if ctx
+            .state()
+            .set_user_info(ctx.sender(), self.info(value))
+            .await?
+        {
+            templates.render(
+                &templates.info_updated,
+                &[("user", &user), ("field", &field)],
+            )
+        } else {
+            templates.render(&templates.not_in_queue, &[("user", &user)])
+        };
+        ctx.reply(reply);
+        Ok(())
+    }
+
+    fn description<'a>(&'a self, templates: &'a Templates) -> &'a str {
+        match self {
+            SetInfo::Ign => &templates.about_setign,
+            SetInfo::Note => &templates.about_note,
+        }
+    }
+
+    fn usage(&self) -> &str {
+        match self {
+            SetInfo::Ign => "<in-game name>",
+            SetInfo::Note => "<text>",
+        }
+    }
+}
+
+struct Here;
+
+#[async_trait]
+impl Handler for Here {
+    async fn handle(&self, ctx: Context<'_>) -> anyhow::Result<()> {
+        if ctx.state().mark_here(ctx.sender()).await? {
+            tracing::debug!("{} is here", ctx.sender());
+        }
+        Ok(())
+    }
+
+    fn description<'a>(&'a self, templates: &'a Templates) -> &'a str {
+        &templates.about_here
+    }
+}
+
+struct Help;
+
+#[async_trait]
+impl Handler for Help {
+    async fn handle(&self, ctx: Context<'_>) -> anyhow::Result<()> {
+        let templates = ctx.templates().await?;
+        let commands = ctx.commands(&templates);
+        let mut available = commands.iter().filter(|command| command.role <= ctx.role());
+        let message = match ctx.argument() {
+            Some(topic) => {
+                let topic = topic
+                    .trim_start_matches(|c: char| !c.is_alphanumeric())
+                    .to_lowercase();
+                match available.find(|command| command.is_called(&topic)) {
+                    Some(command) => templates.render(
+                        &templates.help_command,
+                        &[
+                            ("usage", &command.usage),
+                            ("description", &command.description),
+                        ],
+                    ),
+                    None => templates.render(&templates.help_unknown, &[("command", &topic)]),
+                }
+            }
+            None => {
+                let commands: Vec<&str> =
+                    available.map(|command| command.command.as_str()).collect();
+                templates.render(&templates.help, &[("commands", &commands.join(", "))])
+            }
+        };
+        ctx.reply(message);
+        Ok(())
+    }
+
+    fn description<'a>(&'a self, templates: &'a Templates) -> &'a str {
+        &templates.about_help
+    }
+
+    fn usage(&self) -> &str {
+        "[command]"
+    }
+}
+
+/// A command added at runtime, which replies with its response.
+struct CustomCommand {
+    response: String,
+}
+
+#[async_trait]
+impl Handler for CustomCommand {
+    async fn handle(&self, ctx: Context<'_>) -> anyhow::Result<()> {
+        let queue_length = ctx.state().queue_length().await?;
+        let templates = ctx.templates().await?;
+        ctx.reply(templates.render(
+            &self.response,
+            &[
+                ("user", &ctx.name()),
+                ("args", &ctx.argument().unwrap_or_default()),
+                ("queue_length", &queue_length),
+            ],
+        ));
+        Ok(())
+    }
+
+    fn description<'a>(&'a self, _templates: &'a Templates) -> &'a str {
+        &self.response
+    }
+}
+
+/// Lets moderators add, change and delete custom commands from chat.
+enum ManageCommand {
+    Add,
+    Edit,
+    Delete,
+}
+
+#[async_trait]
+impl Handler for ManageCommand {
+    async fn handle(&self, ctx: Context<'_>) -> anyhow::Result<()> {
+        // Deleting only needs a name, the others need a response as well
+        let args = match self {
+            ManageCommand::Delete => ctx.args::<String>().await?.map(|name| (name, None)),
+            _ => ctx
+                .args::<(String, String)>()
+                .await?
+                .map(|(name, response)| (name, Some(response))),
+        };
+        let (name, response) = match args {
+            Some(args) => args,
+            None => return Ok(()),
+        };
+        let name = name
+            .trim_start_matches(|c: char| !c.is_alphanumeric())
+            .to_lowercase();
+        if name.is_empty() {
+            ctx.reply_usage().await?;
+            return Ok(());
+        }
+        let command = format!("{}{}", ctx.prefix(), name);
+
+        let result = match (ctx.is_taken(&name), &*self, response) {
+            (true, ManageCommand::Add, _) => Err(CustomCommandError::Exists(name)),
+            (true, _, _) => Err(CustomCommandError::NotFound(name)),
+            (false, ManageCommand::Add, Some(response)) => {
+                ctx.state()
+                    .set_custom_command(name, response, WriteMode::Create)
+                    .await?
+            }
+            (false, ManageCommand::Edit, Some(response)) => {
+                ctx.state()
+                    .set_custom_command(name, response, WriteMode::Replace)
+                    .await?
+            }
+            (false, _, _) => ctx.state().delete_custom_command(name).await?,
+        };
+
+        let templates = ctx.templates().await?;
+        let values: &[(&str, &(dyn std::fmt::Display + Sync))] = &[("command", &command)];
+        let message = match result {
+            Ok(()) => templates.render(
+                match self {
+                    ManageCommand::Add => &templates.command_added,
+                    ManageCommand::Edit => &templates.command_updated,
+                    ManageCommand::Delete => &templates.command_deleted,
+                },
+                values,
+            ),
+            Err(CustomCommandError::Exists(_)) => {
+                templates.render(&templates.command_exists, values)
+            }
+            Err(CustomCommandError::NotFound(_)) => {
+                templates.render(&templates.help_unknown, values)
+            }
+            Err(e) => templates.render(
+                &templates.command_invalid,
+                &[("command", &command), ("error", &e.to_string())],
+            ),
+        };
+        ctx.reply(message);
+        Ok(())
+    }
+
+    fn description<'a>(&'a self, templates: &'a Templates) -> &'a str {
+        match self {
+            ManageCommand::Add => &templates.about_addcmd,
+            ManageCommand::Edit => &templates.about_editcmd,
+            ManageCommand::Delete => &templates.about_delcmd,
+        }
+    }
+
+    fn usage(&self) -> &str {
+        match self {
+            ManageCommand::Add | ManageCommand::Edit => "<name> <response>",
+            ManageCommand::Delete => "<name>",
+        }
+    }
+
+    fn role(&self) -> Role {
+        Role::Moderator
+    }
+}
+
+struct QueueStats;
+
+#[async_trait]
+impl Handler for QueueStats {
+    async fn handle(&self, ctx: Context<'_>) -> anyhow::Result<()> {
+        let stats = ctx.state().stats().await?;
+        let templates = ctx.templates().await?;
+        // Waits are shown in whole minutes
+        let minutes = |secs: Option<i64>| (secs.unwrap_or_default() + 30) / 60;
+        let message = templates.render(
+            &templates.stats,
+            &[
+                ("joins", &stats.total_joins),
+                ("users", &stats.unique_users),
+                ("rate", &format!("{:.1}", stats.joins_per_minute)),
+                ("average", &minutes(stats.average_wait_secs)),
+                ("median", &minutes(stats.median_wait_secs)),
+                ("max", &minutes(stats.max_wait_secs)),
+                ("abandoned", &(stats.abandonment_rate * 100.0).round()),
+                ("peak", &stats.peak_queue_length),
+            ],
+        );
+        ctx.reply(message);
+        Ok(())
+    }
+
+    fn description<'a>(&'a self, templates: &'a Templates) -> &'a str {
+        &templates.about_qstats
+    }
+
+    fn role(&self) -> Role {
+        Role::Moderator
+    }
+}
+
+/// Passes what the rest of the app tells the bot on to `primary`, while copying announcements and
+/// custom command changes to `others`, so several chats can share one queue.
+pub async fn relay(mut rx: Rx, primary: Tx, others: Vec<Tx>) {
+    while let Some(command) = rx.recv().await {
+        for other in &others {
+            let copy = match &command {
+                Commands::SendMessage(message) => Commands::SendMessage(message.clone()),
+                Commands::CustomCommand { name, response } => Commands::CustomCommand {
+                    name: name.clone(),
+                    response: response.clone(),
+                },
+                _ => continue,
+            };
+            if other.send(copy).await.is_err() {
+                tracing::warn!("A bot stopped, not relaying to it");
+            }
+        }
+        if primary.send(command).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn builtins() -> Vec<(&'static str, Box<dyn Handler>)> {
+    vec![
+        ("join", Box::new(Join {})),
+        ("next", Box::new(Peek {})),
+        ("place", Box::new(Place {})),
+        ("leave", Box::new(Leave {})),
+        ("here", Box::new(Here {})),
+        ("setign", Box::new(SetInfo::Ign)),
+        ("note", Box::new(SetInfo::Note)),
+        ("help", Box::new(Help {})),
+        ("addcmd", Box::new(ManageCommand::Add)),
+        ("editcmd", Box::new(ManageCommand::Edit)),
+        ("delcmd", Box::new(ManageCommand::Delete)),
+        ("qstats", Box::new(QueueStats {})),
+    ]
+}
+
+/// The names custom commands can't take: the built-in commands `settings` leaves on, and their
+/// aliases.
+pub fn reserved_names(settings: &CommandSettings) -> HashSet<String> {
+    let builtins = builtins()
+        .into_iter()
+        .map(|(name, _)| name)
+        .filter(|name| !settings.is_disabled(name))
+        .map(str::to_owned);
+    let aliases = settings
+        .aliases
+        .values()
+        .flatten()
+        .map(|alias| alias.to_lowercase());
+    builtins.chain(aliases).collect()
+}
+
+pub fn build_bot(bot: &mut Bot, settings: &CommandSettings) {
+    for (name, handler) in builtins() {
+        if !settings.is_disabled(name) {
+            bot.registry.handlers.insert(name.to_owned(), handler);
+        }
+    }
+
+    bot.with_prefixes(settings.prefixes.clone());
+    bot.cooldowns = Cooldowns::new(&settings.cooldowns);
+    bot.responses = settings
+        .responses
+        .iter()
+        .map(|(name, mode)| (name.to_lowercase(), *mode))
+        .collect();
+    for (name, aliases) in &settings.aliases {
+        for alias in aliases {
+            bot.with_alias(alias, name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        build_bot,
+        memory::{MemoryChat, MemoryTransport},
+        outbound::Delivery,
+        transport::{ChatEvent, IncomingMessage},
+        Bot, Role,
+    };
+    use crate::{
+        client::QueueClient,
+        init_state,
+        settings::{CommandSettings, Settings},
+    };
+
+    async fn start_bot(settings: Settings) -> MemoryChat {
+        let commands = settings.commands.clone();
+        let (state_tx, state_rx) = tokio::sync::mpsc::channel(8);
+        let (chat_tx, chat_rx) = tokio::sync::mpsc::channel(8);
+        let _state = init_state(state_rx, chat_tx, settings).await;
+        let client = QueueClient::new(state_tx);
+        assert!(client.toggle().await.unwrap());
+
+        let (transport, chat) = MemoryTransport::new("#chan");
+        let mut bot = Bot::new(transport, chat_rx);
+        build_bot(&mut bot, &commands);
+        tokio::spawn(async move { bot.run(client).await });
+        chat
+    }
+
+    #[tokio::test]
+    async fn answers_commands_over_any_transport() {
+        let mut chat = start_bot(Settings::default()).await;
+
+        // Only moderators may add commands, so this is ignored
+        chat.say("alice", Role::Everyone, "!addcmd discord nope");
+        chat.say("alice", Role::Everyone, "!JOIN");
+
+        let reply = chat.next_sent().await.unwrap();
+        assert_eq!(reply.target, "#chan");
+        assert!(reply.text.starts_with("alice is #1."), "{}", reply.text);
+    }
+
+    #[tokio::test]
+    async fn aliases_and_disabled_commands() {
+        let mut chat = start_bot(Settings {
+            commands: CommandSettings {
+                prefixes: vec!["?".to_owned()],
+                aliases: vec![("join".to_owned(), vec!["J".to_owned()])]
+                    .into_iter()
+                    .collect(),
+                disabled: vec!["Next".to_owned()],
+                ..CommandSettings::default()
+            },
+            ..Default::default()
+        })
+        .await;
+
+        // Neither of these is answered: `next` is turned off and `!` is no longer a prefix
+        chat.say("alice", Role::Everyone, "?next");
+        chat.say("alice", Role::Everyone, "!place");
+        chat.say("alice", Role::Everyone, "?j");
+
+        let reply = chat.next_sent().await.unwrap();
+        assert!(reply.text.starts_with("alice is #1."), "{}", reply.text);
+    }
+
+    #[tokio::test]
+    async fn users_known_by_id_are_shown_by_name() {
+        let mut chat = start_bot(Settings::default()).await;
+
+        chat.event(ChatEvent::Message(IncomingMessage {
+            channel: "#chan".to_owned(),
+            sender: "discord:2".to_owned(),
+            display_name: Some("alice".to_owned()),
+            text: "!join".to_owned(),
+            id: None,
+            sender_id: None,
+            role: Role::Everyone,
+            must_reply: false,
+        }));
+        chat.say("bob", Role::Everyone, "!next");
+
+        let reply = chat.next_sent().await.unwrap();
+        assert!(reply.text.starts_with("alice is #1."), "{}", reply.text);
+        assert_eq!(chat.next_sent().await.unwrap().text, "alice");
+    }
+
+    #[tokio::test]
+    async fn help_is_in_the_channel_language() {
+        let mut chat = start_bot(Settings {
+            locale: "es".to_owned(),
+            ..Default::default()
+        })
+        .await;
+
+        chat.say("alice", Role::Everyone, "!help j");
+        chat.say("alice", Role::Everyone, "!help join");
+        let reply = chat.next_sent().await.unwrap();
+        assert_eq!(reply.text, "No existe el comando j.");
+        let reply = chat.next_sent().await.unwrap();
+        assert_eq!(reply.text, "!join [in-game name] - Únete a la cola");
+
+        chat.event(ChatEvent::WrongChannel {
+            reply_to: "9".to_owned(),
+            channel: "<#100>".to_owned(),
+        });
+        let reply = chat.next_sent().await.unwrap();
+        assert_eq!(reply.text, "Los comandos solo funcionan en <#100>.");
+        assert_eq!(reply.delivery, Delivery::Reply("9".to_owned()));
+    }
+
+    #[test]
+    fn parse_command_and_argument() {
+        let prefixes = vec!["!!".to_owned(), "!".to_owned(), "?".to_owned()];
+        assert_eq!(
+            Bot::parse_command(&prefixes, "!join"),
+            Some("join".to_owned())
+        );
+        assert_eq!(
+            Bot::parse_command(&prefixes, "?JOIN now"),
+            Some("join".to_owned())
+        );
+        assert_eq!(
+            Bot::parse_command(&prefixes, "!!join"),
+            Some("join".to_owned())
+        );
+        assert_eq!(Bot::parse_command(&prefixes, "join"), None);
+        assert_eq!(Bot::parse_command(&prefixes, "! join"), None);
+        assert_eq!(Bot::parse_argument("!join"), None);
+        assert_eq!(Bot::parse_argument("!join   "), None);
+        assert_eq!(Bot::parse_argument("!join Some Name "), Some("Some Name"));
+    }
 }
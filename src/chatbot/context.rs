@@ -0,0 +1,233 @@
+use super::{outbound::Writer, CommandInfo, Message, Registry, Role};
+use crate::{
+    client::{ClientError, QueueClient},
+    templates::Templates,
+};
+
+/// Everything a command handler gets to work with: the message that invoked it, a way to answer
+/// and the shared state.
+pub struct Context<'a> {
+    msg: Message<'a>,
+    writer: Writer<'a>,
+    client: &'a QueueClient,
+    /// Every command the bot has, for those that talk about the others.
+    registry: &'a Registry,
+    /// The name the command being run was registered with.
+    name: &'a str,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(
+        msg: Message<'a>,
+        writer: Writer<'a>,
+        client: &'a QueueClient,
+        registry: &'a Registry,
+        name: &'a str,
+    ) -> Context<'a> {
+        Context {
+            msg,
+            writer,
+            client,
+            registry,
+            name,
+        }
+    }
+
+    pub fn msg(&self) -> &Message<'a> {
+        &self.msg
+    }
+
+    /// Who used the command, as the queue knows them.
+    pub fn sender(&self) -> &'a str {
+        self.msg.sender
+    }
+
+    /// Who used the command, as they're shown in chat.
+    pub fn name(&self) -> &'a str {
+        self.msg.display_name.unwrap_or(self.msg.sender)
+    }
+
+    pub fn role(&self) -> Role {
+        self.msg.role
+    }
+
+    /// Every command the bot has, described in the language of `templates`.
+    pub fn commands(&self, templates: &Templates) -> Vec<CommandInfo> {
+        self.registry.command_info(templates)
+    }
+
+    /// Whether `name` is a built-in command or an alias.
+    pub fn is_taken(&self, name: &str) -> bool {
+        self.registry.is_taken(name)
+    }
+
+    pub fn prefix(&self) -> &'a str {
+        self.registry.prefix()
+    }
+
+    /// Answers in the channel the command was used in, however the command is configured to
+    /// respond.
+    pub fn reply(&self, message: impl Into<String>) {
+        self.writer.send_privmsg(self.msg.target, message);
+    }
+
+    /// The templates for the channel the command was used in.
+    pub async fn templates(&self) -> Result<Templates, ClientError> {
+        self.client.templates(Some(self.msg.target)).await
+    }
+
+    /// The queue and everything else the state task looks after.
+    pub fn state(&self) -> &'a QueueClient {
+        self.client
+    }
+
+    /// Everything after the command name, if anything was given.
+    pub fn argument(&self) -> Option<&'a str> {
+        super::Bot::parse_argument(self.msg.message)
+    }
+
+    /// The command's arguments, parsed as `T`. When they don't fit, the command's usage is sent
+    /// back instead and there are none.
+    pub async fn args<T: FromArgs>(&self) -> Result<Option<T>, ClientError> {
+        let args = T::from_args(self.argument().unwrap_or_default());
+        if args.is_none() {
+            self.reply_usage().await?;
+        }
+        Ok(args)
+    }
+
+    /// Tells the sender how the command is used.
+    pub async fn reply_usage(&self) -> Result<(), ClientError> {
+        let templates = self.templates().await?;
+        if let Some(command) = self.registry.info(self.name, &templates) {
+            self.reply(templates.render(
+                &templates.help_command,
+                &[
+                    ("usage", &command.usage),
+                    ("description", &command.description),
+                ],
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Arguments a command can be given, parsed from the text after its name.
+pub trait FromArgs: Sized {
+    /// `None` if `input` doesn't fit.
+    fn from_args(input: &str) -> Option<Self>;
+}
+
+/// A single argument. Most take one word, but a `String` given last takes the rest of the message,
+/// so names and responses can have spaces in them.
+pub trait Arg: Sized {
+    /// Takes the argument from the start of `input`, returning it and what's left.
+    fn take(input: &str, last: bool) -> Option<(Self, &str)>;
+}
+
+// The next word of `input` and what follows it
+fn word(input: &str) -> Option<(&str, &str)> {
+    let input = input.trim_start();
+    if input.is_empty() {
+        return None;
+    }
+    Some(input.split_once(' ').unwrap_or((input, "")))
+}
+
+impl Arg for String {
+    fn take(input: &str, last: bool) -> Option<(Self, &str)> {
+        if last {
+            let rest = input.trim();
+            (!rest.is_empty()).then(|| (rest.to_owned(), ""))
+        } else {
+            word(input).map(|(word, rest)| (word.to_owned(), rest))
+        }
+    }
+}
+
+macro_rules! number_arg {
+    ($($t:ty),*) => {$(
+        impl Arg for $t {
+            fn take(input: &str, _last: bool) -> Option<(Self, &str)> {
+                let (word, rest) = word(input)?;
+                Some((word.parse().ok()?, rest))
+            }
+        }
+    )*};
+}
+
+number_arg!(u8, u16, u32, u64, usize, i32, i64);
+
+/// An argument that may be left out. One that is given but doesn't parse is still an error.
+impl<T: Arg> Arg for Option<T> {
+    fn take(input: &str, last: bool) -> Option<(Self, &str)> {
+        if input.trim().is_empty() {
+            return Some((None, input));
+        }
+        T::take(input, last).map(|(arg, rest)| (Some(arg), rest))
+    }
+}
+
+impl<T: Arg> FromArgs for T {
+    fn from_args(input: &str) -> Option<Self> {
+        let (arg, rest) = T::take(input, true)?;
+        rest.trim().is_empty().then_some(arg)
+    }
+}
+
+macro_rules! tuple_args {
+    ($($name:ident),* ; $last:ident) => {
+        impl<$($name: Arg,)* $last: Arg> FromArgs for ($($name,)* $last,) {
+            fn from_args(input: &str) -> Option<Self> {
+                let rest = input;
+                $(
+                    #[allow(non_snake_case)]
+                    let ($name, rest) = $name::take(rest, false)?;
+                )*
+                #[allow(non_snake_case)]
+                let ($last, rest) = $last::take(rest, true)?;
+                rest.trim().is_empty().then_some(($($name,)* $last,))
+            }
+        }
+    };
+}
+
+tuple_args!(; A);
+tuple_args!(A; B);
+tuple_args!(A, B; C);
+tuple_args!(A, B, C; D);
+
+#[cfg(test)]
+mod tests {
+    use super::FromArgs;
+
+    #[test]
+    fn parses_typed_arguments() {
+        assert_eq!(
+            <(String, Option<u16>)>::from_args("alice 3"),
+            Some(("alice".to_owned(), Some(3)))
+        );
+        assert_eq!(
+            <(String, Option<u16>)>::from_args("alice"),
+            Some(("alice".to_owned(), None))
+        );
+        assert_eq!(<(String, Option<u16>)>::from_args("alice three"), None);
+        assert_eq!(<(String, Option<u16>)>::from_args("alice 3 4"), None);
+        assert_eq!(<(String, Option<u16>)>::from_args(""), None);
+    }
+
+    #[test]
+    fn last_string_takes_the_rest() {
+        assert_eq!(
+            <(String, String)>::from_args("discord Join us  on Discord "),
+            Some(("discord".to_owned(), "Join us  on Discord".to_owned()))
+        );
+        assert_eq!(<(String, String)>::from_args("discord"), None);
+        assert_eq!(
+            Option::<String>::from_args("Player One"),
+            Some(Some("Player One".to_owned()))
+        );
+        assert_eq!(Option::<String>::from_args(""), Some(None));
+        assert_eq!(u16::from_args("12"), Some(12));
+    }
+}
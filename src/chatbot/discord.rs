@@ -0,0 +1,750 @@
+use super::{
+    outbound::{self, Delivery, Limits, Pending},
+    transport::{ChatEvent, ChatTransport, IncomingMessage},
+    CommandInfo, Role,
+};
+use crate::{metrics, settings::DiscordSettings};
+use async_trait::async_trait;
+use futures::prelude::*;
+use reqwest::Method;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+use tokio_tungstenite::{tungstenite::Message as WsMessage, MaybeTlsStream, WebSocketStream};
+
+const GATEWAY_URL: &str = "wss://gateway.discord.gg/?v=10&encoding=json";
+const API_URL: &str = "https://discord.com/api/v10";
+
+// Gateway opcodes
+const OP_DISPATCH: u64 = 0;
+const OP_HEARTBEAT: u64 = 1;
+const OP_IDENTIFY: u64 = 2;
+const OP_RECONNECT: u64 = 7;
+const OP_INVALID_SESSION: u64 = 9;
+const OP_HELLO: u64 = 10;
+const OP_HEARTBEAT_ACK: u64 = 11;
+
+/// GUILDS, GUILD_MESSAGES and MESSAGE_CONTENT. The last has to be switched on for the bot in the
+/// developer portal, or messages arrive without their text.
+const INTENTS: u64 = 1 | 1 << 9 | 1 << 15;
+/// ADMINISTRATOR and MANAGE_MESSAGES, either of which makes a member a moderator.
+const MODERATOR_PERMISSIONS: u64 = 1 << 3 | 1 << 13;
+
+/// How long to wait before reconnecting after losing the gateway.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long a slash command shows the bot thinking before giving up on an answer.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long Discord accepts follow-up answers to a slash command.
+const INTERACTION_LIFETIME: Duration = Duration::from_secs(15 * 60);
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A slash command the bot has acknowledged, which its answers are sent to.
+struct Interaction {
+    token: String,
+    /// Who used the command.
+    user_id: Option<String>,
+    received: Instant,
+    /// Whether the first answer has replaced the "thinking" message. Later ones are follow-ups.
+    answered: bool,
+}
+
+/// What woke up the gateway while it was waiting.
+enum Wake {
+    Socket(Option<Result<WsMessage, tokio_tungstenite::tungstenite::Error>>),
+    Heartbeat,
+}
+
+/// The connection to the Discord gateway, kept up on its own task so a handshake is never cut
+/// short by the bot turning to something else. Events are handed to the transport as they arrive.
+struct Gateway {
+    token: String,
+    socket: Option<Socket>,
+    /// How often the gateway wants a heartbeat, and when the next one is due.
+    heartbeat: Option<(Duration, Instant)>,
+    /// Whether the gateway acknowledged the last heartbeat. A connection that stops answering is
+    /// dropped and made again.
+    acked: bool,
+    /// The last event's sequence number, sent back with heartbeats.
+    sequence: Option<u64>,
+    /// Dispatched events, by name.
+    events: mpsc::UnboundedSender<(String, Value)>,
+}
+
+impl Gateway {
+    /// Connects, and reconnects whenever the connection is lost, until the transport is gone.
+    async fn run(mut self) {
+        while !self.events.is_closed() {
+            let heartbeat = self.heartbeat.map(|(_, next)| next);
+            let socket = match &mut self.socket {
+                Some(socket) => socket,
+                None => {
+                    match tokio_tungstenite::connect_async(GATEWAY_URL).await {
+                        Ok((socket, _)) => self.socket = Some(socket),
+                        Err(e) => {
+                            tracing::warn!("Could not connect to the Discord gateway: {}", e);
+                            tokio::time::sleep(RECONNECT_DELAY).await;
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            let wake = tokio::select! {
+                message = socket.next() => Wake::Socket(message),
+                _ = outbound::sleep_until(heartbeat) => Wake::Heartbeat,
+            };
+            match wake {
+                Wake::Socket(Some(Ok(WsMessage::Text(text)))) => self.read(&text).await,
+                Wake::Socket(Some(Ok(WsMessage::Close(frame)))) => {
+                    tracing::warn!("Discord gateway closed the connection: {:?}", frame);
+                    self.disconnect();
+                }
+                Wake::Socket(Some(Ok(_))) => {}
+                Wake::Socket(Some(Err(e))) => {
+                    tracing::warn!("Could not read from the Discord gateway: {}", e);
+                    self.disconnect();
+                }
+                Wake::Socket(None) => self.disconnect(),
+                Wake::Heartbeat => self.send_heartbeat().await,
+            }
+        }
+    }
+
+    async fn send(&mut self, payload: Value) {
+        let socket = match &mut self.socket {
+            Some(socket) => socket,
+            None => return,
+        };
+        if let Err(e) = socket.send(WsMessage::Text(payload.to_string())).await {
+            tracing::warn!("Could not write to the Discord gateway: {}", e);
+            self.disconnect();
+        }
+    }
+
+    async fn send_heartbeat(&mut self) {
+        if !self.acked {
+            tracing::warn!("Discord gateway stopped answering heartbeats, reconnecting");
+            self.disconnect();
+            return;
+        }
+        self.acked = false;
+        if let Some((interval, next)) = &mut self.heartbeat {
+            *next = Instant::now() + *interval;
+        }
+        self.send(json!({ "op": OP_HEARTBEAT, "d": self.sequence }))
+            .await;
+    }
+
+    fn disconnect(&mut self) {
+        metrics::set_connected("discord", false);
+        self.socket = None;
+        self.heartbeat = None;
+        self.sequence = None;
+        self.acked = true;
+    }
+
+    async fn read(&mut self, text: &str) {
+        let payload: Value = match serde_json::from_str(text) {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::warn!("Could not parse Discord gateway payload: {}", e);
+                return;
+            }
+        };
+        if let Some(sequence) = payload["s"].as_u64() {
+            self.sequence = Some(sequence);
+        }
+        match payload["op"].as_u64() {
+            Some(OP_DISPATCH) => {
+                let event = payload["t"].as_str().unwrap_or_default().to_owned();
+                let _ = self.events.send((event, payload["d"].clone()));
+            }
+            Some(OP_HEARTBEAT) => self.send_heartbeat().await,
+            Some(OP_RECONNECT) | Some(OP_INVALID_SESSION) => self.disconnect(),
+            Some(OP_HELLO) => {
+                let interval = Duration::from_millis(
+                    payload["d"]["heartbeat_interval"]
+                        .as_u64()
+                        .unwrap_or(41_250),
+                );
+                self.heartbeat = Some((interval, Instant::now() + interval));
+                let identify = json!({
+                    "op": OP_IDENTIFY,
+                    "d": {
+                        "token": self.token,
+                        "intents": INTENTS,
+                        "properties": {
+                            "os": std::env::consts::OS,
+                            "browser": "brittlq",
+                            "device": "brittlq",
+                        },
+                    },
+                });
+                self.send(identify).await;
+            }
+            Some(OP_HEARTBEAT_ACK) => self.acked = true,
+            _ => {}
+        }
+    }
+}
+
+/// A Discord channel, read through the gateway and written to through the HTTP API. Commands work
+/// both as chat messages and as slash commands.
+pub struct DiscordTransport {
+    settings: DiscordSettings,
+    client: reqwest::Client,
+    /// Events from the gateway, once it has been started.
+    events: Option<mpsc::UnboundedReceiver<(String, Value)>>,
+    application_id: Option<String>,
+    /// The commands offered as slash commands.
+    commands: Vec<CommandInfo>,
+    interactions: HashMap<String, Interaction>,
+    /// Direct message channels, by user id.
+    dm_channels: HashMap<String, String>,
+    pending: VecDeque<ChatEvent>,
+}
+
+impl DiscordTransport {
+    /// Connects lazily, the first time the bot waits for chat.
+    pub fn new(settings: DiscordSettings) -> DiscordTransport {
+        DiscordTransport {
+            settings,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()
+                .unwrap(),
+            events: None,
+            application_id: None,
+            commands: Vec::new(),
+            interactions: HashMap::new(),
+            dm_channels: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn api(&self, method: Method, path: &str, body: Option<Value>) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .request(method, format!("{}{}", API_URL, path))
+            .header("Authorization", format!("Bot {}", self.settings.token));
+        match body {
+            Some(body) => request
+                .header("Content-Type", "application/json")
+                .body(body.to_string()),
+            None => request,
+        }
+    }
+
+    async fn call(&self, method: Method, path: &str, body: Option<Value>) -> anyhow::Result<Value> {
+        let text = self
+            .api(method, path, body)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        Ok(serde_json::from_str(&text).unwrap_or(Value::Null))
+    }
+
+    // For requests made while reading the gateway, which would otherwise be cut short if the bot
+    // stops waiting on recv
+    fn spawn_call(&self, method: Method, path: &str, body: Option<Value>) {
+        let request = self.api(method, path, body);
+        tokio::spawn(async move {
+            if let Err(e) = request.send().await.and_then(|r| r.error_for_status()) {
+                tracing::warn!("Discord request failed: {}", e);
+            }
+        });
+    }
+
+    fn dispatch(&mut self, event: &str, data: &Value) {
+        match event {
+            "READY" => {
+                metrics::set_connected("discord", true);
+                self.application_id = data["application"]["id"].as_str().map(str::to_owned);
+                if let Some(application_id) = &self.application_id {
+                    let path = format!("/applications/{}/commands", application_id);
+                    self.spawn_call(Method::PUT, &path, Some(slash_commands(&self.commands)));
+                }
+            }
+            "MESSAGE_CREATE" => {
+                if let Some(message) = self.message(data) {
+                    self.pending.push_back(ChatEvent::Message(message));
+                }
+            }
+            "INTERACTION_CREATE" => {
+                if let Some(message) = self.interaction(data) {
+                    self.pending.push_back(ChatEvent::Message(message));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// A chat message in the bot's channel. The bot's own messages, and those of other bots, are
+    /// left out.
+    fn message(&self, data: &Value) -> Option<IncomingMessage> {
+        let author = &data["author"];
+        if data["channel_id"].as_str()? != self.settings.channel_id
+            || author["bot"].as_bool() == Some(true)
+        {
+            return None;
+        }
+        Some(IncomingMessage {
+            channel: self.settings.channel_id.clone(),
+            sender: user_key(author)?,
+            display_name: author["username"].as_str().map(str::to_owned),
+            text: data["content"].as_str()?.to_owned(),
+            id: data["id"].as_str().map(str::to_owned),
+            sender_id: author["id"].as_str().map(str::to_owned),
+            role: self.role(&data["member"]),
+            must_reply: false,
+        })
+    }
+
+    /// A slash command, turned into the chat command it stands for. Discord wants an answer within
+    /// three seconds, so the bot is shown thinking until its reply is sent.
+    fn interaction(&mut self, data: &Value) -> Option<IncomingMessage> {
+        // Only application commands; autocomplete and components aren't used
+        if data["type"].as_u64() != Some(2) {
+            return None;
+        }
+        let id = data["id"].as_str()?;
+        let token = data["token"].as_str()?;
+        let callback = format!("/interactions/{}/{}/callback", id, token);
+
+        if data["channel_id"].as_str() != Some(&self.settings.channel_id) {
+            // 64 makes the answer visible only to whoever used the command
+            let defer = json!({ "type": 5, "data": { "flags": 64 } });
+            self.spawn_call(Method::POST, &callback, Some(defer));
+            self.defer(id, token, &data["member"]["user"]);
+            self.pending.push_back(ChatEvent::WrongChannel {
+                reply_to: id.to_owned(),
+                channel: format!("<#{}>", self.settings.channel_id),
+            });
+            return None;
+        }
+        let text = command_text(&self.commands, &data["data"])?;
+        let member = &data["member"];
+        let user = &member["user"];
+        let message = IncomingMessage {
+            channel: self.settings.channel_id.clone(),
+            sender: user_key(user)?,
+            display_name: user["username"].as_str().map(str::to_owned),
+            text,
+            id: Some(id.to_owned()),
+            sender_id: user["id"].as_str().map(str::to_owned),
+            role: self.role(member),
+            must_reply: true,
+        };
+
+        self.spawn_call(Method::POST, &callback, Some(json!({ "type": 5 })));
+        self.defer(id, token, user);
+        Some(message)
+    }
+
+    // Remembers a slash command the bot is thinking about, so its answer can replace that
+    fn defer(&mut self, id: &str, token: &str, user: &Value) {
+        self.interactions.insert(
+            id.to_owned(),
+            Interaction {
+                token: token.to_owned(),
+                user_id: user["id"].as_str().map(str::to_owned),
+                received: Instant::now(),
+                answered: false,
+            },
+        );
+    }
+
+    /// The slash command `user_id` is waiting on an answer to, if they used one just now.
+    fn waiting_interaction(&self, user_id: &str, now: Instant) -> Option<String> {
+        self.interactions
+            .iter()
+            .filter(|(_, interaction)| {
+                interaction.user_id.as_deref() == Some(user_id)
+                    && interaction.received + ANSWER_TIMEOUT > now
+            })
+            .max_by_key(|(_, interaction)| interaction.received)
+            .map(|(id, _)| id.clone())
+    }
+
+    // Whispers to slash commands are answered where only their user can see them. The thinking
+    // message everyone saw can't be made private, so it's removed and the answer sent on its own.
+    async fn answer_privately(&mut self, id: &str, text: &str) -> anyhow::Result<()> {
+        let application_id = self.application_id.clone().unwrap_or_default();
+        let interaction = match self.interactions.get_mut(id) {
+            Some(interaction) => interaction,
+            None => return Ok(()),
+        };
+        let token = interaction.token.clone();
+        if !std::mem::replace(&mut interaction.answered, true) {
+            let path = format!("/webhooks/{}/{}/messages/@original", application_id, token);
+            self.call(Method::DELETE, &path, None).await?;
+        }
+        let mut body = content(text);
+        body["flags"] = json!(64);
+        let path = format!("/webhooks/{}/{}", application_id, token);
+        self.call(Method::POST, &path, Some(body)).await?;
+        Ok(())
+    }
+
+    fn role(&self, member: &Value) -> Role {
+        let has_role = member["roles"].as_array().is_some_and(|roles| {
+            roles
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|role| self.settings.moderator_roles.iter().any(|r| r == role))
+        });
+        // Only interactions come with the member's permissions
+        let permissions = member["permissions"]
+            .as_str()
+            .and_then(|permissions| permissions.parse::<u64>().ok())
+            .unwrap_or(0);
+        if has_role || permissions & MODERATOR_PERMISSIONS != 0 {
+            Role::Moderator
+        } else {
+            Role::Everyone
+        }
+    }
+
+    /// When the next slash command runs out of time for an answer, or for follow-ups.
+    fn next_expiry(&self) -> Option<Instant> {
+        self.interactions
+            .values()
+            .map(|interaction| {
+                interaction.received
+                    + if interaction.answered {
+                        INTERACTION_LIFETIME
+                    } else {
+                        ANSWER_TIMEOUT
+                    }
+            })
+            .min()
+    }
+
+    // Commands that had nothing to say would leave the bot thinking forever, so their thinking
+    // message is removed
+    fn expire(&mut self, now: Instant) {
+        let application_id = self.application_id.clone().unwrap_or_default();
+        let mut unanswered = Vec::new();
+        self.interactions.retain(|_, interaction| {
+            if !interaction.answered && interaction.received + ANSWER_TIMEOUT <= now {
+                interaction.answered = true;
+                unanswered.push(interaction.token.clone());
+            }
+            interaction.received + INTERACTION_LIFETIME > now
+        });
+        for token in unanswered {
+            let path = format!("/webhooks/{}/{}/messages/@original", application_id, token);
+            self.spawn_call(Method::DELETE, &path, None);
+        }
+    }
+
+    async fn direct_message(&mut self, user_id: &str, text: &str) -> anyhow::Result<()> {
+        let channel_id = match self.dm_channels.get(user_id) {
+            Some(channel_id) => channel_id.clone(),
+            None => {
+                let body = json!({ "recipient_id": user_id });
+                let channel = self
+                    .call(Method::POST, "/users/@me/channels", Some(body))
+                    .await?;
+                let channel_id = channel["id"]
+                    .as_str()
+                    .ok_or_else(|| anyhow::anyhow!("Discord did not return a DM channel"))?
+                    .to_owned();
+                self.dm_channels
+                    .insert(user_id.to_owned(), channel_id.clone());
+                channel_id
+            }
+        };
+        let path = format!("/channels/{}/messages", channel_id);
+        self.call(Method::POST, &path, Some(content(text))).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ChatTransport for DiscordTransport {
+    async fn recv(&mut self) -> Option<ChatEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            let expiry = self.next_expiry();
+            let token = &self.settings.token;
+            let events = self.events.get_or_insert_with(|| {
+                let (events_tx, events_rx) = mpsc::unbounded_channel();
+                let gateway = Gateway {
+                    token: token.clone(),
+                    socket: None,
+                    heartbeat: None,
+                    acked: true,
+                    sequence: None,
+                    events: events_tx,
+                };
+                tokio::spawn(gateway.run());
+                events_rx
+            });
+
+            // Both are cancel safe, so nothing is lost if the bot stops waiting
+            tokio::select! {
+                event = events.recv() => match event {
+                    Some((event, data)) => self.dispatch(&event, &data),
+                    None => return None,
+                },
+                _ = outbound::sleep_until(expiry) => self.expire(Instant::now()),
+            }
+        }
+    }
+
+    async fn send(&mut self, message: Pending) -> anyhow::Result<()> {
+        let path = format!("/channels/{}/messages", message.target);
+        let body = match message.delivery {
+            Delivery::Channel => content(&message.text),
+            Delivery::Reply(id) => {
+                if let Some(interaction) = self.interactions.get_mut(&id) {
+                    let application_id = self.application_id.clone().unwrap_or_default();
+                    let (method, path) = if interaction.answered {
+                        let path = format!("/webhooks/{}/{}", application_id, interaction.token);
+                        (Method::POST, path)
+                    } else {
+                        let path = format!(
+                            "/webhooks/{}/{}/messages/@original",
+                            application_id, interaction.token
+                        );
+                        (Method::PATCH, path)
+                    };
+                    interaction.answered = true;
+                    self.call(method, &path, Some(content(&message.text)))
+                        .await?;
+                    return Ok(());
+                }
+                let mut body = content(&message.text);
+                body["message_reference"] =
+                    json!({ "message_id": id, "fail_if_not_exists": false });
+                body
+            }
+            Delivery::Whisper(user_id) => {
+                if let Some(id) = self.waiting_interaction(&user_id, Instant::now()) {
+                    return self.answer_privately(&id, &message.text).await;
+                }
+                match self.direct_message(&user_id, &message.text).await {
+                    Ok(()) => return Ok(()),
+                    Err(e) => tracing::warn!("Could not DM, answering in the channel: {}", e),
+                }
+                content(&message.text)
+            }
+        };
+        self.call(Method::POST, &path, Some(body)).await?;
+        Ok(())
+    }
+
+    fn channel(&self) -> &str {
+        &self.settings.channel_id
+    }
+
+    fn limits(&self) -> Limits {
+        Limits::DISCORD
+    }
+
+    async fn set_commands(&mut self, commands: &[CommandInfo]) -> anyhow::Result<()> {
+        self.commands = commands.to_vec();
+        // Otherwise they're registered once the gateway says who the bot is
+        if let Some(application_id) = &self.application_id {
+            let path = format!("/applications/{}/commands", application_id);
+            self.call(Method::PUT, &path, Some(slash_commands(&self.commands)))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// What the queue knows a Discord user by. Their id never changes, and the prefix keeps them apart
+/// from Twitch users with the same name.
+fn user_key(user: &Value) -> Option<String> {
+    Some(format!("discord:{}", user["id"].as_str()?))
+}
+
+/// A message body for `text`. Names and arguments come from chat, so only users can be mentioned,
+/// never roles or everyone.
+fn content(text: &str) -> Value {
+    json!({ "content": text, "allowed_mentions": { "parse": ["users"] } })
+}
+
+/// The slash commands standing for `commands`, in the form Discord registers them. Arguments are
+/// taken as one text option, and moderator commands are hidden from members who can't use them.
+fn slash_commands(commands: &[CommandInfo]) -> Value {
+    let slash_commands = commands
+        .iter()
+        .filter(|command| is_slash_name(&command.name))
+        .map(|command| {
+            let mut description = if command.description.is_empty() {
+                command.usage.as_str()
+            } else {
+                command.description.as_str()
+            };
+            if description.chars().count() > 100 {
+                description = &description[..description.char_indices().nth(100).unwrap().0];
+            }
+            let mut slash = json!({ "name": command.name, "type": 1, "description": description });
+            if let Some((_, args)) = command.usage.split_once(' ') {
+                slash["options"] = json!([{
+                    "type": 3,
+                    "name": "args",
+                    "description": args.chars().take(100).collect::<String>(),
+                    "required": args.starts_with('<'),
+                }]);
+            }
+            if command.role >= Role::Moderator {
+                slash["default_member_permissions"] = json!((1u64 << 13).to_string());
+            }
+            slash
+        })
+        .collect();
+    Value::Array(slash_commands)
+}
+
+/// Whether Discord accepts `name` as a slash command name.
+fn is_slash_name(name: &str) -> bool {
+    (1..=32).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// The chat command a slash command's `data` stands for, like `!setign foo`.
+fn command_text(commands: &[CommandInfo], data: &Value) -> Option<String> {
+    let name = data["name"].as_str()?;
+    let command = commands.iter().find(|command| command.name == name)?;
+    let args = data["options"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|option| option["name"] == "args")
+        .and_then(|option| option["value"].as_str());
+    Some(match args {
+        Some(args) => format!("{} {}", command.command, args),
+        None => command.command.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transport() -> DiscordTransport {
+        DiscordTransport::new(DiscordSettings {
+            token: "token".to_owned(),
+            channel_id: "100".to_owned(),
+            moderator_roles: vec!["7".to_owned()],
+        })
+    }
+
+    fn command(name: &str, usage: &str, role: Role) -> CommandInfo {
+        CommandInfo {
+            name: name.to_owned(),
+            command: format!("!{}", name),
+            aliases: Vec::new(),
+            usage: usage.to_owned(),
+            description: format!("Does {}", name),
+            role,
+            custom: false,
+        }
+    }
+
+    #[test]
+    fn reads_messages_in_the_channel() {
+        let transport = transport();
+        let message = |channel: &str, roles: Value, bot: bool| {
+            json!({
+                "id": "1",
+                "channel_id": channel,
+                "content": "!join",
+                "author": { "id": "2", "username": "alice", "bot": bot },
+                "member": { "roles": roles },
+            })
+        };
+
+        let read = transport
+            .message(&message("100", json!([]), false))
+            .unwrap();
+        assert_eq!(read.sender, "discord:2");
+        assert_eq!(read.display_name.as_deref(), Some("alice"));
+        assert_eq!(read.text, "!join");
+        assert_eq!(read.id.as_deref(), Some("1"));
+        assert_eq!(read.role, Role::Everyone);
+        assert!(!read.must_reply);
+
+        let moderator = transport.message(&message("100", json!(["7"]), false));
+        assert_eq!(moderator.unwrap().role, Role::Moderator);
+        assert!(transport
+            .message(&message("101", json!([]), false))
+            .is_none());
+        assert!(transport
+            .message(&message("100", json!([]), true))
+            .is_none());
+    }
+
+    #[test]
+    fn whispers_answer_the_users_slash_command() {
+        let mut transport = transport();
+        transport.defer("1", "token", &json!({ "id": "2" }));
+        let now = Instant::now();
+
+        assert_eq!(
+            transport.waiting_interaction("2", now),
+            Some("1".to_owned())
+        );
+        assert_eq!(transport.waiting_interaction("3", now), None);
+        assert_eq!(
+            transport.waiting_interaction("2", now + ANSWER_TIMEOUT),
+            None
+        );
+    }
+
+    #[test]
+    fn slash_commands_from_chat_commands() {
+        let commands = vec![
+            command("setign", "!setign <in-game name>", Role::Everyone),
+            command("help", "!help [command]", Role::Everyone),
+            command("place", "!place", Role::Everyone),
+            command("addcmd", "!addcmd <name> <response>", Role::Moderator),
+            command("Not Valid", "!Not Valid", Role::Everyone),
+        ];
+
+        let slash = slash_commands(&commands);
+        let slash = slash.as_array().unwrap();
+        assert_eq!(slash.len(), 4);
+        assert_eq!(slash[0]["name"], "setign");
+        assert_eq!(slash[0]["description"], "Does setign");
+        assert_eq!(slash[0]["options"][0]["required"], true);
+        assert_eq!(slash[1]["options"][0]["required"], false);
+        assert!(slash[2].get("options").is_none());
+        assert_eq!(slash[3]["default_member_permissions"], "8192");
+    }
+
+    #[test]
+    fn slash_commands_become_chat_commands() {
+        let commands = vec![
+            command("setign", "!setign <in-game name>", Role::Everyone),
+            command("place", "!place", Role::Everyone),
+        ];
+        let with_args = json!({
+            "name": "setign",
+            "options": [{ "name": "args", "type": 3, "value": "Player One" }],
+        });
+
+        assert_eq!(
+            command_text(&commands, &with_args).as_deref(),
+            Some("!setign Player One")
+        );
+        assert_eq!(
+            command_text(&commands, &json!({ "name": "place" })).as_deref(),
+            Some("!place")
+        );
+        assert_eq!(command_text(&commands, &json!({ "name": "nope" })), None);
+    }
+}
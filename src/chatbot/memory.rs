@@ -0,0 +1,93 @@
+use super::{
+    outbound::{Limits, Pending},
+    transport::{ChatEvent, ChatTransport, IncomingMessage},
+    Role,
+};
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+
+/// A chat that only exists in memory, for running the bot without connecting anywhere.
+pub struct MemoryTransport {
+    channel: String,
+    events: mpsc::UnboundedReceiver<ChatEvent>,
+    sent: mpsc::UnboundedSender<Pending>,
+}
+
+/// The other end of a [`MemoryTransport`], which plays the part of everyone else in chat.
+pub struct MemoryChat {
+    channel: String,
+    events: mpsc::UnboundedSender<ChatEvent>,
+    sent: mpsc::UnboundedReceiver<Pending>,
+    next_id: usize,
+}
+
+impl MemoryTransport {
+    pub fn new(channel: impl Into<String>) -> (MemoryTransport, MemoryChat) {
+        let channel = channel.into();
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let (sent_tx, sent_rx) = mpsc::unbounded_channel();
+        (
+            MemoryTransport {
+                channel: channel.clone(),
+                events: events_rx,
+                sent: sent_tx,
+            },
+            MemoryChat {
+                channel,
+                events: events_tx,
+                sent: sent_rx,
+                next_id: 0,
+            },
+        )
+    }
+}
+
+#[async_trait]
+impl ChatTransport for MemoryTransport {
+    async fn recv(&mut self) -> Option<ChatEvent> {
+        self.events.recv().await
+    }
+
+    async fn send(&mut self, message: Pending) -> anyhow::Result<()> {
+        self.sent.send(message)?;
+        Ok(())
+    }
+
+    fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    // Behaves like Twitch, where the bot mostly runs
+    fn limits(&self) -> Limits {
+        Limits::TWITCH
+    }
+}
+
+impl MemoryChat {
+    pub fn event(&self, event: ChatEvent) {
+        // The bot has stopped if this fails, which whoever is waiting on it will find out
+        let _ = self.events.send(event);
+    }
+
+    /// `sender` says `text` in the channel. Each message gets a new id, which is returned.
+    pub fn say(&mut self, sender: &str, role: Role, text: &str) -> String {
+        self.next_id += 1;
+        let id = self.next_id.to_string();
+        self.event(ChatEvent::Message(IncomingMessage {
+            channel: self.channel.clone(),
+            sender: sender.to_owned(),
+            display_name: None,
+            text: text.to_owned(),
+            id: Some(id.clone()),
+            sender_id: Some(format!("id-{}", sender)),
+            role,
+            must_reply: false,
+        }));
+        id
+    }
+
+    /// The next message the bot sent, or `None` once the bot has stopped.
+    pub async fn next_sent(&mut self) -> Option<Pending> {
+        self.sent.recv().await
+    }
+}
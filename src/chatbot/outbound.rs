@@ -0,0 +1,432 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// How fast, and how long, a platform lets the bot send messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    /// Messages are counted over a sliding window of this length.
+    pub window: Duration,
+    /// How many messages fit in the window for a regular user, and for a moderator or broadcaster.
+    pub messages: usize,
+    pub moderator_messages: usize,
+    /// The longest message, in bytes.
+    pub message_len: usize,
+    /// For platforms that send messages as IRC lines, the longest line including the command,
+    /// target and trailing CRLF.
+    pub irc_line_len: Option<usize>,
+}
+
+impl Limits {
+    /// Twitch chat, where lines can be at most 512 bytes and messages are cut off at 500
+    /// characters.
+    pub const TWITCH: Limits = Limits {
+        window: Duration::from_secs(30),
+        messages: 20,
+        moderator_messages: 100,
+        message_len: 500,
+        irc_line_len: Some(512),
+    };
+
+    /// A Discord channel, which takes 5 messages every 5 seconds of up to 2000 characters. Bytes
+    /// are counted rather than characters, so long messages are split early rather than late.
+    pub const DISCORD: Limits = Limits {
+        window: Duration::from_secs(5),
+        messages: 5,
+        moderator_messages: 5,
+        message_len: 2000,
+        irc_line_len: None,
+    };
+
+    /// The longest message that fits on one line sent to `target`.
+    fn max_len(&self, target: &str) -> usize {
+        match self.irc_line_len {
+            Some(line_len) => {
+                let overhead = "PRIVMSG  :\r\n".len() + target.len();
+                line_len.saturating_sub(overhead).min(self.message_len)
+            }
+            None => self.message_len,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Priority {
+    Normal,
+    /// Announcements and replies to moderators, which skip ahead of normal messages.
+    High,
+}
+
+/// How a message reaches chat.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Delivery {
+    /// Said in the channel.
+    Channel,
+    /// Said in the channel, in the reply thread of the chat message with this id.
+    Reply(String),
+    /// Whispered to the user with this id.
+    Whisper(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pending {
+    pub target: String,
+    pub text: String,
+    pub delivery: Delivery,
+    priority: Priority,
+}
+
+/// Messages waiting to be sent to chat, released no faster than the platform allows.
+#[derive(Debug)]
+pub struct Outbound {
+    limits: Limits,
+    pending: VecDeque<Pending>,
+    /// When each message in the current window was sent, oldest first.
+    sent: VecDeque<Instant>,
+    /// Whether the bot is a moderator in its channel, which raises its limit.
+    is_moderator: bool,
+}
+
+impl Outbound {
+    pub fn new(limits: Limits) -> Outbound {
+        Outbound {
+            limits,
+            pending: VecDeque::new(),
+            sent: VecDeque::new(),
+            is_moderator: false,
+        }
+    }
+
+    pub fn set_moderator(&mut self, is_moderator: bool) {
+        self.is_moderator = is_moderator;
+    }
+
+    /// Queues `text` for `target`. A message identical to one already waiting is dropped, though it
+    /// can still raise the waiting one's priority.
+    pub fn push(&mut self, target: &str, text: String, priority: Priority, delivery: Delivery) {
+        if let Some(index) = self.pending.iter().position(|pending| {
+            pending.target == target && pending.text == text && pending.delivery == delivery
+        }) {
+            if self.pending[index].priority >= priority {
+                return;
+            }
+            self.pending.remove(index);
+        }
+
+        let index = self
+            .pending
+            .iter()
+            .position(|pending| pending.priority < priority)
+            .unwrap_or(self.pending.len());
+        self.pending.insert(
+            index,
+            Pending {
+                target: target.to_owned(),
+                text,
+                delivery,
+                priority,
+            },
+        );
+    }
+
+    /// Queues a whisper that couldn't be delivered to be said in the channel instead.
+    pub fn push_in_channel(&mut self, pending: Pending) {
+        self.push(
+            &pending.target,
+            pending.text,
+            pending.priority,
+            Delivery::Channel,
+        );
+    }
+
+    /// When the next message may be sent, if there is one waiting. Whispers don't go through chat,
+    /// so they can always be sent.
+    pub fn next_send(&mut self, now: Instant) -> Option<Instant> {
+        if matches!(self.pending.front()?.delivery, Delivery::Whisper(_)) {
+            return Some(now);
+        }
+        self.expire(now);
+        match self.sent.front() {
+            Some(&oldest) if self.sent.len() >= self.limit() => Some(oldest + self.limits.window),
+            _ => Some(now),
+        }
+    }
+
+    /// Takes the next line to send if it may be sent now, counting it against the limit unless
+    /// it's a whisper. Messages too long for one line are sent a line at a time.
+    pub fn pop(&mut self, now: Instant) -> Option<Pending> {
+        if self.next_send(now)? > now {
+            return None;
+        }
+        let front = self.pending.front_mut()?;
+        if !matches!(front.delivery, Delivery::Whisper(_)) {
+            self.sent.push_back(now);
+        }
+
+        match split(&front.text, self.limits.max_len(&front.target)) {
+            Some((end, rest)) => {
+                let line = front.text[..end].to_owned();
+                front.text.replace_range(..rest, "");
+                Some(Pending {
+                    target: front.target.clone(),
+                    text: line,
+                    delivery: front.delivery.clone(),
+                    priority: front.priority,
+                })
+            }
+            None => self.pending.pop_front(),
+        }
+    }
+
+    fn limit(&self) -> usize {
+        if self.is_moderator {
+            self.limits.moderator_messages
+        } else {
+            self.limits.messages
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while matches!(self.sent.front(), Some(&sent) if sent + self.limits.window <= now) {
+            self.sent.pop_front();
+        }
+    }
+}
+
+/// Where to break `text` if it is longer than `max_len` bytes: the end of the first line and the
+/// start of the rest. Lines break on whitespace so names stay whole, and only fall back to
+/// breaking mid-word, on a character boundary, when a single word is too long.
+fn split(text: &str, max_len: usize) -> Option<(usize, usize)> {
+    if text.len() <= max_len {
+        return None;
+    }
+    let mut limit = max_len.max(1);
+    while !text.is_char_boundary(limit) {
+        limit -= 1;
+    }
+    if limit == 0 {
+        // A single character longer than the limit, which can't be split
+        limit = text.chars().next().map_or(text.len(), char::len_utf8);
+    }
+
+    // The last whitespace that still leaves the line within the limit
+    let space = text
+        .char_indices()
+        .take_while(|&(index, _)| index <= limit)
+        .filter(|&(index, c)| index > 0 && c.is_whitespace())
+        .last()
+        .map(|(index, _)| index);
+    match space {
+        Some(space) => {
+            let end = text[..space].trim_end().len();
+            let rest = space + text[space..].len() - text[space..].trim_start().len();
+            Some((end, rest))
+        }
+        None => Some((limit, limit)),
+    }
+}
+
+/// What handlers use to reply in chat. Messages go through the bot's [`Outbound`] queue.
+/// Replies are delivered however the command is configured to respond.
+pub struct Writer<'a> {
+    outbound: &'a Mutex<Outbound>,
+    priority: Priority,
+    delivery: Delivery,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(
+        outbound: &'a Mutex<Outbound>,
+        priority: Priority,
+        delivery: Delivery,
+    ) -> Writer<'a> {
+        Writer {
+            outbound,
+            priority,
+            delivery,
+        }
+    }
+
+    pub fn send_privmsg(&self, target: &str, message: impl Into<String>) {
+        self.outbound.lock().unwrap().push(
+            target,
+            message.into(),
+            self.priority,
+            self.delivery.clone(),
+        );
+    }
+}
+
+/// Waits until `deadline`, or forever if there isn't one.
+pub async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => futures::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Limits::TWITCH.window;
+    const USER_LIMIT: usize = Limits::TWITCH.messages;
+    const MODERATOR_LIMIT: usize = Limits::TWITCH.moderator_messages;
+
+    fn texts(outbound: &Outbound) -> Vec<&str> {
+        outbound
+            .pending
+            .iter()
+            .map(|pending| pending.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn throttles_to_the_limit() {
+        let mut outbound = Outbound::new(Limits::TWITCH);
+        let now = Instant::now();
+        for i in 0..=USER_LIMIT {
+            outbound.push("#chan", i.to_string(), Priority::Normal, Delivery::Channel);
+        }
+
+        for _ in 0..USER_LIMIT {
+            assert!(outbound.pop(now).is_some());
+        }
+        assert_eq!(outbound.pop(now), None);
+        assert_eq!(outbound.next_send(now), Some(now + WINDOW));
+        assert!(outbound.pop(now + WINDOW).is_some());
+        assert_eq!(outbound.next_send(now + WINDOW), None);
+    }
+
+    #[test]
+    fn moderators_get_a_higher_limit() {
+        let mut outbound = Outbound::new(Limits::TWITCH);
+        outbound.set_moderator(true);
+        let now = Instant::now();
+        for i in 0..MODERATOR_LIMIT {
+            outbound.push("#chan", i.to_string(), Priority::Normal, Delivery::Channel);
+        }
+
+        for _ in 0..MODERATOR_LIMIT {
+            assert!(outbound.pop(now).is_some());
+        }
+    }
+
+    #[test]
+    fn whispers_skip_the_chat_limit() {
+        let mut outbound = Outbound::new(Limits::TWITCH);
+        let now = Instant::now();
+        for i in 0..USER_LIMIT {
+            outbound.push("#chan", i.to_string(), Priority::Normal, Delivery::Channel);
+        }
+        let whisper = Delivery::Whisper("42".to_owned());
+        outbound.push("#chan", "psst".to_owned(), Priority::Normal, whisper);
+        outbound.push(
+            "#chan",
+            "late".to_owned(),
+            Priority::Normal,
+            Delivery::Channel,
+        );
+
+        for _ in 0..USER_LIMIT {
+            assert!(outbound.pop(now).is_some());
+        }
+        assert_eq!(outbound.pop(now).unwrap().text, "psst");
+        assert_eq!(outbound.pop(now), None);
+
+        // A whisper said in the channel instead counts like any other message
+        let whisper = Pending {
+            target: "#chan".to_owned(),
+            text: "psst".to_owned(),
+            delivery: Delivery::Whisper("42".to_owned()),
+            priority: Priority::High,
+        };
+        outbound.push_in_channel(whisper);
+        assert_eq!(texts(&outbound), ["psst", "late"]);
+        assert_eq!(outbound.next_send(now), Some(now + WINDOW));
+    }
+
+    fn lines(text: &str, max_len: usize) -> Vec<&str> {
+        let mut lines = Vec::new();
+        let mut rest = text;
+        while let Some((end, start)) = split(rest, max_len) {
+            lines.push(&rest[..end]);
+            rest = &rest[start..];
+        }
+        lines.push(rest);
+        lines
+    }
+
+    #[test]
+    fn splits_long_messages_between_names() {
+        assert_eq!(lines("short", 10), ["short"]);
+        assert_eq!(
+            lines("Up next: @alice, @bob, @carol", 16),
+            ["Up next: @alice,", "@bob, @carol"]
+        );
+        assert_eq!(lines("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        // Never in the middle of a character
+        assert_eq!(lines("ééé", 3), ["é", "é", "é"]);
+    }
+
+    #[test]
+    fn pops_long_messages_a_line_at_a_time() {
+        let mut outbound = Outbound::new(Limits::TWITCH);
+        let now = Instant::now();
+        let names = vec!["someone"; 100].join(", ");
+        outbound.push("#chan", names.clone(), Priority::Normal, Delivery::Channel);
+
+        let mut sent = Vec::new();
+        while let Some(line) = outbound.pop(now) {
+            assert!(line.text.len() <= Limits::TWITCH.max_len("#chan"));
+            sent.push(line.text);
+        }
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent.join(" "), names);
+    }
+
+    #[test]
+    fn limits_come_from_the_platform() {
+        let mut outbound = Outbound::new(Limits::DISCORD);
+        let now = Instant::now();
+        let names = vec!["someone"; 100].join(", ");
+        outbound.push("100", names.clone(), Priority::Normal, Delivery::Channel);
+        assert_eq!(outbound.pop(now).unwrap().text, names);
+
+        for i in 0..Limits::DISCORD.messages {
+            outbound.push("100", i.to_string(), Priority::Normal, Delivery::Channel);
+        }
+        for _ in 1..Limits::DISCORD.messages {
+            assert!(outbound.pop(now).is_some());
+        }
+        assert_eq!(outbound.pop(now), None);
+        assert!(outbound.pop(now + Limits::DISCORD.window).is_some());
+    }
+
+    #[test]
+    fn coalesces_duplicates_and_prioritises() {
+        let mut outbound = Outbound::new(Limits::TWITCH);
+        outbound.push("#chan", "a".to_owned(), Priority::Normal, Delivery::Channel);
+        outbound.push("#chan", "b".to_owned(), Priority::Normal, Delivery::Channel);
+        outbound.push("#chan", "a".to_owned(), Priority::Normal, Delivery::Channel);
+        outbound.push(
+            "#other",
+            "a".to_owned(),
+            Priority::Normal,
+            Delivery::Channel,
+        );
+        assert_eq!(texts(&outbound), ["a", "b", "a"]);
+
+        outbound.push("#chan", "c".to_owned(), Priority::High, Delivery::Channel);
+        outbound.push("#chan", "b".to_owned(), Priority::High, Delivery::Channel);
+        assert_eq!(texts(&outbound), ["c", "b", "a", "a"]);
+
+        outbound.push(
+            "#chan",
+            "c".to_owned(),
+            Priority::High,
+            Delivery::Reply("id".to_owned()),
+        );
+        assert_eq!(texts(&outbound), ["c", "b", "c", "a", "a"]);
+    }
+}
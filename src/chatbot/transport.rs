@@ -0,0 +1,68 @@
+use super::{
+    outbound::{Limits, Pending},
+    CommandInfo, Role,
+};
+use async_trait::async_trait;
+
+/// A chat message someone sent in a channel the bot is in.
+#[derive(Clone, Debug)]
+pub struct IncomingMessage {
+    pub channel: String,
+    /// Who sent the message, which the queue knows them by.
+    pub sender: String,
+    /// How to show the sender in chat, when `sender` is only an id.
+    pub display_name: Option<String>,
+    pub text: String,
+    /// The platform's id for the message, which replies can thread under.
+    pub id: Option<String>,
+    /// The platform's id for the sender, which whispers are addressed to.
+    pub sender_id: Option<String>,
+    pub role: Role,
+    /// Whether the platform expects every answer to be a reply to this message, like Discord's
+    /// slash commands.
+    pub must_reply: bool,
+}
+
+/// Something that happened in chat.
+#[derive(Clone, Debug)]
+pub enum ChatEvent {
+    Message(IncomingMessage),
+    /// People joined the channel, or were already in it when the bot joined.
+    Joined(Vec<String>),
+    /// Someone left the channel.
+    Parted(String),
+    /// Whether the bot itself is a moderator, which lets it send messages faster.
+    BotIsModerator(bool),
+    /// A whisper that couldn't be delivered, to be said in the channel instead.
+    NotWhispered(Pending),
+    /// A command used outside the bot's channel, to be answered with where commands work.
+    /// `channel` is how the platform links to the bot's channel.
+    WrongChannel {
+        reply_to: String,
+        channel: String,
+    },
+}
+
+/// A connection to a chat platform. The bot reads what happens in chat from it and sends its
+/// messages through it, so commands don't depend on any one platform.
+#[async_trait]
+pub trait ChatTransport: Send {
+    /// The next thing that happened in chat, or `None` once the connection is gone. This must be
+    /// cancel safe, as the bot waits on it alongside other things.
+    async fn recv(&mut self) -> Option<ChatEvent>;
+
+    /// Sends a message, as a reply or whisper if the platform supports them and as a plain message
+    /// in the channel otherwise.
+    async fn send(&mut self, message: Pending) -> anyhow::Result<()>;
+
+    /// The channel announcements are sent to.
+    fn channel(&self) -> &str;
+
+    /// How fast the bot may send messages, and how long they may be.
+    fn limits(&self) -> Limits;
+
+    /// Tells the platform which commands the bot has, for platforms that list them to users.
+    async fn set_commands(&mut self, _commands: &[CommandInfo]) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,465 @@
+use super::{
+    outbound::{Delivery, Limits, Pending},
+    transport::{ChatEvent, ChatTransport, IncomingMessage},
+    whisper::Whisperer,
+    Role,
+};
+use crate::metrics;
+use async_trait::async_trait;
+use futures::prelude::*;
+mod irc {
+    pub use irc::client::prelude::*;
+    pub use irc::client::ClientStream;
+    pub use irc::error::*;
+    pub use irc::proto::message::Tag;
+}
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{Duration, Instant};
+
+/// How long to wait before reconnecting after losing chat, doubling after each failed attempt up
+/// to the maximum.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Twitch chat over IRC. The connection is kept up on its own task and made again whenever it is
+/// lost.
+pub struct TwitchTransport {
+    channel: String,
+    /// Messages read from chat.
+    incoming: mpsc::UnboundedReceiver<irc::Message>,
+    /// Messages to send, held by the connection task until it is in the channel.
+    outgoing: mpsc::UnboundedSender<irc::Message>,
+    /// Events read from a single IRC message that haven't been handed out yet.
+    pending: VecDeque<ChatEvent>,
+    whisperer: Option<Arc<Mutex<Whisperer>>>,
+    /// Whispers that couldn't be sent, handed back to the bot to say in the channel.
+    not_whispered_tx: mpsc::UnboundedSender<Pending>,
+    not_whispered_rx: mpsc::UnboundedReceiver<Pending>,
+}
+
+struct Connection {
+    // Kept so the connection stays open
+    _client: irc::Client,
+    stream: irc::ClientStream,
+    sender: irc::Sender,
+    /// Whether the bot has joined its channel. Messages wait until it has.
+    joined: bool,
+}
+
+impl Connection {
+    async fn open(config: irc::Config) -> Result<Connection, irc::Error> {
+        let mut client = irc::Client::from_config(config).await?;
+        client.identify()?;
+        // Without this Twitch doesn't send JOIN and PART for other users
+        // and without tags it doesn't say who is a moderator. Commands gets USERSTATE, which tells
+        // us whether the bot itself is one.
+        client.send_cap_req(&[
+            irc::Capability::Custom("twitch.tv/membership"),
+            irc::Capability::Custom("twitch.tv/tags"),
+            irc::Capability::Custom("twitch.tv/commands"),
+        ])?;
+        Ok(Connection {
+            stream: client.stream()?,
+            sender: client.sender(),
+            _client: client,
+            joined: false,
+        })
+    }
+}
+
+/// Keeps the IRC connection up on its own task, so reconnecting is never cut short by the bot
+/// turning to something else. Messages sent while disconnected are held until the bot is back in
+/// its channel.
+struct Connector {
+    config: irc::Config,
+    connection: Option<Connection>,
+    /// When to try connecting again while disconnected.
+    reconnect_at: Instant,
+    reconnect_delay: Duration,
+    /// A message that was taken to be sent when the connection broke, sent first on reconnecting.
+    unsent: Option<irc::Message>,
+    incoming: mpsc::UnboundedSender<irc::Message>,
+    outgoing: mpsc::UnboundedReceiver<irc::Message>,
+}
+
+impl Connector {
+    fn new(
+        config: irc::Config,
+        incoming: mpsc::UnboundedSender<irc::Message>,
+        outgoing: mpsc::UnboundedReceiver<irc::Message>,
+    ) -> Connector {
+        Connector {
+            config,
+            connection: None,
+            reconnect_at: Instant::now(),
+            reconnect_delay: RECONNECT_DELAY,
+            unsent: None,
+            incoming,
+            outgoing,
+        }
+    }
+
+    /// Reads and writes chat, and reconnects whenever the connection is lost, until the transport
+    /// is gone.
+    async fn run(mut self) {
+        while !self.incoming.is_closed() {
+            if self.connection.as_ref().is_some_and(|c| c.joined) {
+                if let Some(message) = self.unsent.take() {
+                    self.write(message);
+                    continue;
+                }
+            }
+            let connection = match &mut self.connection {
+                Some(connection) => connection,
+                None => {
+                    tokio::time::sleep_until(self.reconnect_at).await;
+                    match Connection::open(self.config.clone()).await {
+                        Ok(connection) => {
+                            tracing::info!("Reconnected to chat");
+                            self.connection = Some(connection);
+                        }
+                        Err(e) => {
+                            tracing::warn!("Could not reconnect to chat: {}", e);
+                            self.disconnect();
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            let joined = connection.joined;
+            tokio::select! {
+                message = connection.stream.next() => match message {
+                    Some(Ok(message)) => {
+                        tracing::debug!("{}", message);
+                        self.read(&message);
+                        let _ = self.incoming.send(message);
+                    }
+                    Some(Err(e @ irc::Error::InvalidMessage { .. })) => {
+                        tracing::warn!("Could not read from chat: {}", e)
+                    }
+                    Some(Err(e)) => {
+                        tracing::warn!("Lost connection to chat, reconnecting: {}", e);
+                        self.disconnect();
+                    }
+                    None => {
+                        tracing::warn!("Chat closed the connection, reconnecting");
+                        self.disconnect();
+                    }
+                },
+                message = self.outgoing.recv(), if joined => match message {
+                    Some(message) => self.write(message),
+                    None => return,
+                },
+            }
+        }
+    }
+
+    fn write(&mut self, message: irc::Message) {
+        let connection = match &self.connection {
+            Some(connection) => connection,
+            None => {
+                self.unsent = Some(message);
+                return;
+            }
+        };
+        if let Err(e) = connection.sender.send(message.clone()) {
+            tracing::warn!("Could not write to chat, reconnecting: {}", e);
+            self.unsent = Some(message);
+            self.disconnect();
+        }
+    }
+
+    fn disconnect(&mut self) {
+        metrics::set_connected("twitch", false);
+        self.connection = None;
+        self.reconnect_at = Instant::now() + self.reconnect_delay;
+        self.reconnect_delay = (self.reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+
+    // keep track of logging in and joining
+    fn read(&mut self, message: &irc::Message) {
+        match message.command {
+            // Logged in. A connection that is dropped before this, like one with a bad token,
+            // keeps backing off
+            irc::Command::Response(irc::Response::RPL_WELCOME, _) => {
+                metrics::set_connected("twitch", true);
+                self.reconnect_delay = RECONNECT_DELAY;
+            }
+            irc::Command::JOIN(..) => {
+                let nickname = self.config.nickname.as_deref().unwrap_or_default();
+                let ours = message
+                    .source_nickname()
+                    .is_some_and(|user| user.eq_ignore_ascii_case(nickname));
+                if let (true, Some(connection)) = (ours, &mut self.connection) {
+                    connection.joined = true;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl TwitchTransport {
+    pub async fn new(user_config: irc::Config) -> Result<TwitchTransport, irc::Error> {
+        let channel = user_config.channels.iter().take(1).cloned().collect();
+        let connection = Connection::open(user_config.clone()).await?;
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+        let mut connector = Connector::new(user_config, incoming_tx, outgoing_rx);
+        connector.connection = Some(connection);
+        tokio::spawn(connector.run());
+        Ok(TwitchTransport::with_channels(
+            channel,
+            incoming_rx,
+            outgoing_tx,
+        ))
+    }
+
+    fn with_channels(
+        channel: String,
+        incoming: mpsc::UnboundedReceiver<irc::Message>,
+        outgoing: mpsc::UnboundedSender<irc::Message>,
+    ) -> TwitchTransport {
+        let (not_whispered_tx, not_whispered_rx) = mpsc::unbounded_channel();
+        TwitchTransport {
+            channel,
+            incoming,
+            outgoing,
+            pending: VecDeque::new(),
+            whisperer: None,
+            not_whispered_tx,
+            not_whispered_rx,
+        }
+    }
+
+    fn send_irc(&self, message: irc::Message) -> anyhow::Result<()> {
+        self.outgoing
+            .send(message)
+            .map_err(|_| anyhow::anyhow!("The chat connection stopped"))
+    }
+
+    // let commands respond with whispers, sent with this token
+    pub fn with_whispers(&mut self, access_token: impl Into<String>) {
+        self.whisperer = Some(Arc::new(Mutex::new(Whisperer::new(access_token))));
+    }
+
+    // turn an IRC message into the events the bot cares about
+    fn read(&mut self, message: irc::Message) {
+        match message.command {
+            irc::Command::JOIN(..) => {
+                if let Some(user) = message.source_nickname() {
+                    self.pending
+                        .push_back(ChatEvent::Joined(vec![user.to_owned()]));
+                }
+            }
+            irc::Command::PART(..) => {
+                if let Some(user) = message.source_nickname() {
+                    self.pending.push_back(ChatEvent::Parted(user.to_owned()));
+                }
+            }
+            // The list of users already in the channel when the bot joins
+            irc::Command::Response(irc::Response::RPL_NAMREPLY, ref args) => {
+                let users = args
+                    .last()
+                    .into_iter()
+                    .flat_map(|names| names.split_whitespace())
+                    .map(str::to_owned)
+                    .collect();
+                self.pending.push_back(ChatEvent::Joined(users));
+            }
+            // Sent on joining and after each of our messages, with our own badges
+            irc::Command::Raw(ref command, _) if command == "USERSTATE" => {
+                self.pending
+                    .push_back(ChatEvent::BotIsModerator(is_moderator(&message)));
+            }
+            irc::Command::PRIVMSG(_, ref text) => {
+                let (channel, sender) = match (message.response_target(), message.source_nickname())
+                {
+                    (Some(channel), Some(sender)) => (channel, sender),
+                    _ => return,
+                };
+                self.pending.push_back(ChatEvent::Message(IncomingMessage {
+                    channel: channel.to_owned(),
+                    sender: sender.to_owned(),
+                    display_name: None,
+                    text: text.clone(),
+                    id: tag(&message, "id").map(str::to_owned),
+                    sender_id: tag(&message, "user-id").map(str::to_owned),
+                    role: if is_moderator(&message) {
+                        Role::Moderator
+                    } else {
+                        Role::Everyone
+                    },
+                    must_reply: false,
+                }));
+            }
+            _ => {}
+        }
+    }
+}
+
+#[async_trait]
+impl ChatTransport for TwitchTransport {
+    async fn recv(&mut self) -> Option<ChatEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            // Both are cancel safe, so nothing is lost if the bot stops waiting
+            tokio::select! {
+                Some(pending) = self.not_whispered_rx.recv() => {
+                    return Some(ChatEvent::NotWhispered(pending));
+                }
+                message = self.incoming.recv() => self.read(message?),
+            }
+        }
+    }
+
+    async fn send(&mut self, message: Pending) -> anyhow::Result<()> {
+        match message.delivery {
+            Delivery::Channel => self.send_irc(irc::Message {
+                tags: None,
+                prefix: None,
+                command: irc::Command::PRIVMSG(message.target, message.text),
+            })?,
+            Delivery::Reply(id) => self.send_irc(irc::Message {
+                tags: Some(vec![irc::Tag("reply-parent-msg-id".to_owned(), Some(id))]),
+                prefix: None,
+                command: irc::Command::PRIVMSG(message.target, message.text),
+            })?,
+            // The API call is made in the background so chat isn't held up waiting on it
+            Delivery::Whisper(ref user_id) => match &self.whisperer {
+                Some(whisperer) => {
+                    let whisperer = whisperer.clone();
+                    let not_whispered = self.not_whispered_tx.clone();
+                    let user_id = user_id.clone();
+                    tokio::spawn(async move {
+                        let sent = whisperer.lock().await.send(&user_id, &message.text).await;
+                        if let Err(e) = sent {
+                            tracing::warn!("Could not whisper, answering in chat: {}", e);
+                            let _ = not_whispered.send(message);
+                        }
+                    });
+                }
+                None => {
+                    let _ = self.not_whispered_tx.send(message);
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    fn limits(&self) -> Limits {
+        Limits::TWITCH
+    }
+}
+
+/// The value of one of the Twitch tags on `message`.
+fn tag<'a>(message: &'a irc::Message, key: &str) -> Option<&'a str> {
+    message
+        .tags
+        .as_ref()?
+        .iter()
+        .find(|irc::Tag(name, _)| name == key)?
+        .1
+        .as_deref()
+}
+
+/// Whether the sender of `message` is a moderator or the broadcaster, going by its Twitch tags.
+fn is_moderator(message: &irc::Message) -> bool {
+    tag(message, "mod") == Some("1")
+        || tag(message, "badges").is_some_and(|badges| {
+            badges
+                .split(',')
+                .any(|badge| badge.starts_with("broadcaster/"))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transport() -> TwitchTransport {
+        let (_, incoming) = mpsc::unbounded_channel();
+        let (outgoing, _) = mpsc::unbounded_channel();
+        TwitchTransport::with_channels("#chan".to_owned(), incoming, outgoing)
+    }
+
+    fn connector() -> Connector {
+        let (incoming, _) = mpsc::unbounded_channel();
+        let (_, outgoing) = mpsc::unbounded_channel();
+        Connector::new(irc::Config::default(), incoming, outgoing)
+    }
+
+    #[test]
+    fn names_are_one_event() {
+        let mut transport = transport();
+        transport.read(":tmi.twitch.tv 353 bot = #chan :a b c".parse().unwrap());
+        transport.read(":d!d@d JOIN #chan".parse().unwrap());
+
+        let joined: Vec<_> = transport
+            .pending
+            .iter()
+            .map(|event| match event {
+                ChatEvent::Joined(users) => users.clone(),
+                event => panic!("Expected Joined, got {:?}", event),
+            })
+            .collect();
+        assert_eq!(joined, [vec!["a", "b", "c"], vec!["d"]]);
+    }
+
+    #[test]
+    fn backs_off_until_logged_in() {
+        let mut connector = connector();
+        connector.disconnect();
+        connector.disconnect();
+        assert_eq!(connector.reconnect_delay, RECONNECT_DELAY * 4);
+        let connected = r#"brittlq_chat_connected{transport="twitch"} 1"#;
+        assert!(!metrics::render().contains(connected));
+
+        connector.read(&":tmi.twitch.tv 001 bot :Welcome, GLHF!".parse().unwrap());
+        assert_eq!(connector.reconnect_delay, RECONNECT_DELAY);
+        assert!(metrics::render().contains(connected));
+    }
+
+    #[test]
+    fn keeps_what_it_could_not_send() {
+        let mut connector = connector();
+        let message: irc::Message = "PRIVMSG #chan :hello".parse().unwrap();
+        connector.write(message.clone());
+        assert_eq!(connector.unsent, Some(message));
+    }
+
+    #[test]
+    fn reads_tags() {
+        let message: irc::Message =
+            "@id=abc-123;user-id=42;display-name= :foo!foo@foo PRIVMSG #chan :!place"
+                .parse()
+                .unwrap();
+        assert_eq!(tag(&message, "id"), Some("abc-123"));
+        assert_eq!(tag(&message, "user-id"), Some("42"));
+        assert_eq!(tag(&message, "missing"), None);
+    }
+
+    #[test]
+    fn moderators_from_tags() {
+        let parse = |raw: &str| raw.parse::<irc::Message>().unwrap();
+        assert!(is_moderator(&parse(
+            "@badges=moderator/1;mod=1 :foo!foo@foo PRIVMSG #chan :!place"
+        )));
+        assert!(is_moderator(&parse(
+            "@badges=broadcaster/1;mod=0 :foo!foo@foo PRIVMSG #chan :!place"
+        )));
+        assert!(!is_moderator(&parse(
+            "@badges=subscriber/12;mod=0 :foo!foo@foo PRIVMSG #chan :!place"
+        )));
+        assert!(!is_moderator(&parse(":foo!foo@foo PRIVMSG #chan :!place")));
+    }
+}
@@ -0,0 +1,65 @@
+use serde::Deserialize;
+use std::time::Duration;
+
+const VALIDATE_URL: &str = "https://id.twitch.tv/oauth2/validate";
+const WHISPERS_URL: &str = "https://api.twitch.tv/helix/whispers";
+
+/// Who the token belongs to, which the whispers API needs alongside it.
+#[derive(Debug, Deserialize)]
+struct Identity {
+    client_id: String,
+    user_id: String,
+}
+
+/// Sends whispers through the Twitch API, as Twitch no longer accepts them over IRC. The token
+/// needs the `user:manage:whispers` scope.
+pub struct Whisperer {
+    client: reqwest::Client,
+    access_token: String,
+    identity: Option<Identity>,
+}
+
+impl Whisperer {
+    pub fn new(access_token: impl Into<String>) -> Whisperer {
+        Whisperer {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()
+                .unwrap(),
+            access_token: access_token.into(),
+            identity: None,
+        }
+    }
+
+    /// Whispers `message` to the user with the id `to_user_id`.
+    pub async fn send(&mut self, to_user_id: &str, message: &str) -> anyhow::Result<()> {
+        if self.identity.is_none() {
+            let body = self
+                .client
+                .get(VALIDATE_URL)
+                .header("Authorization", format!("OAuth {}", self.access_token))
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            self.identity = Some(serde_json::from_str(&body)?);
+        }
+        let identity = self.identity.as_ref().unwrap();
+
+        self.client
+            .post(WHISPERS_URL)
+            .query(&[
+                ("from_user_id", identity.user_id.as_str()),
+                ("to_user_id", to_user_id),
+            ])
+            .header("Authorization", format!("Bearer {}", self.access_token))
+            .header("Client-Id", &identity.client_id)
+            .header("Content-Type", "application/json")
+            .body(serde_json::json!({ "message": message }).to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,354 @@
+use crate::{
+    announcements::Announcement,
+    custom_commands::{CustomCommandError, WriteMode},
+    locale::LocaleError,
+    settings::AnnouncementSettings,
+    stats::StatsReport,
+    templates::Templates,
+    JoinError, StateCommand, StateTx, UserEntry, UserInfo, STATE_CHANNEL_SIZE,
+};
+use chrono::{DateTime, Local};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use tokio::sync::{mpsc::error::TrySendError, oneshot};
+use tokio::time::Duration;
+use uuid::Uuid;
+
+/// How long to wait on the state task before giving up on it.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq)]
+pub enum ClientError {
+    /// The state task has stopped.
+    Stopped,
+    /// The state task didn't answer in time.
+    Timeout,
+    /// The state task has too many requests waiting to take another without waiting.
+    Busy,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Stopped => write!(f, "the queue has stopped"),
+            ClientError::Timeout => write!(f, "the queue took too long to answer"),
+            ClientError::Busy => write!(f, "the queue is too busy"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// A handle on the state task, for the server and the chat bot. Each method sends one
+/// [`StateCommand`] and waits for its answer.
+#[derive(Clone, Debug)]
+pub struct QueueClient {
+    tx: StateTx,
+    timeout: Duration,
+}
+
+impl QueueClient {
+    pub fn new(tx: StateTx) -> QueueClient {
+        QueueClient {
+            tx,
+            timeout: TIMEOUT,
+        }
+    }
+
+    /// Gives up on requests after `timeout` rather than the default of five seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> QueueClient {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many requests are waiting for the state task, for a channel made with
+    /// [`STATE_CHANNEL_SIZE`].
+    pub fn backlog(&self) -> usize {
+        STATE_CHANNEL_SIZE.saturating_sub(self.tx.capacity())
+    }
+
+    fn try_send(&self, command: StateCommand) -> Result<(), ClientError> {
+        self.tx.try_send(command.into()).map_err(|e| match e {
+            TrySendError::Full(_) => ClientError::Busy,
+            TrySendError::Closed(_) => ClientError::Stopped,
+        })
+    }
+
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> StateCommand,
+    ) -> Result<T, ClientError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let request = async {
+            self.tx
+                .send(command(resp_tx).into())
+                .await
+                .map_err(|_| ClientError::Stopped)?;
+            resp_rx.await.map_err(|_| ClientError::Stopped)
+        };
+        tokio::time::timeout(self.timeout, request)
+            .await
+            .map_err(|_| ClientError::Timeout)?
+    }
+
+    /// Adds `user` to the queue, shown in chat as `display_name` if they have one, returning their
+    /// index in it.
+    pub async fn add_user(
+        &self,
+        user: &str,
+        display_name: Option<&str>,
+        ign: Option<String>,
+    ) -> Result<Result<usize, JoinError>, ClientError> {
+        let user = user.to_owned();
+        let display_name = display_name.map(str::to_owned);
+        self.request(|tx| StateCommand::AddUser {
+            user,
+            display_name,
+            ign,
+            tx,
+        })
+        .await
+    }
+
+    /// The whole queue and whether it's open, as the frontend shows it.
+    pub async fn queue(&self) -> Result<serde_json::Value, ClientError> {
+        self.request(StateCommand::GetQueue).await
+    }
+
+    /// Whether the queue is open.
+    pub async fn is_open(&self) -> Result<bool, ClientError> {
+        self.request(StateCommand::GetQueueStatus).await
+    }
+
+    pub async fn queue_length(&self) -> Result<usize, ClientError> {
+        self.request(StateCommand::GetQueueLength).await
+    }
+
+    /// Where `user` is in the queue, if they're in it.
+    pub async fn find(&self, user: &str) -> Result<Option<usize>, ClientError> {
+        let name = user.to_owned();
+        self.request(|tx| StateCommand::FindUser { name, tx }).await
+    }
+
+    /// The first `count` users in the queue.
+    pub async fn peek(&self, count: u16) -> Result<Vec<UserEntry>, ClientError> {
+        self.request(|tx| StateCommand::PeekQueue { count, tx })
+            .await
+    }
+
+    /// Takes the first `count` users off the queue, or `None` if it's empty.
+    pub async fn pop(&self, count: u16) -> Result<Option<Vec<UserEntry>>, ClientError> {
+        self.request(|tx| StateCommand::PopQueue { count, tx })
+            .await
+    }
+
+    /// Takes `user` out of the queue, returning their entry if they were in it.
+    pub async fn remove(&self, user: &str) -> Result<Option<UserEntry>, ClientError> {
+        let user = user.to_owned();
+        self.request(|tx| StateCommand::RemoveUser { user, tx })
+            .await
+    }
+
+    /// Opens the queue if it's closed and closes it if it's open, returning whether it's now open.
+    pub async fn toggle(&self) -> Result<bool, ClientError> {
+        self.request(StateCommand::ToggleQueue).await
+    }
+
+    /// Opens the queue for `minutes`, returning when it will close.
+    pub async fn open_for(&self, minutes: u32) -> Result<DateTime<Local>, ClientError> {
+        self.request(|tx| StateCommand::OpenFor { minutes, tx })
+            .await
+    }
+
+    pub async fn close_at(&self, time: DateTime<Local>) -> Result<(), ClientError> {
+        self.request(|tx| StateCommand::CloseAt { time, tx }).await
+    }
+
+    pub async fn cancel_schedule(&self) -> Result<(), ClientError> {
+        self.request(StateCommand::CancelSchedule).await
+    }
+
+    /// Starts a ready check, returning false if one is already running.
+    pub async fn begin_ready_check(&self) -> Result<bool, ClientError> {
+        self.request(StateCommand::BeginReadyCheck).await
+    }
+
+    /// Pings the first `count` users, returning who was pinged and a receiver that fires once
+    /// they have all answered.
+    pub async fn start_ready_check(
+        &self,
+        count: u16,
+    ) -> Result<(Vec<UserEntry>, oneshot::Receiver<()>), ClientError> {
+        let (all_here, all_here_rx) = oneshot::channel();
+        let pinged = self
+            .request(|tx| StateCommand::StartReadyCheck {
+                count,
+                all_here,
+                tx,
+            })
+            .await?;
+        Ok((pinged, all_here_rx))
+    }
+
+    /// Whether `user` was waiting on a ready check.
+    pub async fn mark_here(&self, user: &str) -> Result<bool, ClientError> {
+        let user = user.to_owned();
+        self.request(|tx| StateCommand::MarkHere { user, tx }).await
+    }
+
+    /// Ends a ready check round, returning who didn't answer.
+    pub async fn finish_ready_round(&self) -> Result<Vec<UserEntry>, ClientError> {
+        self.request(StateCommand::FinishReadyRound).await
+    }
+
+    /// Pops the users who answered the ready check.
+    pub async fn pop_ready(&self, count: u16) -> Result<Option<Vec<UserEntry>>, ClientError> {
+        self.request(|tx| StateCommand::PopReady { count, tx })
+            .await
+    }
+
+    /// Records that `users` are in the channel. Like [`QueueClient::user_parted`], this doesn't
+    /// wait for room in a busy state task, so chat isn't held up by a large channel.
+    pub fn users_joined(&self, users: Vec<String>) -> Result<(), ClientError> {
+        self.try_send(StateCommand::UsersJoined(users))
+    }
+
+    pub fn user_parted(&self, user: String) -> Result<(), ClientError> {
+        self.try_send(StateCommand::UserParted(user))
+    }
+
+    /// Counts a chat message towards announcements. The state task may itself be waiting for the
+    /// bot to take an announcement, so this never waits for room either.
+    pub fn chat_line(&self) -> Result<(), ClientError> {
+        self.try_send(StateCommand::ChatLine)
+    }
+
+    /// Whether `user` was in the queue to have their info set.
+    pub async fn set_user_info(&self, user: &str, info: UserInfo) -> Result<bool, ClientError> {
+        let user = user.to_owned();
+        let set = self
+            .request(|tx| StateCommand::SetUserInfo { user, info, tx })
+            .await?;
+        Ok(set.is_some())
+    }
+
+    /// The templates for `channel`, or for the bot's own channel.
+    pub async fn templates(&self, channel: Option<&str>) -> Result<Templates, ClientError> {
+        let channel = channel.map(str::to_owned);
+        self.request(|tx| StateCommand::GetTemplates { channel, tx })
+            .await
+    }
+
+    /// The templates for `locale`, or for the default locale.
+    pub async fn locale(&self, locale: Option<String>) -> Result<Option<Templates>, ClientError> {
+        self.request(|tx| StateCommand::GetLocale { locale, tx })
+            .await
+    }
+
+    pub async fn set_templates(
+        &self,
+        locale: Option<String>,
+        overrides: HashMap<String, String>,
+    ) -> Result<Result<(), LocaleError>, ClientError> {
+        self.request(|tx| StateCommand::SetTemplates {
+            locale,
+            overrides,
+            tx,
+        })
+        .await
+    }
+
+    pub async fn custom_commands(&self) -> Result<BTreeMap<String, String>, ClientError> {
+        self.request(StateCommand::GetCustomCommands).await
+    }
+
+    pub async fn set_custom_command(
+        &self,
+        name: String,
+        response: String,
+        mode: WriteMode,
+    ) -> Result<Result<(), CustomCommandError>, ClientError> {
+        self.request(|tx| StateCommand::SetCustomCommand {
+            name,
+            response,
+            mode,
+            tx,
+        })
+        .await
+    }
+
+    pub async fn delete_custom_command(
+        &self,
+        name: String,
+    ) -> Result<Result<(), CustomCommandError>, ClientError> {
+        self.request(|tx| StateCommand::DeleteCustomCommand { name, tx })
+            .await
+    }
+
+    pub async fn announcements(&self) -> Result<Vec<Announcement>, ClientError> {
+        self.request(StateCommand::GetAnnouncements).await
+    }
+
+    /// Adds an announcement, or replaces the one with `id`. Returns its id, or `None` if there is
+    /// no announcement to replace.
+    pub async fn set_announcement(
+        &self,
+        id: Option<Uuid>,
+        announcement: AnnouncementSettings,
+    ) -> Result<Option<Uuid>, ClientError> {
+        self.request(|tx| StateCommand::SetAnnouncement {
+            id,
+            announcement,
+            tx,
+        })
+        .await
+    }
+
+    /// How the queue has been used since it was last opened.
+    pub async fn stats(&self) -> Result<StatsReport, ClientError> {
+        self.request(StateCommand::GetStats).await
+    }
+
+    /// Everyone banned from the queue.
+    pub async fn bans(&self) -> Result<Vec<String>, ClientError> {
+        self.request(StateCommand::GetBans).await
+    }
+
+    /// Bans `user` and takes them out of the queue. False if they were already banned.
+    pub async fn ban(&self, user: &str) -> Result<bool, ClientError> {
+        let user = user.to_owned();
+        self.request(|tx| StateCommand::BanUser { user, tx }).await
+    }
+
+    /// False if `user` wasn't banned.
+    pub async fn unban(&self, user: &str) -> Result<bool, ClientError> {
+        let user = user.to_owned();
+        self.request(|tx| StateCommand::UnbanUser { user, tx })
+            .await
+    }
+
+    /// Whether there was an announcement with `id` to delete.
+    pub async fn delete_announcement(&self, id: Uuid) -> Result<bool, ClientError> {
+        let deleted = self
+            .request(|tx| StateCommand::DeleteAnnouncement { id, tx })
+            .await?;
+        Ok(deleted.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn errors_when_the_state_task_is_gone_or_stuck() {
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        let client = QueueClient::new(tx).with_timeout(Duration::from_millis(10));
+
+        // Nothing reads the requests, so nothing answers
+        assert_eq!(client.toggle().await, Err(ClientError::Timeout));
+
+        drop(rx);
+        assert_eq!(client.toggle().await, Err(ClientError::Stopped));
+    }
+}
@@ -0,0 +1,102 @@
+use crate::settings::CooldownSettings;
+use chrono::{DateTime, Duration, Local};
+use std::collections::HashMap;
+
+/// When each command was last used, to hold off repeats until its cooldowns have run out.
+#[derive(Debug, Default)]
+pub struct Cooldowns {
+    settings: HashMap<String, CooldownSettings>,
+    /// Command name to when anyone last used it.
+    global: HashMap<String, DateTime<Local>>,
+    /// Command name and user to when that user last used it.
+    users: HashMap<(String, String), DateTime<Local>>,
+}
+
+impl Cooldowns {
+    pub fn new(settings: &HashMap<String, CooldownSettings>) -> Cooldowns {
+        Cooldowns {
+            settings: settings
+                .iter()
+                .map(|(command, cooldown)| (command.to_lowercase(), cooldown.clone()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether `user` may use `command` now. If they may, the use is recorded and starts the
+    /// command's cooldowns.
+    pub fn try_use(&mut self, command: &str, user: &str, now: DateTime<Local>) -> bool {
+        let cooldown = match self.settings.get(command) {
+            Some(cooldown) => cooldown,
+            None => return true,
+        };
+        let global = Duration::seconds(cooldown.global_secs as i64);
+        let per_user = Duration::seconds(cooldown.user_secs as i64);
+        let user_key = (command.to_owned(), user.to_lowercase());
+
+        let cooling = |last: Option<&DateTime<Local>>, length: Duration| matches!(last, Some(&last) if now < last + length);
+        if cooling(self.global.get(command), global) || cooling(self.users.get(&user_key), per_user)
+        {
+            return false;
+        }
+
+        self.global.insert(command.to_owned(), now);
+        self.users.insert(user_key, now);
+        // Forget users whose cooldowns have run out so the map doesn't grow with every chatter
+        let settings = &self.settings;
+        self.users.retain(|(command, _), last| {
+            settings
+                .get(command)
+                .is_some_and(|cooldown| now < *last + Duration::seconds(cooldown.user_secs as i64))
+        });
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cooldowns() -> Cooldowns {
+        let settings = vec![(
+            "place".to_owned(),
+            CooldownSettings {
+                global_secs: 5,
+                user_secs: 30,
+            },
+        )]
+        .into_iter()
+        .collect();
+        Cooldowns::new(&settings)
+    }
+
+    #[test]
+    fn global_cooldown_applies_to_everyone() {
+        let mut cooldowns = cooldowns();
+        let now = Local::now();
+
+        assert!(cooldowns.try_use("place", "foo", now));
+        assert!(!cooldowns.try_use("place", "bar", now + Duration::seconds(4)));
+        assert!(cooldowns.try_use("place", "bar", now + Duration::seconds(5)));
+    }
+
+    #[test]
+    fn user_cooldown_applies_per_user() {
+        let mut cooldowns = cooldowns();
+        let now = Local::now();
+
+        assert!(cooldowns.try_use("place", "foo", now));
+        assert!(!cooldowns.try_use("place", "FOO", now + Duration::seconds(10)));
+        assert!(cooldowns.try_use("place", "bar", now + Duration::seconds(10)));
+        assert!(cooldowns.try_use("place", "foo", now + Duration::seconds(30)));
+    }
+
+    #[test]
+    fn commands_without_cooldowns_are_always_allowed() {
+        let mut cooldowns = cooldowns();
+        let now = Local::now();
+
+        assert!(cooldowns.try_use("join", "foo", now));
+        assert!(cooldowns.try_use("join", "foo", now));
+    }
+}
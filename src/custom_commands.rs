@@ -0,0 +1,244 @@
+use crate::{
+    store::QueueStore,
+    templates::{self, TemplateError},
+};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+/// The placeholders a custom command's response may use.
+pub const PLACEHOLDERS: &[&str] = &["user", "args", "queue_length"];
+
+const MAX_NAME_LEN: usize = 25;
+
+/// The store setting the commands are saved under.
+const STORE_KEY: &str = "custom_commands";
+
+/// Whether `set` may add a new command, replace an existing one, or both.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WriteMode {
+    Create,
+    Replace,
+    Any,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CustomCommandError {
+    /// Names may only use letters, digits and underscores.
+    InvalidName(String),
+    Exists(String),
+    NotFound(String),
+    Template(TemplateError),
+}
+
+impl fmt::Display for CustomCommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CustomCommandError::InvalidName(name) => write!(
+                f,
+                "`{}` must be 1 to {} letters, digits or underscores",
+                name, MAX_NAME_LEN
+            ),
+            CustomCommandError::Exists(name) => write!(f, "`{}` already exists", name),
+            CustomCommandError::NotFound(name) => write!(f, "`{}` doesn't exist", name),
+            CustomCommandError::Template(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for CustomCommandError {}
+
+/// Commands that reply with a fixed response, added by the streamer or moderators at runtime and
+/// saved along with the queue.
+#[derive(Debug, Default)]
+pub struct CustomCommands {
+    commands: BTreeMap<String, String>,
+    /// Names taken by built-in commands and their aliases.
+    reserved: HashSet<String>,
+    /// The locales responses are rendered in, which decide the plural forms they give. English
+    /// if there are none.
+    locales: Vec<String>,
+}
+
+impl CustomCommands {
+    /// The commands saved in `store`, if any.
+    pub fn load(store: &dyn QueueStore) -> CustomCommands {
+        let commands = store
+            .setting(STORE_KEY)
+            .and_then(|saved| {
+                serde_json::from_str(&saved)
+                    .inspect_err(|e| {
+                        tracing::warn!("Could not read the saved custom commands: {}", e)
+                    })
+                    .ok()
+            })
+            .unwrap_or_default();
+        CustomCommands {
+            commands,
+            ..Default::default()
+        }
+    }
+
+    pub fn save(&self, store: &mut dyn QueueStore) {
+        store.set_setting(STORE_KEY, &serde_json::to_string(&self.commands).unwrap());
+    }
+
+    // keep commands from taking these names
+    pub fn reserve(&mut self, names: impl IntoIterator<Item = String>) {
+        self.reserved.extend(names);
+    }
+
+    // check responses against the plural rules of these locales
+    pub fn render_in(&mut self, locales: impl IntoIterator<Item = String>) {
+        self.locales = locales.into_iter().collect();
+    }
+
+    pub fn all(&self) -> &BTreeMap<String, String> {
+        &self.commands
+    }
+
+    /// Sets the response of the command `name`, lowercased.
+    pub fn set(
+        &mut self,
+        name: &str,
+        response: String,
+        mode: WriteMode,
+    ) -> Result<(), CustomCommandError> {
+        let name = name.to_lowercase();
+        if name.is_empty()
+            || name.len() > MAX_NAME_LEN
+            || !name.chars().all(|c| c.is_alphanumeric() || c == '_')
+        {
+            return Err(CustomCommandError::InvalidName(name));
+        }
+        if self.reserved.contains(&name) {
+            return Err(CustomCommandError::Exists(name));
+        }
+        match (mode, self.commands.contains_key(&name)) {
+            (WriteMode::Create, true) => return Err(CustomCommandError::Exists(name)),
+            (WriteMode::Replace, false) => return Err(CustomCommandError::NotFound(name)),
+            _ => {}
+        }
+        self.check(&response)
+            .map_err(CustomCommandError::Template)?;
+
+        self.commands.insert(name, response);
+        Ok(())
+    }
+
+    /// Deletes the command `name`.
+    pub fn remove(&mut self, name: &str) -> Result<(), CustomCommandError> {
+        let name = name.to_lowercase();
+        match self.commands.remove(&name) {
+            Some(_) => Ok(()),
+            None => Err(CustomCommandError::NotFound(name)),
+        }
+    }
+
+    // The response is the same in every channel, so its plurals need only fit one of the locales
+    // the channels speak
+    fn check(&self, response: &str) -> Result<(), TemplateError> {
+        let check = |locale: &str| templates::check("response", response, PLACEHOLDERS, locale);
+        let first = self.locales.first().map_or("en", String::as_str);
+        check(first).or_else(|e| {
+            if self
+                .locales
+                .iter()
+                .skip(1)
+                .any(|locale| check(locale).is_ok())
+            {
+                Ok(())
+            } else {
+                Err(e)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn create_and_replace_modes() {
+        let mut commands = CustomCommands::default();
+
+        assert_eq!(
+            commands.set("Discord", "discord.gg/x".to_owned(), WriteMode::Create),
+            Ok(())
+        );
+        assert_eq!(
+            commands.set("discord", "y".to_owned(), WriteMode::Create),
+            Err(CustomCommandError::Exists("discord".to_owned()))
+        );
+        assert_eq!(
+            commands.set("rules", "y".to_owned(), WriteMode::Replace),
+            Err(CustomCommandError::NotFound("rules".to_owned()))
+        );
+        assert_eq!(
+            commands.set("discord", "discord.gg/y".to_owned(), WriteMode::Replace),
+            Ok(())
+        );
+        assert_eq!(commands.all()["discord"], "discord.gg/y");
+    }
+
+    #[test]
+    fn rejects_bad_names_and_responses() {
+        let mut commands = CustomCommands::default();
+
+        assert!(matches!(
+            commands.set("!nope", "x".to_owned(), WriteMode::Any),
+            Err(CustomCommandError::InvalidName(_))
+        ));
+        assert!(matches!(
+            commands.set("queue", "{position}".to_owned(), WriteMode::Any),
+            Err(CustomCommandError::Template(_))
+        ));
+        assert!(commands.all().is_empty());
+    }
+
+    #[test]
+    fn reserved_names_are_taken() {
+        let mut commands = CustomCommands::default();
+        commands.reserve(vec!["join".to_owned()]);
+
+        assert_eq!(
+            commands.set("JOIN", "x".to_owned(), WriteMode::Any),
+            Err(CustomCommandError::Exists("join".to_owned()))
+        );
+    }
+
+    #[test]
+    fn plurals_fit_a_spoken_locale() {
+        let mut commands = CustomCommands::default();
+        let three = "{queue_length:# osoba|# osoby|# osób}";
+        assert!(commands.set("q", three.to_owned(), WriteMode::Any).is_err());
+
+        commands.render_in(vec!["en".to_owned(), "pl".to_owned()]);
+        assert_eq!(commands.set("q", three.to_owned(), WriteMode::Any), Ok(()));
+        let two = "{queue_length:# person|# people}";
+        assert_eq!(commands.set("q", two.to_owned(), WriteMode::Any), Ok(()));
+
+        commands.render_in(vec!["pl".to_owned()]);
+        assert!(commands.set("q", two.to_owned(), WriteMode::Any).is_err());
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let mut store = MemoryStore::default();
+
+        let mut commands = CustomCommands::load(&store);
+        commands
+            .set("rules", "Be nice, {user}".to_owned(), WriteMode::Any)
+            .unwrap();
+        commands
+            .set("discord", "discord.gg/x".to_owned(), WriteMode::Any)
+            .unwrap();
+        commands.remove("discord").unwrap();
+        commands.save(&mut store);
+
+        let loaded = CustomCommands::load(&store);
+        assert_eq!(loaded.all(), commands.all());
+        assert_eq!(loaded.all().len(), 1);
+    }
+}
@@ -1,29 +1,58 @@
+use announcements::{Announcement, Announcements};
 use chrono::prelude::*;
 use chrono::Local;
+use custom_commands::{CustomCommandError, CustomCommands, WriteMode};
 use irc::client::prelude::*;
+use locale::{LocaleError, Locales};
+use opentelemetry::KeyValue;
+use ready_check::ReadyCheck;
+use schedule::Schedule;
 use serde::{Deserialize, Serialize, Serializer};
-use std::collections::{HashMap, VecDeque};
+use settings::{AnnouncementSettings, OnTimeout, QueueSettings, Settings, TelemetrySettings};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::OnceLock;
+use store::QueueStore;
+use templates::Templates;
 use tokio::sync::oneshot;
-use tracing::{subscriber::set_global_default, Subscriber};
+use tracing::{subscriber::set_global_default, Instrument, Subscriber};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
-use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
+use tracing_subscriber::{layer::SubscriberExt, reload, EnvFilter, Registry};
 use uuid::Uuid;
 
-pub type StateTx = tokio::sync::mpsc::Sender<StateCommand>;
+pub type StateTx = tokio::sync::mpsc::Sender<StateMessage>;
 pub type StateRx<T> = tokio::sync::oneshot::Receiver<T>;
 
+/// How many requests can wait on the state task before senders have to wait too.
+pub const STATE_CHANNEL_SIZE: usize = 32;
+
+pub mod announcements;
 pub mod chatbot;
+pub mod client;
+pub mod cooldown;
+pub mod custom_commands;
+pub mod locale;
+pub mod metrics;
+pub mod presence;
+pub mod ready_check;
+pub mod schedule;
 pub mod server;
+pub mod settings;
+pub mod stats;
+pub mod store;
+pub mod templates;
 
 #[derive(Debug)]
 pub enum StateCommand {
     AddUser {
         user: String,
-        tx: oneshot::Sender<usize>,
+        display_name: Option<String>,
+        ign: Option<String>,
+        tx: oneshot::Sender<Result<usize, JoinError>>,
     },
     GetQueue(oneshot::Sender<serde_json::Value>),
     GetQueueStatus(oneshot::Sender<bool>),
+    GetQueueLength(oneshot::Sender<usize>),
     FindUser {
         name: String,
         tx: oneshot::Sender<Option<usize>>,
@@ -38,9 +67,163 @@ pub enum StateCommand {
     },
     RemoveUser {
         user: String,
-        tx: oneshot::Sender<Option<()>>,
+        tx: oneshot::Sender<Option<UserEntry>>,
     },
     ToggleQueue(oneshot::Sender<bool>),
+    OpenFor {
+        minutes: u32,
+        tx: oneshot::Sender<DateTime<Local>>,
+    },
+    CloseAt {
+        time: DateTime<Local>,
+        tx: oneshot::Sender<()>,
+    },
+    CancelSchedule(oneshot::Sender<()>),
+    /// Starts a ready check. Replies false if one is already running.
+    BeginReadyCheck(oneshot::Sender<bool>),
+    StartReadyCheck {
+        count: u16,
+        all_here: oneshot::Sender<()>,
+        tx: oneshot::Sender<Vec<UserEntry>>,
+    },
+    MarkHere {
+        user: String,
+        tx: oneshot::Sender<bool>,
+    },
+    FinishReadyRound(oneshot::Sender<Vec<UserEntry>>),
+    PopReady {
+        count: u16,
+        tx: oneshot::Sender<Option<Vec<UserEntry>>>,
+    },
+    /// People joined the channel, or were already in it.
+    UsersJoined(Vec<String>),
+    UserParted(String),
+    /// Someone said something in chat.
+    ChatLine,
+    SetUserInfo {
+        user: String,
+        info: UserInfo,
+        tx: oneshot::Sender<Option<()>>,
+    },
+    /// Templates for a channel, or for the bot's own channel when `None`.
+    GetTemplates {
+        channel: Option<String>,
+        tx: oneshot::Sender<Templates>,
+    },
+    /// Templates for a locale, or for the default locale when `None`.
+    GetLocale {
+        locale: Option<String>,
+        tx: oneshot::Sender<Option<Templates>>,
+    },
+    SetTemplates {
+        locale: Option<String>,
+        overrides: HashMap<String, String>,
+        tx: oneshot::Sender<Result<(), LocaleError>>,
+    },
+    /// Every custom command, by name, with its response.
+    GetCustomCommands(oneshot::Sender<BTreeMap<String, String>>),
+    SetCustomCommand {
+        name: String,
+        response: String,
+        mode: WriteMode,
+        tx: oneshot::Sender<Result<(), CustomCommandError>>,
+    },
+    DeleteCustomCommand {
+        name: String,
+        tx: oneshot::Sender<Result<(), CustomCommandError>>,
+    },
+    GetAnnouncements(oneshot::Sender<Vec<Announcement>>),
+    /// Adds an announcement, or replaces the one with `id`. Replies with its id, or `None` if
+    /// there is no announcement to replace.
+    SetAnnouncement {
+        id: Option<Uuid>,
+        announcement: AnnouncementSettings,
+        tx: oneshot::Sender<Option<Uuid>>,
+    },
+    DeleteAnnouncement {
+        id: Uuid,
+        tx: oneshot::Sender<Option<()>>,
+    },
+    /// How the queue has been used since it was last opened.
+    GetStats(oneshot::Sender<stats::StatsReport>),
+    /// Everyone who may not join the queue.
+    GetBans(oneshot::Sender<Vec<String>>),
+    /// Bans `user` and takes them out of the queue. Replies false if they were already banned.
+    BanUser {
+        user: String,
+        tx: oneshot::Sender<bool>,
+    },
+    /// Replies false if `user` wasn't banned.
+    UnbanUser {
+        user: String,
+        tx: oneshot::Sender<bool>,
+    },
+}
+
+impl StateCommand {
+    /// The variant's name, for tracing.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StateCommand::AddUser { .. } => "AddUser",
+            StateCommand::GetQueue(_) => "GetQueue",
+            StateCommand::GetQueueStatus(_) => "GetQueueStatus",
+            StateCommand::GetQueueLength(_) => "GetQueueLength",
+            StateCommand::FindUser { .. } => "FindUser",
+            StateCommand::PeekQueue { .. } => "PeekQueue",
+            StateCommand::PopQueue { .. } => "PopQueue",
+            StateCommand::RemoveUser { .. } => "RemoveUser",
+            StateCommand::ToggleQueue(_) => "ToggleQueue",
+            StateCommand::OpenFor { .. } => "OpenFor",
+            StateCommand::CloseAt { .. } => "CloseAt",
+            StateCommand::CancelSchedule(_) => "CancelSchedule",
+            StateCommand::BeginReadyCheck(_) => "BeginReadyCheck",
+            StateCommand::StartReadyCheck { .. } => "StartReadyCheck",
+            StateCommand::MarkHere { .. } => "MarkHere",
+            StateCommand::FinishReadyRound(_) => "FinishReadyRound",
+            StateCommand::PopReady { .. } => "PopReady",
+            StateCommand::UsersJoined(_) => "UsersJoined",
+            StateCommand::UserParted(_) => "UserParted",
+            StateCommand::ChatLine => "ChatLine",
+            StateCommand::SetUserInfo { .. } => "SetUserInfo",
+            StateCommand::GetTemplates { .. } => "GetTemplates",
+            StateCommand::GetLocale { .. } => "GetLocale",
+            StateCommand::SetTemplates { .. } => "SetTemplates",
+            StateCommand::GetCustomCommands(_) => "GetCustomCommands",
+            StateCommand::SetCustomCommand { .. } => "SetCustomCommand",
+            StateCommand::DeleteCustomCommand { .. } => "DeleteCustomCommand",
+            StateCommand::GetAnnouncements(_) => "GetAnnouncements",
+            StateCommand::SetAnnouncement { .. } => "SetAnnouncement",
+            StateCommand::DeleteAnnouncement { .. } => "DeleteAnnouncement",
+            StateCommand::GetStats(_) => "GetStats",
+            StateCommand::GetBans(_) => "GetBans",
+            StateCommand::BanUser { .. } => "BanUser",
+            StateCommand::UnbanUser { .. } => "UnbanUser",
+        }
+    }
+}
+
+/// A [`StateCommand`] and the span it was sent from, so the state task's work shows up under the
+/// chat command or API request that asked for it.
+#[derive(Debug)]
+pub struct StateMessage {
+    pub command: StateCommand,
+    pub span: tracing::Span,
+}
+
+impl From<StateCommand> for StateMessage {
+    fn from(command: StateCommand) -> Self {
+        StateMessage {
+            command,
+            span: tracing::Span::current(),
+        }
+    }
+}
+
+/// Details a user can attach to their queue entry. Fields left as `None` are unchanged.
+#[derive(Debug, Default)]
+pub struct UserInfo {
+    pub ign: Option<String>,
+    pub note: Option<String>,
 }
 
 fn serialize_datetime<S>(date_time: &DateTime<Local>, s: S) -> Result<S::Ok, S::Error>
@@ -51,57 +234,140 @@ where
     s.serialize_str(&date_time.format(TIME_FMT).to_string())
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct UserEntry {
     pub nickname: String,
+    /// How the user is shown in chat, when their nickname is only an id. Discord users are queued
+    /// by id, so they can't be mistaken for Twitch users with the same name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
     #[serde(serialize_with = "serialize_datetime")]
     pub time_joined: DateTime<Local>,
     pub id: Uuid,
+    /// In-game name, for the streamer to invite the user with.
+    pub ign: Option<String>,
+    pub note: Option<String>,
+    /// False once the user has been gone from the channel for longer than the grace period.
+    pub present: bool,
+    #[serde(skip)]
+    pub parted_at: Option<DateTime<Local>>,
 }
 
-#[derive(Default, Deserialize, Serialize)]
-pub struct Queue {
-    pub queue: VecDeque<UserEntry>,
-    pub is_open: bool,
+impl UserEntry {
+    pub fn new(nickname: String) -> UserEntry {
+        UserEntry {
+            nickname,
+            display_name: None,
+            time_joined: Local::now(),
+            id: Uuid::new_v4(),
+            ign: None,
+            note: None,
+            present: true,
+            parted_at: None,
+        }
+    }
+
+    /// The name to show in chat.
+    pub fn name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.nickname)
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Token {
-    pub access_token: String,
-    scope: String,
-    token_type: String,
+#[derive(Debug, PartialEq)]
+pub enum JoinError {
+    /// The queue has been closed by the streamer.
+    Closed,
+    /// The queue reached its capacity and closed itself.
+    Full,
+    /// The user has been banned from the queue.
+    Banned,
 }
 
-pub fn pop(num: u16, user_queue: &mut VecDeque<UserEntry>) -> Option<Vec<UserEntry>> {
-    let mut popped_entries = Vec::new();
-    for _ in 0..num {
-        if let Some(x) = user_queue.pop_front() {
-            popped_entries.push(x);
+pub struct Queue {
+    pub store: Box<dyn QueueStore>,
+    /// Kept in the store so the queue is still open, or closed, after a restart.
+    is_open: bool,
+    pub capacity: Option<usize>,
+    pub reopen_below: Option<usize>,
+    /// Set when the queue closed itself because it was full, as opposed to being closed by hand.
+    pub auto_closed: bool,
+}
+
+impl Queue {
+    pub fn new(settings: &QueueSettings, store: Box<dyn QueueStore>) -> Queue {
+        Queue {
+            is_open: store.setting("is_open").as_deref() == Some("true"),
+            store,
+            capacity: settings.capacity,
+            reopen_below: settings.reopen_below,
+            auto_closed: false,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.is_open
+    }
+
+    pub fn set_open(&mut self, is_open: bool) {
+        self.is_open = is_open;
+        self.store
+            .set_setting("is_open", if is_open { "true" } else { "false" });
+    }
+
+    pub fn is_full(&self) -> bool {
+        matches!(self.capacity, Some(capacity) if self.store.len() >= capacity)
+    }
+
+    /// The queue as the frontend shows it, with when it's scheduled to close.
+    pub fn to_json(&self, closes_at: Option<DateTime<Local>>) -> serde_json::Value {
+        serde_json::json!({
+            "queue": self.store.entries(),
+            "is_open": self.is_open,
+            "capacity": self.capacity,
+            "closes_at": closes_at,
+        })
+    }
+
+    /// Closes the queue if it just filled up. Returns true if the queue was closed.
+    fn close_if_full(&mut self) -> bool {
+        if self.is_open && self.is_full() {
+            self.set_open(false);
+            self.auto_closed = true;
+            true
+        } else {
+            false
         }
     }
-    if popped_entries.is_empty() {
-        None
-    } else {
-        Some(popped_entries)
+
+    /// Reopens a queue that closed itself once it has drained below the reopen threshold.
+    /// Returns true if the queue was reopened.
+    fn reopen_if_drained(&mut self) -> bool {
+        match self.reopen_below {
+            Some(threshold) if self.auto_closed && self.store.len() < threshold => {
+                self.set_open(true);
+                self.auto_closed = false;
+                true
+            }
+            _ => false,
+        }
     }
 }
 
-pub fn get_user_config(token: &str) -> Config {
-    let mut settings = config::Config::default();
-    settings
-        .merge(config::File::with_name("Settings").required(false))
-        .unwrap()
-        .merge(config::Environment::with_prefix("TWITCH"))
-        .unwrap();
-    let config = settings.try_into::<HashMap<String, String>>().unwrap();
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Token {
+    pub access_token: String,
+    scope: String,
+    token_type: String,
+}
 
-    let name = match config.get("name") {
+pub fn get_user_config(token: &str, settings: &Settings) -> Config {
+    let name = match &settings.name {
         Some(n) => n,
         None => {
             panic!("Expected `name` in Settings.toml or TWITCH_NAME environment variable");
         }
     };
-    let channel = match config.get("channel") {
+    let channel = match &settings.channel {
         Some(n) => {
             if n.starts_with('#') {
                 n.clone()
@@ -124,26 +390,89 @@ pub fn get_user_config(token: &str) -> Config {
     }
 }
 
-pub fn find(nickname: &str, user_queue: &VecDeque<UserEntry>) -> Option<usize> {
-    user_queue
-        .iter()
-        .position(|entry: &UserEntry| entry.nickname == nickname)
-}
-
-pub fn remove(name: &str, user_queue: &mut VecDeque<UserEntry>) -> Option<()> {
-    match find(name, user_queue) {
-        Some(index) => user_queue.remove(index).map(|_| ()),
-        None => None,
-    }
-}
-
-pub fn subscriber_init() -> impl Subscriber {
+/// Bunyan logs on stdout, and spans exported over OTLP too when a collector is configured. Has
+/// to be called from within the tokio runtime, which exports the spans.
+pub fn subscriber_init(settings: &TelemetrySettings) -> impl Subscriber {
     let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let (env_filter, handle) = reload::Layer::new(env_filter);
+    // Only the first subscriber's filter can be changed, which is the one that gets registered
+    let _ = LOG_FILTER.set(handle);
     let formatting_layer = BunyanFormattingLayer::new("qbot".into(), std::io::stdout);
+    let otlp_layer = settings.otlp_endpoint.as_ref().and_then(|endpoint| {
+        match otlp_tracer(endpoint, &settings.service_name) {
+            Ok(tracer) => Some(tracing_opentelemetry::layer().with_tracer(tracer)),
+            Err(e) => {
+                // There's no subscriber to log this with yet
+                eprintln!("Could not export traces to {}: {}", endpoint, e);
+                None
+            }
+        }
+    });
     Registry::default()
         .with(env_filter)
         .with(JsonStorageLayer)
         .with(formatting_layer)
+        .with(otlp_layer)
+}
+
+fn otlp_tracer(
+    endpoint: &str,
+    service_name: &str,
+) -> Result<opentelemetry::sdk::trace::Tracer, opentelemetry::trace::TraceError> {
+    let resource = opentelemetry::sdk::Resource::new(vec![KeyValue::new(
+        "service.name",
+        service_name.to_owned(),
+    )]);
+    opentelemetry_otlp::new_pipeline()
+        .with_endpoint(endpoint)
+        .with_trace_config(opentelemetry::sdk::trace::config().with_resource(resource))
+        .with_tonic()
+        .install_batch(opentelemetry::runtime::Tokio)
+}
+
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+#[derive(Debug, PartialEq)]
+pub enum LogFilterError {
+    /// The directives aren't in `RUST_LOG` syntax.
+    Invalid(String),
+    /// [`subscriber_init`] hasn't been called, or its subscriber is gone.
+    NoSubscriber,
+}
+
+impl std::fmt::Display for LogFilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogFilterError::Invalid(e) => write!(f, "Invalid log filter: {}", e),
+            LogFilterError::NoSubscriber => write!(f, "Logging isn't set up"),
+        }
+    }
+}
+
+impl std::error::Error for LogFilterError {}
+
+/// The directives logs are currently filtered with, like `info,irc=trace`.
+pub fn log_filter() -> Result<String, LogFilterError> {
+    LOG_FILTER
+        .get()
+        .and_then(|handle| handle.with_current(|filter| filter.to_string()).ok())
+        .ok_or(LogFilterError::NoSubscriber)
+}
+
+/// Replaces the log filter set from `RUST_LOG` at startup with `directives`, in the same syntax.
+pub fn set_log_filter(directives: &str) -> Result<(), LogFilterError> {
+    let filter =
+        EnvFilter::try_new(directives).map_err(|e| LogFilterError::Invalid(e.to_string()))?;
+    LOG_FILTER
+        .get()
+        .ok_or(LogFilterError::NoSubscriber)?
+        .reload(filter)
+        .map_err(|_| LogFilterError::NoSubscriber)
+}
+
+/// Sends any spans that haven't been exported yet.
+pub fn subscriber_shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
 }
 
 pub fn register_subscriber<T>(subscriber: T)
@@ -154,65 +483,696 @@ where
     set_global_default(subscriber).expect("Failed to set subscriber");
 }
 
-pub async fn init_state(
-    mut state_rx: tokio::sync::mpsc::Receiver<StateCommand>,
-) -> tokio::task::JoinHandle<Result<(), anyhow::Error>> {
-    tokio::spawn(async move {
+// Responses are always sent before announcing so a chat handler waiting on the state task can
+// return and let the bot drain its command channel.
+async fn announce(chat_tx: &chatbot::Tx, message: &str) {
+    if chat_tx
+        .send(chatbot::Commands::SendMessage(message.to_owned()))
+        .await
+        .is_err()
+    {
+        tracing::warn!("Chatbot is gone, dropping announcement: {}", message);
+    }
+}
+
+/// Calls up a popped group in chat.
+pub fn pop_message(templates: &Templates, popped: &[UserEntry]) -> String {
+    let group = popped
+        .iter()
+        .map(|u| {
+            let note = u.note.as_deref().unwrap_or_default();
+            match &u.ign {
+                Some(ign) => templates.render(
+                    &templates.pop_member_ign,
+                    &[("user", &u.name()), ("ign", ign), ("note", &note)],
+                ),
+                None => templates.render(
+                    &templates.pop_member,
+                    &[("user", &u.name()), ("note", &note)],
+                ),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+    templates.render(
+        &templates.pop,
+        &[("group", &group), ("count", &popped.len())],
+    )
+}
+
+// Tells the bot to register, or without a response unregister, a custom command
+async fn update_custom_command(chat_tx: &chatbot::Tx, name: String, response: Option<String>) {
+    if chat_tx
+        .send(chatbot::Commands::CustomCommand { name, response })
+        .await
+        .is_err()
+    {
+        tracing::warn!("Chatbot is gone, it will load custom commands when it starts");
+    }
+}
+
+async fn run_scheduled(
+    event: schedule::Event,
+    state: &mut Queue,
+    templates: &Templates,
+    chat_tx: &chatbot::Tx,
+) {
+    match event {
+        schedule::Event::Open { until } => {
+            state.set_open(true);
+            state.auto_closed = false;
+            let time = until.format("%H:%M").to_string();
+            announce(
+                chat_tx,
+                &templates.render(&templates.scheduled_open, &[("time", &time)]),
+            )
+            .await;
+        }
+        schedule::Event::Warn(minutes) => {
+            if state.is_open() {
+                announce(
+                    chat_tx,
+                    &templates.render(&templates.closing_warning, &[("minutes", &minutes)]),
+                )
+                .await;
+            }
+        }
+        schedule::Event::Close => {
+            // Closing by hand already cancels the schedule, but a full queue closes by itself
+            let was_open = state.is_open();
+            state.set_open(false);
+            state.auto_closed = false;
+            if was_open {
+                announce(chat_tx, &templates.queue_closed).await;
+            }
+        }
+    }
+}
+
+/// Everything the state task owns, so each command can be handled on its own.
+struct StateTask {
+    state: Queue,
+    schedule: Schedule,
+    ready_check: Option<ReadyCheck>,
+    locales: Locales,
+    custom_commands: CustomCommands,
+    announcements: Announcements,
+    stats: stats::Stats,
+    chat_tx: chatbot::Tx,
+    settings: Settings,
+}
+
+impl StateTask {
+    async fn handle_command(&mut self, command: StateCommand) {
         use crate::StateCommand::*;
-        let mut state = Queue {
-            queue: VecDeque::new(),
-            is_open: false,
-        };
+        let StateTask {
+            state,
+            schedule,
+            ready_check,
+            locales,
+            custom_commands,
+            announcements,
+            stats,
+            chat_tx,
+            settings,
+        } = self;
+        match command {
+            AddUser {
+                user,
+                display_name,
+                ign,
+                tx,
+            } => {
+                let pos = state.store.find(&user);
 
-        while let Some(command) = state_rx.recv().await {
-            match command {
-                AddUser { user, tx } => {
-                    let pos = find(&user, &state.queue);
-
-                    if let Some(index) = pos {
-                        tx.send(index).unwrap();
-                    } else {
-                        state.queue.push_back(UserEntry {
-                            nickname: user,
-                            time_joined: Local::now(),
-                            id: Uuid::new_v4(),
-                        });
-                        tx.send(state.queue.len() - 1).unwrap();
+                if let Some(index) = pos {
+                    if ign.is_some() {
+                        state
+                            .store
+                            .update(index, &mut |entry| entry.ign = ign.clone());
+                    }
+                    let _ = tx.send(Ok(index));
+                } else if state.store.is_banned(&user) {
+                    let _ = tx.send(Err(JoinError::Banned));
+                } else if state.is_full() {
+                    let _ = tx.send(Err(JoinError::Full));
+                } else if !state.is_open() {
+                    let _ = tx.send(Err(JoinError::Closed));
+                } else {
+                    stats.joined(&user, state.store.len() + 1);
+                    let mut entry = UserEntry::new(user);
+                    entry.display_name = display_name;
+                    entry.ign = ign;
+                    state.store.push(entry);
+                    let _ = tx.send(Ok(state.store.len() - 1));
+
+                    if state.close_if_full() {
+                        announce(chat_tx, &locales.for_channel(None).queue_filled).await;
                     }
                 }
-                GetQueue(tx) => {
-                    tx.send(serde_json::to_value(&state).unwrap()).unwrap();
+            }
+            GetQueue(tx) => {
+                let _ = tx.send(state.to_json(schedule.closes_at()));
+            }
+
+            GetQueueStatus(tx) => {
+                let _ = tx.send(state.is_open());
+            }
+
+            GetQueueLength(tx) => {
+                let _ = tx.send(state.store.len());
+            }
+
+            FindUser { name, tx } => {
+                let _ = tx.send(state.store.find(&name));
+            }
+
+            PeekQueue { count, tx } => {
+                let first_n: Vec<_> = state
+                    .store
+                    .entries()
+                    .iter()
+                    .take(count as usize)
+                    .cloned()
+                    .collect();
+                let _ = tx.send(first_n);
+            }
+
+            PopQueue { count, tx } => {
+                let popped_users = state.store.pop(count as usize);
+                stats.popped(&popped_users, Local::now());
+                let _ = tx.send(Some(popped_users).filter(|popped| !popped.is_empty()));
+
+                if state.reopen_if_drained() {
+                    announce(chat_tx, &locales.for_channel(None).queue_reopened).await;
+                }
+            }
+
+            RemoveUser { user, tx } => {
+                let removed = state.store.remove(&user);
+                stats.left(removed.as_ref().map_or(0, |_| 1));
+                let _ = tx.send(removed);
+
+                if state.reopen_if_drained() {
+                    announce(chat_tx, &locales.for_channel(None).queue_reopened).await;
+                }
+            }
+
+            ToggleQueue(tx) => {
+                state.set_open(!state.is_open());
+                state.auto_closed = false;
+                if state.is_open() {
+                    stats.opened(Local::now(), state.store.len());
+                } else {
+                    schedule.cancel();
+                }
+                let _ = tx.send(state.is_open());
+            }
+
+            OpenFor { minutes, tx } => {
+                let until = Local::now() + chrono::Duration::minutes(minutes as i64);
+                schedule.close_at(until);
+                let _ = tx.send(until);
+                if !state.is_open() {
+                    stats.opened(Local::now(), state.store.len());
+                }
+                run_scheduled(
+                    schedule::Event::Open { until },
+                    state,
+                    locales.for_channel(None),
+                    chat_tx,
+                )
+                .await;
+            }
+
+            CloseAt { time, tx } => {
+                schedule.close_at(time);
+                let _ = tx.send(());
+            }
+
+            CancelSchedule(tx) => {
+                schedule.cancel();
+                let _ = tx.send(());
+            }
+
+            BeginReadyCheck(tx) => {
+                let idle = ready_check.is_none();
+                if idle {
+                    *ready_check = Some(ReadyCheck::default());
+                }
+                let _ = tx.send(idle);
+            }
+
+            StartReadyCheck {
+                count,
+                all_here,
+                tx,
+            } => {
+                let pinged = ready_check
+                    .get_or_insert_with(ReadyCheck::default)
+                    .start_round(state.store.entries(), count as usize, all_here);
+                let _ = tx.send(pinged);
+            }
+
+            MarkHere { user, tx } => {
+                let confirmed = ready_check
+                    .as_mut()
+                    .is_some_and(|check| check.confirm(&user));
+                let _ = tx.send(confirmed);
+            }
+
+            FinishReadyRound(tx) => {
+                let missing = ready_check.as_mut().map_or_else(Vec::new, |check| {
+                    check.finish_round(&mut *state.store, settings.ready_check.on_timeout)
+                });
+                if settings.ready_check.on_timeout == OnTimeout::Remove {
+                    stats.left(missing.len());
+                }
+                let _ = tx.send(missing);
+
+                if state.reopen_if_drained() {
+                    announce(chat_tx, &locales.for_channel(None).queue_reopened).await;
+                }
+            }
+
+            UsersJoined(users) => {
+                for user in &users {
+                    presence::joined(user, &mut *state.store);
+                }
+            }
+
+            UserParted(user) => {
+                presence::parted(&user, &mut *state.store, Local::now());
+            }
+
+            ChatLine => {
+                announcements.chat_line();
+                for message in announcements.poll(Local::now(), state.is_open()) {
+                    announce(chat_tx, &message).await;
+                }
+            }
+
+            GetAnnouncements(tx) => {
+                let _ = tx.send(announcements.list());
+            }
+
+            SetAnnouncement {
+                id,
+                announcement,
+                tx,
+            } => {
+                let id = match id {
+                    Some(id) => announcements.replace(id, announcement).then_some(id),
+                    None => Some(announcements.add(announcement)),
+                };
+                if id.is_some() {
+                    announcements.save(&mut *state.store);
+                }
+                let _ = tx.send(id);
+            }
+
+            DeleteAnnouncement { id, tx } => {
+                let removed = announcements.remove(id);
+                if removed {
+                    announcements.save(&mut *state.store);
+                }
+                let _ = tx.send(removed.then_some(()));
+            }
+
+            SetUserInfo { user, info, tx } => {
+                let updated = state.store.find(&user).map(|index| {
+                    state.store.update(index, &mut |entry| {
+                        if info.ign.is_some() {
+                            entry.ign = info.ign.clone();
+                        }
+                        if info.note.is_some() {
+                            entry.note = info.note.clone();
+                        }
+                    });
+                });
+                let _ = tx.send(updated);
+            }
+
+            GetStats(tx) => {
+                let _ = tx.send(stats.report(Local::now()));
+            }
+
+            GetBans(tx) => {
+                let _ = tx.send(state.store.bans());
+            }
+
+            BanUser { user, tx } => {
+                let _ = tx.send(state.store.ban(&user));
+                // Bans are kept lowercase, so match the queue the same way
+                let banned = user.to_lowercase();
+                // Not counted as leaving, they didn't choose to
+                state
+                    .store
+                    .remove_where(&mut |entry| entry.nickname.to_lowercase() == banned);
+
+                if state.reopen_if_drained() {
+                    announce(chat_tx, &locales.for_channel(None).queue_reopened).await;
+                }
+            }
+
+            UnbanUser { user, tx } => {
+                let _ = tx.send(state.store.unban(&user));
+            }
+
+            GetTemplates { channel, tx } => {
+                let _ = tx.send(locales.for_channel(channel.as_deref()).clone());
+            }
+
+            GetLocale { locale, tx } => {
+                let locale = locale.unwrap_or_else(|| locales.default_locale().to_owned());
+                let _ = tx.send(locales.get(&locale).cloned());
+            }
+
+            SetTemplates {
+                locale,
+                overrides,
+                tx,
+            } => {
+                let locale = locale.unwrap_or_else(|| locales.default_locale().to_owned());
+                let _ = tx.send(locales.update(&locale, &overrides));
+            }
+
+            GetCustomCommands(tx) => {
+                let _ = tx.send(custom_commands.all().clone());
+            }
+
+            SetCustomCommand {
+                name,
+                response,
+                mode,
+                tx,
+            } => {
+                let result = custom_commands.set(&name, response, mode);
+                let ok = result.is_ok();
+                if ok {
+                    custom_commands.save(&mut *state.store);
                 }
+                let _ = tx.send(result);
 
-                GetQueueStatus(tx) => {
-                    tx.send(state.is_open).unwrap();
+                if ok {
+                    let name = name.to_lowercase();
+                    let response = custom_commands.all().get(&name).cloned();
+                    update_custom_command(chat_tx, name, response).await;
                 }
+            }
+
+            DeleteCustomCommand { name, tx } => {
+                let result = custom_commands.remove(&name);
+                let ok = result.is_ok();
+                if ok {
+                    custom_commands.save(&mut *state.store);
+                }
+                let _ = tx.send(result);
 
-                FindUser { name, tx } => {
-                    tx.send(find(&name, &state.queue)).unwrap();
+                if ok {
+                    update_custom_command(chat_tx, name.to_lowercase(), None).await;
                 }
+            }
 
-                PeekQueue { count, tx } => {
-                    let first_n: Vec<_> =
-                        state.queue.iter().take(count as usize).cloned().collect();
-                    tx.send(first_n).unwrap();
+            PopReady { count, tx } => {
+                let popped = ready_check
+                    .take()
+                    .map(|check| check.pop(&mut *state.store, count as usize))
+                    .filter(|popped| !popped.is_empty());
+                if let Some(popped) = &popped {
+                    stats.popped(popped, Local::now());
                 }
+                let _ = tx.send(popped);
 
-                PopQueue { count, tx } => {
-                    let popped_users = pop(count, &mut state.queue);
-                    tx.send(popped_users).unwrap();
+                if state.reopen_if_drained() {
+                    announce(chat_tx, &locales.for_channel(None).queue_reopened).await;
                 }
+            }
+        }
+    }
+}
+
+pub async fn init_state(
+    mut state_rx: tokio::sync::mpsc::Receiver<StateMessage>,
+    chat_tx: chatbot::Tx,
+    settings: Settings,
+) -> tokio::task::JoinHandle<Result<(), anyhow::Error>> {
+    // Opened before the task starts, so a database that can't be opened stops the bot starting
+    let store = store::open(&settings.storage).unwrap_or_else(|e| {
+        panic!(
+            "Could not open {} in [storage]: {}",
+            settings.storage.path.display(),
+            e
+        )
+    });
+    tokio::spawn(async move {
+        use crate::StateCommand::*;
+        let state = Queue::new(&settings.queue, store);
+        let locales = Locales::new(&settings).expect("Templates are checked on load");
+        let mut custom_commands = CustomCommands::load(&*state.store);
+        custom_commands.reserve(chatbot::reserved_names(&settings.commands));
+        custom_commands.render_in(locales.spoken().into_iter().map(str::to_owned));
+        let mut task = StateTask {
+            schedule: Schedule::new(&settings.schedule),
+            ready_check: None,
+            custom_commands,
+            announcements: Announcements::load(&settings.announcements, &*state.store),
+            stats: stats::Stats::new(Local::now(), state.store.len()),
+            state,
+            locales,
+            chat_tx,
+            settings,
+        };
 
-                RemoveUser { user, tx } => {
-                    tx.send(remove(&user, &mut state.queue)).unwrap();
+        loop {
+            // Anything handled last time round may have changed the queue
+            metrics::set_queue(task.state.store.len(), task.state.is_open());
+            let next_scheduled = task.schedule.next_deadline();
+            let next_absent =
+                presence::next_deadline(task.state.store.entries(), &task.settings.presence);
+            let next_announcement = task.announcements.next_deadline();
+
+            let StateMessage { command, span } = tokio::select! {
+                message = state_rx.recv() => match message {
+                    Some(message) => message,
+                    None => break,
+                },
+                _ = schedule::sleep_until(next_scheduled) => {
+                    for event in task.schedule.poll(Local::now()) {
+                        let was_open = task.state.is_open();
+                        let templates = task.locales.for_channel(None);
+                        run_scheduled(event, &mut task.state, templates, &task.chat_tx).await;
+                        if !was_open && task.state.is_open() {
+                            task.stats.opened(Local::now(), task.state.store.len());
+                        }
+                    }
+                    continue;
+                }
+                _ = schedule::sleep_until(next_absent) => {
+                    let now = Local::now();
+                    let removed =
+                        presence::sweep(&mut *task.state.store, &task.settings.presence, now);
+                    for entry in &removed {
+                        tracing::info!("Removed {} after they left the channel", entry.nickname);
+                    }
+                    task.stats.left(removed.len());
+                    if task.state.reopen_if_drained() {
+                        announce(&task.chat_tx, &task.locales.for_channel(None).queue_reopened)
+                            .await;
+                    }
+                    continue;
+                }
+                _ = schedule::sleep_until(next_announcement) => {
+                    for message in task.announcements.poll(Local::now(), task.state.is_open()) {
+                        announce(&task.chat_tx, &message).await;
+                    }
+                    continue;
                 }
+            };
 
-                ToggleQueue(tx) => {
-                    state.is_open = !state.is_open;
-                    tx.send(state.is_open).unwrap();
+            match command {
+                // Chat traffic comes too often to be worth a span each
+                ChatLine | UsersJoined(_) | UserParted(_) => task.handle_command(command).await,
+                _ => {
+                    let span =
+                        tracing::info_span!(parent: &span, "state", command = command.name());
+                    task.handle_command(command).instrument(span).await;
                 }
             }
         }
         Ok(()) as anyhow::Result<()>
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn add_user(tx: &StateTx, user: &str) -> Result<usize, JoinError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(
+            StateCommand::AddUser {
+                user: user.to_owned(),
+                display_name: None,
+                ign: None,
+                tx: resp_tx,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn queue_closes_when_full_and_reopens_when_drained() {
+        let (state_tx, state_rx) = tokio::sync::mpsc::channel(1);
+        let (chat_tx, mut chat_rx) = tokio::sync::mpsc::channel(4);
+        let _state = init_state(
+            state_rx,
+            chat_tx,
+            Settings {
+                queue: QueueSettings {
+                    capacity: Some(2),
+                    reopen_below: Some(2),
+                },
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        state_tx
+            .send(StateCommand::ToggleQueue(resp_tx).into())
+            .await
+            .unwrap();
+        assert!(resp_rx.await.unwrap());
+
+        assert_eq!(add_user(&state_tx, "a").await, Ok(0));
+        assert_eq!(add_user(&state_tx, "b").await, Ok(1));
+        assert!(matches!(
+            chat_rx.recv().await,
+            Some(chatbot::Commands::SendMessage(_))
+        ));
+        assert_eq!(add_user(&state_tx, "c").await, Err(JoinError::Full));
+        assert_eq!(add_user(&state_tx, "b").await, Ok(1));
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        state_tx
+            .send(
+                StateCommand::RemoveUser {
+                    user: "a".to_owned(),
+                    tx: resp_tx,
+                }
+                .into(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp_rx.await.unwrap().unwrap().nickname, "a");
+        assert!(matches!(
+            chat_rx.recv().await,
+            Some(chatbot::Commands::SendMessage(_))
+        ));
+        assert_eq!(add_user(&state_tx, "c").await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn scheduled_close_is_only_announced_if_open() {
+        let (chat_tx, mut chat_rx) = tokio::sync::mpsc::channel(4);
+        let templates = Templates::default();
+        let mut queue = Queue::new(
+            &QueueSettings::default(),
+            Box::new(store::MemoryStore::default()),
+        );
+
+        run_scheduled(schedule::Event::Close, &mut queue, &templates, &chat_tx).await;
+        assert!(chat_rx.try_recv().is_err());
+
+        queue.set_open(true);
+        run_scheduled(schedule::Event::Close, &mut queue, &templates, &chat_tx).await;
+        assert!(!queue.is_open());
+        assert!(chat_rx.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn user_info_only_changes_what_is_given() {
+        let (state_tx, state_rx) = tokio::sync::mpsc::channel(1);
+        let (chat_tx, _chat_rx) = tokio::sync::mpsc::channel(4);
+        let _state = init_state(state_rx, chat_tx, Settings::default()).await;
+        let client = client::QueueClient::new(state_tx);
+        assert!(client.toggle().await.unwrap());
+        let info = |ign: Option<&str>, note: Option<&str>| UserInfo {
+            ign: ign.map(str::to_owned),
+            note: note.map(str::to_owned),
+        };
+
+        assert!(!client
+            .set_user_info("alice", info(Some("Alice"), None))
+            .await
+            .unwrap());
+        client
+            .add_user("alice", None, Some("Al".to_owned()))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(client
+            .set_user_info("alice", info(None, Some("brb")))
+            .await
+            .unwrap());
+        assert!(client
+            .set_user_info("alice", info(Some("Alice"), None))
+            .await
+            .unwrap());
+
+        let entry = &client.peek(1).await.unwrap()[0];
+        assert_eq!(entry.ign.as_deref(), Some("Alice"));
+        assert_eq!(entry.note.as_deref(), Some("brb"));
+    }
+
+    #[tokio::test]
+    async fn banned_users_are_removed_and_cannot_join() {
+        let (state_tx, state_rx) = tokio::sync::mpsc::channel(1);
+        let (chat_tx, _chat_rx) = tokio::sync::mpsc::channel(4);
+        let _state = init_state(state_rx, chat_tx, Settings::default()).await;
+        let client = client::QueueClient::new(state_tx);
+        assert!(client.toggle().await.unwrap());
+
+        assert_eq!(client.add_user("mallory", None, None).await.unwrap(), Ok(0));
+        assert!(client.ban("Mallory").await.unwrap());
+        assert_eq!(client.queue_length().await.unwrap(), 0);
+        assert_eq!(
+            client.add_user("mallory", None, None).await.unwrap(),
+            Err(JoinError::Banned)
+        );
+
+        assert_eq!(client.bans().await.unwrap(), ["mallory"]);
+        assert!(client.unban("mallory").await.unwrap());
+        assert_eq!(client.add_user("mallory", None, None).await.unwrap(), Ok(0));
+
+        assert_eq!(client.add_user("émile", None, None).await.unwrap(), Ok(1));
+        assert!(client.ban("ÉMILE").await.unwrap());
+        assert_eq!(client.queue_length().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn stats_start_over_when_the_queue_opens() {
+        let (state_tx, state_rx) = tokio::sync::mpsc::channel(1);
+        let (chat_tx, _chat_rx) = tokio::sync::mpsc::channel(4);
+        let _state = init_state(state_rx, chat_tx, Settings::default()).await;
+        let client = client::QueueClient::new(state_tx);
+        assert!(client.toggle().await.unwrap());
+
+        assert_eq!(client.add_user("alice", None, None).await.unwrap(), Ok(0));
+        assert_eq!(client.add_user("bob", None, None).await.unwrap(), Ok(1));
+        assert!(client.ban("bob").await.unwrap());
+        let stats = client.stats().await.unwrap();
+        assert_eq!(stats.total_joins, 2);
+        assert_eq!(stats.abandoned, 0);
+
+        assert!(!client.toggle().await.unwrap());
+        assert!(client.toggle().await.unwrap());
+        let stats = client.stats().await.unwrap();
+        assert_eq!(stats.total_joins, 0);
+        assert_eq!(stats.peak_queue_length, 1);
+    }
+}
@@ -0,0 +1,354 @@
+use crate::{
+    settings::Settings,
+    templates::{TemplateError, Templates},
+};
+use std::collections::HashMap;
+use std::fmt;
+
+// The language part of a locale, e.g. `pt` for `pt-BR`
+fn language(locale: &str) -> &str {
+    locale.split(['-', '_']).next().unwrap_or(locale)
+}
+
+/// How many plural forms templates in `locale` give, following the CLDR cardinal categories.
+/// Forms are ordered `one|other`, or `one|few|many` for Slavic languages.
+pub fn plural_forms(locale: &str) -> usize {
+    match language(locale) {
+        "ru" | "uk" | "pl" | "cs" | "sk" => 3,
+        _ => 2,
+    }
+}
+
+/// Which of the plural forms to use for `n` in `locale`.
+pub fn plural_index(locale: &str, n: u64) -> usize {
+    match language(locale) {
+        "fr" | "pt" => (n > 1) as usize,
+        "ru" | "uk" => match (n % 10, n % 100) {
+            (1, rem) if rem != 11 => 0,
+            (2..=4, rem) if !(12..=14).contains(&rem) => 1,
+            _ => 2,
+        },
+        "pl" => match (n % 10, n % 100) {
+            _ if n == 1 => 0,
+            (2..=4, rem) if !(12..=14).contains(&rem) => 1,
+            _ => 2,
+        },
+        "cs" | "sk" => match n {
+            1 => 0,
+            2..=4 => 1,
+            _ => 2,
+        },
+        _ => (n != 1) as usize,
+    }
+}
+
+fn builtin(locale: &str) -> Option<Templates> {
+    match locale {
+        "en" => Some(Templates::default()),
+        "es" => Some(Templates {
+            locale: "es".to_owned(),
+            place: "{user} es el #{position}. {ahead}, el tiempo de espera es de aproximadamente {wait}-{wait_max} minutos".to_owned(),
+            on_deck: "Eres el siguiente".to_owned(),
+            groups_ahead: "{groups:Hay # grupo|Hay # grupos} delante de ti".to_owned(),
+            not_in_queue: "{user} no está en la cola".to_owned(),
+            queue_full: "Lo siento {user}, la cola está llena.".to_owned(),
+            leave: "{user} ha salido de la cola.".to_owned(),
+            peek: "{users}".to_owned(),
+            peek_empty: "La cola está vacía".to_owned(),
+            info_updated: "{user}, tu {field} se ha actualizado.".to_owned(),
+            info_too_long: "{user}, tu {field} puede tener como máximo {max} caracteres.".to_owned(),
+            field_ign: "nombre en el juego".to_owned(),
+            field_note: "nota".to_owned(),
+            queue_opened: "La cola está abierta.".to_owned(),
+            queue_closed: "La cola está cerrada.".to_owned(),
+            queue_filled: "La cola está llena y se ha cerrado.".to_owned(),
+            queue_reopened: "Vuelve a haber sitio y la cola está abierta.".to_owned(),
+            scheduled_open: "La cola está abierta hasta las {time}. ¡Escribe !join para unirte!".to_owned(),
+            closing_warning: "La cola se cierra en {minutes:# minuto|# minutos}.".to_owned(),
+            pop: "Siguientes: {group}. Podéis encontrar a BK en el juego con el siguiente mensaje: @brittleknee Hi.".to_owned(),
+            pop_member: "@{user}".to_owned(),
+            pop_member_ign: "@{user} (IGN: {ign})".to_owned(),
+            ready_check: "{users} ¡os toca! Escribid !here en {timeout} segundos para mantener vuestro sitio.".to_owned(),
+            ready_check_moved: "Enviados al final de la cola por no responder a tiempo: {users}".to_owned(),
+            ready_check_removed: "Eliminados de la cola por no responder a tiempo: {users}".to_owned(),
+            help: "Comandos: {commands}".to_owned(),
+            help_command: "{usage} - {description}".to_owned(),
+            help_unknown: "No existe el comando {command}.".to_owned(),
+            command_added: "Se ha añadido {command}.".to_owned(),
+            command_updated: "Se ha actualizado {command}.".to_owned(),
+            command_deleted: "Se ha eliminado {command}.".to_owned(),
+            command_exists: "Ya existe el comando {command}.".to_owned(),
+            command_invalid: "No se puede guardar {command}: {error}".to_owned(),
+            stats: "{joins:# entrada|# entradas} de {users:# usuario|# usuarios} ({rate} por minuto). Espera: {average} min de media, {median} min de mediana, {max} min como máximo. El {abandoned}% se fue antes de su turno. Cola más larga: {peak}.".to_owned(),
+            wrong_channel: "Los comandos solo funcionan en {channel}.".to_owned(),
+            about_join: "Únete a la cola".to_owned(),
+            about_next: "Mira quién va después".to_owned(),
+            about_place: "Mira tu puesto en la cola".to_owned(),
+            about_leave: "Sal de la cola".to_owned(),
+            about_setign: "Indica tu nombre en el juego".to_owned(),
+            about_note: "Añade una nota a tu puesto en la cola".to_owned(),
+            about_here: "Confirma que sigues aquí cuando te llame una comprobación".to_owned(),
+            about_help: "Muestra los comandos, o explica uno".to_owned(),
+            about_addcmd: "Añade un comando que responde con el texto dado".to_owned(),
+            about_editcmd: "Cambia la respuesta de un comando añadido con addcmd".to_owned(),
+            about_delcmd: "Elimina un comando añadido con addcmd".to_owned(),
+            about_qstats: "Muestra cómo se ha usado la cola en este directo".to_owned(),
+        }),
+        _ => None,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LocaleError {
+    UnknownTemplate {
+        locale: String,
+        template: String,
+    },
+    Template {
+        locale: String,
+        error: TemplateError,
+    },
+}
+
+impl fmt::Display for LocaleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocaleError::UnknownTemplate { locale, template } => {
+                write!(f, "`{}` is not a template (locale {})", template, locale)
+            }
+            LocaleError::Template { locale, error } => write!(f, "{} (locale {})", error, locale),
+        }
+    }
+}
+
+impl std::error::Error for LocaleError {}
+
+/// Template bundles for every configured language, and which one each channel speaks.
+#[derive(Clone, Debug)]
+pub struct Locales {
+    bundles: HashMap<String, Templates>,
+    default_locale: String,
+    /// Channel name, without the leading `#`, to locale.
+    channels: HashMap<String, String>,
+}
+
+impl Locales {
+    /// Builds the bundles from the built-in languages, with any templates from the settings layered
+    /// on top. A locale that isn't built in starts from the English templates.
+    pub fn new(settings: &Settings) -> Result<Locales, LocaleError> {
+        let mut locales = Locales {
+            bundles: HashMap::new(),
+            default_locale: settings.locale.clone(),
+            channels: settings
+                .channel_locales
+                .iter()
+                .map(|(channel, locale)| {
+                    (channel.trim_start_matches('#').to_owned(), locale.clone())
+                })
+                .collect(),
+        };
+        for (locale, overrides) in &settings.locales {
+            locales.update(locale, overrides)?;
+        }
+        let referenced: Vec<String> = std::iter::once(&locales.default_locale)
+            .chain(locales.channels.values())
+            .cloned()
+            .collect();
+        for locale in referenced {
+            if !locales.bundles.contains_key(&locale) {
+                locales.update(&locale, &HashMap::new())?;
+            }
+        }
+        Ok(locales)
+    }
+
+    /// The templates `channel` should be answered with, or the default locale's.
+    pub fn for_channel(&self, channel: Option<&str>) -> &Templates {
+        channel
+            .and_then(|channel| self.channels.get(channel.trim_start_matches('#')))
+            .and_then(|locale| self.bundles.get(locale))
+            .unwrap_or_else(|| &self.bundles[&self.default_locale])
+    }
+
+    pub fn get(&self, locale: &str) -> Option<&Templates> {
+        self.bundles.get(locale)
+    }
+
+    pub fn default_locale(&self) -> &str {
+        &self.default_locale
+    }
+
+    /// Every locale a channel is answered in, the default first.
+    pub fn spoken(&self) -> Vec<&str> {
+        let mut spoken = vec![self.default_locale.as_str()];
+        for locale in self.channels.values() {
+            if !spoken.contains(&locale.as_str()) {
+                spoken.push(locale);
+            }
+        }
+        spoken
+    }
+
+    /// Replaces templates in `locale`, creating it if needed. Nothing changes if any of the
+    /// resulting templates are invalid.
+    pub fn update(
+        &mut self,
+        locale: &str,
+        overrides: &HashMap<String, String>,
+    ) -> Result<(), LocaleError> {
+        let base = self
+            .bundles
+            .get(locale)
+            .cloned()
+            .or_else(|| builtin(locale))
+            .unwrap_or_default();
+
+        let mut fields = match serde_json::to_value(base).unwrap() {
+            serde_json::Value::Object(fields) => fields,
+            _ => unreachable!("Templates serialize to an object"),
+        };
+        for (template, text) in overrides {
+            if template == "locale" || !fields.contains_key(template) {
+                return Err(LocaleError::UnknownTemplate {
+                    locale: locale.to_owned(),
+                    template: template.clone(),
+                });
+            }
+            fields.insert(template.clone(), serde_json::Value::String(text.clone()));
+        }
+        fields.insert(
+            "locale".to_owned(),
+            serde_json::Value::String(locale.to_owned()),
+        );
+
+        let templates: Templates =
+            serde_json::from_value(serde_json::Value::Object(fields)).unwrap();
+        templates
+            .validate()
+            .map_err(|error| LocaleError::Template {
+                locale: locale.to_owned(),
+                error,
+            })?;
+        self.bundles.insert(locale.to_owned(), templates);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings {
+            channel: Some("#english".to_owned()),
+            locale: "es".to_owned(),
+            channel_locales: vec![("english".to_owned(), "en".to_owned())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn builtin_locales_are_valid() {
+        for locale in &["en", "es"] {
+            assert_eq!(builtin(locale).unwrap().validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn channels_use_their_locale() {
+        let locales = Locales::new(&settings()).unwrap();
+
+        assert_eq!(locales.for_channel(Some("#english")).locale, "en");
+        assert_eq!(locales.for_channel(Some("#other")).locale, "es");
+        assert_eq!(locales.for_channel(None).locale, "es");
+    }
+
+    #[test]
+    fn overrides_layer_on_builtin_locale() {
+        let mut locales = Locales::new(&settings()).unwrap();
+        let overrides = vec![("leave".to_owned(), "Adiós {user}".to_owned())]
+            .into_iter()
+            .collect();
+
+        locales.update("es", &overrides).unwrap();
+
+        let es = locales.get("es").unwrap();
+        assert_eq!(es.leave, "Adiós {user}");
+        assert_eq!(es.peek_empty, "La cola está vacía");
+    }
+
+    #[test]
+    fn invalid_overrides_are_rejected() {
+        let mut locales = Locales::new(&settings()).unwrap();
+        let overrides = vec![("nope".to_owned(), "".to_owned())]
+            .into_iter()
+            .collect();
+
+        assert!(matches!(
+            locales.update("es", &overrides),
+            Err(LocaleError::UnknownTemplate { .. })
+        ));
+    }
+
+    #[test]
+    fn locales_without_a_bundle_keep_english_plurals() {
+        let mut locales = Locales::new(&Settings {
+            locale: "ru".to_owned(),
+            ..Default::default()
+        })
+        .unwrap();
+        let ru = locales.get("ru").unwrap();
+        assert_eq!(
+            ru.render(&ru.groups_ahead, &[("groups", &5)]),
+            "There are 5 groups ahead of you"
+        );
+
+        let overrides = vec![(
+            "groups_ahead".to_owned(),
+            "{groups:# группа|# группы|# групп} впереди".to_owned(),
+        )]
+        .into_iter()
+        .collect();
+        locales.update("ru", &overrides).unwrap();
+        let ru = locales.get("ru").unwrap();
+        assert_eq!(
+            ru.render(&ru.groups_ahead, &[("groups", &5)]),
+            "5 групп впереди"
+        );
+
+        let overrides = vec![(
+            "groups_ahead".to_owned(),
+            "{groups:# группа|# групп} впереди".to_owned(),
+        )]
+        .into_iter()
+        .collect();
+        assert!(locales.update("ru", &overrides).is_err());
+    }
+
+    #[test]
+    fn english_fallbacks_count_like_english() {
+        let locales = Locales::new(&Settings {
+            locale: "fr".to_owned(),
+            ..Default::default()
+        })
+        .unwrap();
+        let fr = locales.get("fr").unwrap();
+        assert_eq!(
+            fr.render(&fr.groups_ahead, &[("groups", &0)]),
+            "There are 0 groups ahead of you"
+        );
+    }
+
+    #[test]
+    fn plural_rules() {
+        assert_eq!(plural_index("en", 1), 0);
+        assert_eq!(plural_index("en", 0), 1);
+        assert_eq!(plural_index("fr", 0), 0);
+        assert_eq!(plural_index("ru", 21), 0);
+        assert_eq!(plural_index("ru", 22), 1);
+        assert_eq!(plural_index("ru", 12), 2);
+        assert_eq!(plural_index("pl", 21), 2);
+    }
+}
@@ -1,4 +1,6 @@
-use brittlq::{chatbot, get_user_config, register_subscriber, server::endpoints, subscriber_init};
+use brittlq::{
+    chatbot, get_user_config, register_subscriber, server::endpoints, settings, subscriber_init,
+};
 use std::process::Command;
 
 /* THE BIG TODO
@@ -41,46 +43,80 @@ use std::process::Command;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let settings = settings::load();
+
     // Set up tracing system
-    let subscriber = subscriber_init();
+    let subscriber = subscriber_init(&settings.telemetry);
     register_subscriber(subscriber);
 
-    let (state_tx, state_rx) = tokio::sync::mpsc::channel(32);
-    let (chat_tx, mut chat_rx) = tokio::sync::mpsc::channel(4);
-    let bot_state_tx = state_tx.clone();
+    let (state_tx, state_rx) = tokio::sync::mpsc::channel(brittlq::STATE_CHANNEL_SIZE);
+    let (chat_tx, chat_rx) = tokio::sync::mpsc::channel(4);
+    let client = brittlq::client::QueueClient::new(state_tx.clone());
+
+    let state_task = brittlq::init_state(state_rx, chat_tx.clone(), settings.clone());
+
+    // Discord doesn't wait for the Twitch login, and hears the same announcements
+    let mut chat_rx = match settings.discord.clone() {
+        Some(discord) => {
+            let (twitch_tx, twitch_rx) = tokio::sync::mpsc::channel(4);
+            let (discord_tx, discord_rx) = tokio::sync::mpsc::channel(4);
+            tokio::spawn(chatbot::relay(chat_rx, twitch_tx, vec![discord_tx]));
 
-    let state_task = brittlq::init_state(state_rx);
+            let transport = chatbot::discord::DiscordTransport::new(discord);
+            let mut bot = chatbot::Bot::new(transport, discord_rx);
+            chatbot::build_bot(&mut bot, &settings.commands);
+            let discord_client = client.clone();
+            tokio::spawn(async move {
+                if let Err(e) = bot.run(discord_client).await {
+                    tracing::error!("Discord bot stopped: {}", e);
+                }
+            });
+            twitch_rx
+        }
+        None => chat_rx,
+    };
 
+    let server_settings = settings.clone();
     let server_task = tokio::spawn(async move {
-        let server = warp::serve(endpoints::queue(state_tx, chat_tx));
+        let server = warp::serve(endpoints::queue(state_tx, chat_tx, server_settings));
         server.run(([127, 0, 0, 1], 8080)).await;
         Ok(()) as anyhow::Result<()>
     });
 
     if cfg!(target_os = "windows") {
         let output = Command::new("cmd")
-            .args(&["/C", "start http://localhost:8080"])
+            .args(["/C", "start http://localhost:8080"])
             .output();
         if output.is_err() {
             tracing::error!("Could not launch browser");
         }
     }
 
-    let mut auth = String::new();
-    if let Some(chatbot::Commands::Token(token)) = chat_rx.recv().await {
-        auth = format!("oauth:{}", token.access_token);
+    // Nothing else can be done for the bot until it has a token to log in with
+    let mut access_token = String::new();
+    while let Some(command) = chat_rx.recv().await {
+        match command {
+            chatbot::Commands::Token(token) => {
+                access_token = token.access_token;
+                break;
+            }
+            command => tracing::debug!("Bot isn't running yet, dropping {:?}", command),
+        }
     }
+    let auth = format!("oauth:{}", access_token);
 
-    let mut bot = chatbot::Bot::new(get_user_config(&auth), chat_rx)
+    let mut transport = chatbot::twitch::TwitchTransport::new(get_user_config(&auth, &settings))
         .await
         .unwrap();
+    transport.with_whispers(access_token);
+    let mut bot = chatbot::Bot::new(transport, chat_rx);
 
     let bot_task = tokio::spawn(async move {
-        chatbot::build_bot(&mut bot);
-        bot.run(bot_state_tx).await
+        chatbot::build_bot(&mut bot, &settings.commands);
+        bot.run(client).await
     });
 
-    tokio::select! {
+    let result = tokio::select! {
         _ = bot_task => {
             tracing::debug!("Bot task exited.");
             Ok(()) as anyhow::Result<()>
@@ -93,5 +129,7 @@ async fn main() -> anyhow::Result<()> {
             tracing::debug!("State task exited.");
             Ok(()) as anyhow::Result<()>
         }
-    }
+    };
+    brittlq::subscriber_shutdown();
+    result
 }
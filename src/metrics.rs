@@ -0,0 +1,125 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+/// Everything `GET /metrics` reports. It's global so the bot, the transports and the server can
+/// count things without being handed anything.
+struct Metrics {
+    registry: Registry,
+    queue_length: IntGauge,
+    queue_open: IntGauge,
+    state_backlog: IntGauge,
+    commands: IntCounterVec,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    chat_connected: IntGaugeVec,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let metrics = Metrics {
+        registry: Registry::new(),
+        queue_length: IntGauge::new("brittlq_queue_length", "Users in the queue").unwrap(),
+        queue_open: IntGauge::new("brittlq_queue_open", "1 while the queue is open").unwrap(),
+        state_backlog: IntGauge::new(
+            "brittlq_state_backlog",
+            "Requests waiting for the state task",
+        )
+        .unwrap(),
+        commands: IntCounterVec::new(
+            Opts::new("brittlq_commands_total", "Chat commands run, by command"),
+            &["command"],
+        )
+        .unwrap(),
+        http_requests: IntCounterVec::new(
+            Opts::new("brittlq_http_requests_total", "API requests answered"),
+            &["method", "route", "status"],
+        )
+        .unwrap(),
+        http_duration: HistogramVec::new(
+            HistogramOpts::new(
+                "brittlq_http_request_duration_seconds",
+                "How long API requests took to answer",
+            ),
+            &["method", "route"],
+        )
+        .unwrap(),
+        chat_connected: IntGaugeVec::new(
+            Opts::new(
+                "brittlq_chat_connected",
+                "1 while logged in to chat, by transport",
+            ),
+            &["transport"],
+        )
+        .unwrap(),
+    };
+    let registry = &metrics.registry;
+    registry
+        .register(Box::new(metrics.queue_length.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(metrics.queue_open.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(metrics.state_backlog.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(metrics.commands.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(metrics.http_requests.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(metrics.http_duration.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(metrics.chat_connected.clone()))
+        .unwrap();
+    metrics
+});
+
+/// Counts a run of the chat command `name`.
+pub fn command_run(name: &str) {
+    METRICS.commands.with_label_values(&[name]).inc();
+}
+
+/// Records an answered API request. `route` should be one of a few fixed names, not the path,
+/// so there's a bounded number of series.
+pub fn http_request(method: &str, route: &str, status: u16, elapsed: Duration) {
+    METRICS
+        .http_requests
+        .with_label_values(&[method, route, &status.to_string()])
+        .inc();
+    METRICS
+        .http_duration
+        .with_label_values(&[method, route])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Records whether the `transport` bot, like `twitch`, is logged in to its chat.
+pub fn set_connected(transport: &str, connected: bool) {
+    METRICS
+        .chat_connected
+        .with_label_values(&[transport])
+        .set(connected as i64);
+}
+
+pub fn set_queue(length: usize, is_open: bool) {
+    METRICS.queue_length.set(length as i64);
+    METRICS.queue_open.set(is_open as i64);
+}
+
+pub fn set_state_backlog(backlog: usize) {
+    METRICS.state_backlog.set(backlog as i64);
+}
+
+/// Every metric in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&METRICS.registry.gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
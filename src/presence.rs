@@ -0,0 +1,134 @@
+use crate::{
+    settings::{AbsentAction, PresenceSettings},
+    store::QueueStore,
+    UserEntry,
+};
+use chrono::{DateTime, Duration, Local};
+use std::collections::VecDeque;
+
+/// Records that `user` is in the channel again.
+pub fn joined(user: &str, queue: &mut dyn QueueStore) {
+    if let Some(index) = queue.find(user) {
+        queue.update(index, &mut |entry| {
+            entry.present = true;
+            entry.parted_at = None;
+        });
+    }
+}
+
+/// Records that `user` left the channel. They keep their spot until the grace period runs out.
+pub fn parted(user: &str, queue: &mut dyn QueueStore, now: DateTime<Local>) {
+    if let Some(index) = queue.find(user) {
+        if queue.entries()[index].parted_at.is_none() {
+            queue.update(index, &mut |entry| entry.parted_at = Some(now));
+        }
+    }
+}
+
+/// The next time a queued user's grace period runs out.
+pub fn next_deadline(
+    queue: &VecDeque<UserEntry>,
+    settings: &PresenceSettings,
+) -> Option<DateTime<Local>> {
+    queue
+        .iter()
+        .filter(|entry| entry.present)
+        .filter_map(|entry| entry.parted_at)
+        .min()
+        .map(|parted_at| parted_at + Duration::seconds(settings.grace_secs as i64))
+}
+
+/// Marks or removes every user who has been gone for longer than the grace period. Returns the
+/// users that were removed.
+pub fn sweep(
+    queue: &mut dyn QueueStore,
+    settings: &PresenceSettings,
+    now: DateTime<Local>,
+) -> Vec<UserEntry> {
+    let grace = Duration::seconds(settings.grace_secs as i64);
+    let expired = |entry: &UserEntry| matches!(entry.parted_at, Some(t) if t + grace <= now);
+
+    match settings.absent {
+        AbsentAction::Mark => {
+            let absent: Vec<_> = (0..queue.len())
+                .filter(|&index| {
+                    let entry = &queue.entries()[index];
+                    entry.present && expired(entry)
+                })
+                .collect();
+            for index in absent {
+                queue.update(index, &mut |entry| entry.present = false);
+            }
+            Vec::new()
+        }
+        AbsentAction::Remove => queue.remove_where(&mut |entry| expired(entry)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn settings(absent: AbsentAction) -> PresenceSettings {
+        PresenceSettings {
+            grace_secs: 60,
+            absent,
+        }
+    }
+
+    fn queue() -> MemoryStore {
+        let mut queue = MemoryStore::default();
+        queue.push(UserEntry::new("a".to_owned()));
+        queue.push(UserEntry::new("b".to_owned()));
+        queue
+    }
+
+    #[test]
+    fn absent_users_are_marked_after_grace_period() {
+        let settings = settings(AbsentAction::Mark);
+        let mut queue = queue();
+        let now = Local::now();
+
+        parted("a", &mut queue, now);
+        assert_eq!(
+            next_deadline(queue.entries(), &settings),
+            Some(now + Duration::seconds(60))
+        );
+        sweep(&mut queue, &settings, now + Duration::seconds(30));
+        assert!(queue.entries()[0].present);
+        sweep(&mut queue, &settings, now + Duration::seconds(60));
+        assert!(!queue.entries()[0].present);
+        assert_eq!(next_deadline(queue.entries(), &settings), None);
+
+        joined("a", &mut queue);
+        assert!(queue.entries()[0].present);
+    }
+
+    #[test]
+    fn absent_users_can_be_removed() {
+        let settings = settings(AbsentAction::Remove);
+        let mut queue = queue();
+        let now = Local::now();
+
+        parted("b", &mut queue, now);
+        let removed = sweep(&mut queue, &settings, now + Duration::seconds(60));
+
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].nickname, "b");
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn rejoining_keeps_spot() {
+        let settings = settings(AbsentAction::Remove);
+        let mut queue = queue();
+        let now = Local::now();
+
+        parted("a", &mut queue, now);
+        joined("a", &mut queue);
+
+        assert!(sweep(&mut queue, &settings, now + Duration::seconds(60)).is_empty());
+        assert_eq!(queue.len(), 2);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::{
+    chatbot::{self, Commands},
+    client::{ClientError, QueueClient},
+    settings::{OnTimeout, ReadyCheckSettings},
+    store::QueueStore,
+    UserEntry,
+};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use tokio::sync::oneshot;
+
+/// An in-progress check that the users about to be popped are still watching.
+#[derive(Default)]
+pub struct ReadyCheck {
+    /// Users pinged in the current round who have not answered yet.
+    pinged: HashSet<String>,
+    confirmed: HashSet<String>,
+    /// Users who did not answer in time and should not be asked again during this check.
+    skipped: HashSet<String>,
+    all_here: Option<oneshot::Sender<()>>,
+}
+
+impl ReadyCheck {
+    /// Picks the next users to ping so that, together with the users who already confirmed, there
+    /// are `count` users in the group. `all_here` fires once every pinged user has answered.
+    pub fn start_round(
+        &mut self,
+        queue: &VecDeque<UserEntry>,
+        count: usize,
+        all_here: oneshot::Sender<()>,
+    ) -> Vec<UserEntry> {
+        let wanted = count.saturating_sub(self.confirmed.len());
+        let candidates: Vec<_> = queue
+            .iter()
+            .filter(|entry| {
+                !self.confirmed.contains(&entry.nickname) && !self.skipped.contains(&entry.nickname)
+            })
+            .take(wanted)
+            .cloned()
+            .collect();
+
+        self.pinged = candidates.iter().map(|u| u.nickname.clone()).collect();
+        if self.pinged.is_empty() {
+            let _ = all_here.send(());
+        } else {
+            self.all_here = Some(all_here);
+        }
+        candidates
+    }
+
+    /// Records that `user` answered. Returns false if they weren't being asked.
+    pub fn confirm(&mut self, user: &str) -> bool {
+        if !self.pinged.remove(user) {
+            return false;
+        }
+        self.confirmed.insert(user.to_owned());
+        if self.pinged.is_empty() {
+            if let Some(all_here) = self.all_here.take() {
+                let _ = all_here.send(());
+            }
+        }
+        true
+    }
+
+    /// Ends the current round, moving or removing everyone who didn't answer.
+    pub fn finish_round(
+        &mut self,
+        queue: &mut dyn QueueStore,
+        on_timeout: OnTimeout,
+    ) -> Vec<UserEntry> {
+        self.all_here = None;
+        let pinged = std::mem::take(&mut self.pinged);
+        let mut missing = queue.remove_where(&mut |entry| pinged.contains(&entry.nickname));
+        self.skipped.extend(pinged);
+        // Keep the order they were queued in when sending them to the back
+        missing.sort_by_key(|entry| entry.time_joined);
+        if on_timeout == OnTimeout::Back {
+            for entry in &missing {
+                queue.push(entry.clone());
+            }
+        }
+        missing
+    }
+
+    /// Removes up to `count` confirmed users from the queue, in queue order.
+    pub fn pop(self, queue: &mut dyn QueueStore, count: usize) -> Vec<UserEntry> {
+        queue.pop_where(count, &mut |entry| self.confirmed.contains(&entry.nickname))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ReadyCheckError {
+    /// Another pop is waiting on a ready check.
+    Running,
+    Client(ClientError),
+}
+
+impl From<ClientError> for ReadyCheckError {
+    fn from(e: ClientError) -> Self {
+        ReadyCheckError::Client(e)
+    }
+}
+
+impl fmt::Display for ReadyCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadyCheckError::Running => write!(f, "A ready check is already running"),
+            ReadyCheckError::Client(e) => e.fmt(f),
+        }
+    }
+}
+
+/// Starts pinging the next group, returning once the check is under way. Whoever confirms with
+/// `!here` is popped and called up in chat, with gaps left by users who didn't answer filled from
+/// further down the queue. Only one check runs at a time.
+pub async fn start(
+    count: u16,
+    client: &QueueClient,
+    chatbot_tx: &chatbot::Tx,
+    settings: &ReadyCheckSettings,
+) -> Result<(), ReadyCheckError> {
+    if !client.begin_ready_check().await? {
+        return Err(ReadyCheckError::Running);
+    }
+    let (client, chatbot_tx, settings) = (client.clone(), chatbot_tx.clone(), settings.clone());
+    tokio::spawn(async move {
+        let rounds = run_rounds(count, &client, &chatbot_tx, &settings).await;
+        if let Err(e) = &rounds {
+            tracing::warn!("Ready check ended early: {}", e);
+        }
+        // Always finished, even after a failed round, so the next pop isn't refused
+        match client.pop_ready(count).await {
+            Ok(Some(popped)) => {
+                let called = match client.templates(None).await {
+                    Ok(templates) => {
+                        say(&chatbot_tx, crate::pop_message(&templates, &popped)).await
+                    }
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = called {
+                    tracing::warn!("Could not call up the ready group: {}", e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => tracing::error!("Could not finish the ready check: {}", e),
+        }
+    });
+    Ok(())
+}
+
+async fn run_rounds(
+    count: u16,
+    client: &QueueClient,
+    chatbot_tx: &chatbot::Tx,
+    settings: &ReadyCheckSettings,
+) -> Result<(), ClientError> {
+    let timeout = std::time::Duration::from_secs(settings.timeout_secs);
+    let templates = client.templates(None).await?;
+
+    for _ in 0..settings.rounds {
+        let (pinged, all_here) = client.start_ready_check(count).await?;
+        if pinged.is_empty() {
+            break;
+        }
+
+        let names = mentions(&pinged, " ");
+        say(
+            chatbot_tx,
+            templates.render(
+                &templates.ready_check,
+                &[("users", &names), ("timeout", &settings.timeout_secs)],
+            ),
+        )
+        .await?;
+
+        let _ = tokio::time::timeout(timeout, all_here).await;
+
+        let missing = client.finish_ready_round().await?;
+        if missing.is_empty() {
+            break;
+        }
+
+        let template = match settings.on_timeout {
+            OnTimeout::Back => &templates.ready_check_moved,
+            OnTimeout::Remove => &templates.ready_check_removed,
+        };
+        say(
+            chatbot_tx,
+            templates.render(template, &[("users", &mentions(&missing, ", "))]),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+// Without a chat bot to ping anyone the check can't go on
+async fn say(chatbot_tx: &chatbot::Tx, message: String) -> Result<(), ClientError> {
+    chatbot_tx
+        .send(Commands::SendMessage(message))
+        .await
+        .map_err(|_| ClientError::Stopped)
+}
+
+fn mentions(users: &[UserEntry], separator: &str) -> String {
+    users
+        .iter()
+        .map(|u| format!("@{}", u.name()))
+        .collect::<Vec<String>>()
+        .join(separator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn queue(names: &[&str]) -> MemoryStore {
+        let mut queue = MemoryStore::default();
+        for name in names {
+            queue.push(UserEntry::new(name.to_string()));
+        }
+        queue
+    }
+
+    fn nicknames(entries: &[UserEntry]) -> Vec<&str> {
+        entries.iter().map(|u| u.nickname.as_str()).collect()
+    }
+
+    #[test]
+    fn non_responders_are_replaced() {
+        let mut queue = queue(&["a", "b", "c", "d"]);
+        let mut check = ReadyCheck::default();
+
+        let (tx, _rx) = oneshot::channel();
+        assert_eq!(
+            nicknames(&check.start_round(queue.entries(), 2, tx)),
+            ["a", "b"]
+        );
+        assert!(check.confirm("a"));
+        assert!(!check.confirm("c"));
+        let missing = check.finish_round(&mut queue, OnTimeout::Back);
+        assert_eq!(nicknames(&missing), ["b"]);
+
+        let (tx, _rx) = oneshot::channel();
+        assert_eq!(nicknames(&check.start_round(queue.entries(), 2, tx)), ["c"]);
+        assert!(check.confirm("c"));
+        assert!(check.finish_round(&mut queue, OnTimeout::Back).is_empty());
+
+        assert_eq!(nicknames(&check.pop(&mut queue, 2)), ["a", "c"]);
+        assert_eq!(
+            queue
+                .entries()
+                .iter()
+                .map(|u| u.nickname.as_str())
+                .collect::<Vec<_>>(),
+            ["d", "b"]
+        );
+    }
+
+    #[test]
+    fn non_responders_can_be_removed() {
+        let mut queue = queue(&["a", "b"]);
+        let mut check = ReadyCheck::default();
+
+        let (tx, _rx) = oneshot::channel();
+        check.start_round(queue.entries(), 2, tx);
+        check.finish_round(&mut queue, OnTimeout::Remove);
+
+        assert!(queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn all_here_fires_once_everyone_answers() {
+        let queue = queue(&["a", "b"]);
+        let mut check = ReadyCheck::default();
+
+        let (tx, mut rx) = oneshot::channel();
+        check.start_round(queue.entries(), 2, tx);
+        check.confirm("a");
+        assert!(rx.try_recv().is_err());
+        check.confirm("b");
+        assert!(rx.await.is_ok());
+    }
+}
@@ -0,0 +1,234 @@
+use crate::settings::{ScheduleSettings, WindowSettings};
+use chrono::{DateTime, Duration, Local};
+use std::str::FromStr;
+
+/// Something the state task should act on because its time has come.
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// A scheduled window started. The queue should open until the given time.
+    Open {
+        until: DateTime<Local>,
+    },
+    /// The queue closes in this many minutes.
+    Warn(u32),
+    Close,
+}
+
+struct Window {
+    cron: cron::Schedule,
+    length: Duration,
+    next_open: Option<DateTime<Local>>,
+}
+
+impl Window {
+    fn new(settings: &WindowSettings, now: DateTime<Local>) -> Result<Window, cron::error::Error> {
+        let cron = cron::Schedule::from_str(&settings.cron)?;
+        let next_open = cron.after(&now).next();
+        Ok(Window {
+            cron,
+            length: Duration::minutes(settings.minutes as i64),
+            next_open,
+        })
+    }
+}
+
+/// Timed opening and closing of the queue, owned by the state task.
+pub struct Schedule {
+    closes_at: Option<DateTime<Local>>,
+    /// Minutes before closing at which a countdown is announced, largest first.
+    warnings: Vec<u32>,
+    /// Warnings that have not been announced yet for the current `closes_at`.
+    pending_warnings: Vec<u32>,
+    windows: Vec<Window>,
+}
+
+impl Schedule {
+    pub fn new(settings: &ScheduleSettings) -> Schedule {
+        let now = Local::now();
+        let mut warnings = settings.warnings.clone();
+        warnings.sort_unstable_by(|a, b| b.cmp(a));
+        warnings.dedup();
+        let windows = settings
+            .windows
+            .iter()
+            .filter_map(|window| match Window::new(window, now) {
+                Ok(window) => Some(window),
+                Err(e) => {
+                    tracing::error!("Ignoring invalid schedule `{}`: {}", window.cron, e);
+                    None
+                }
+            })
+            .collect();
+
+        Schedule {
+            closes_at: None,
+            warnings,
+            pending_warnings: Vec::new(),
+            windows,
+        }
+    }
+
+    pub fn closes_at(&self) -> Option<DateTime<Local>> {
+        self.closes_at
+    }
+
+    /// Close the queue at `time`, replacing any previously scheduled close.
+    pub fn close_at(&mut self, time: DateTime<Local>) {
+        self.closes_at = Some(time);
+        self.pending_warnings = self.warnings.clone();
+    }
+
+    pub fn cancel(&mut self) {
+        self.closes_at = None;
+        self.pending_warnings.clear();
+    }
+
+    /// The next time `poll` has something to report.
+    pub fn next_deadline(&self) -> Option<DateTime<Local>> {
+        let close = self.closes_at.map(|closes_at| {
+            self.pending_warnings
+                .first()
+                .map(|&minutes| closes_at - Duration::minutes(minutes as i64))
+                .unwrap_or(closes_at)
+        });
+        self.windows
+            .iter()
+            .filter_map(|window| window.next_open)
+            .chain(close)
+            .min()
+    }
+
+    /// Advances the schedule to `now`, returning everything that became due.
+    pub fn poll(&mut self, now: DateTime<Local>) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        for window in self.windows.iter_mut() {
+            if let Some(next_open) = window.next_open {
+                if next_open <= now {
+                    let until = now + window.length;
+                    // Overlapping windows extend the queue rather than cutting it short
+                    if !matches!(self.closes_at, Some(closes_at) if closes_at >= until) {
+                        self.closes_at = Some(until);
+                        self.pending_warnings = self.warnings.clone();
+                    }
+                    window.next_open = window.cron.after(&now).next();
+                    events.push(Event::Open { until });
+                }
+            }
+        }
+
+        if let Some(closes_at) = self.closes_at {
+            if closes_at <= now {
+                self.cancel();
+                events.push(Event::Close);
+            } else {
+                // Only announce once if several warnings became due at once, with the minutes
+                // actually left rather than the threshold, which may have passed a while ago
+                let remaining = closes_at - now;
+                let mut due = false;
+                while let Some(&minutes) = self.pending_warnings.first() {
+                    if remaining <= Duration::minutes(minutes as i64) {
+                        self.pending_warnings.remove(0);
+                        due = true;
+                    } else {
+                        break;
+                    }
+                }
+                if due {
+                    let minutes = (remaining.num_seconds() + 59) / 60;
+                    events.push(Event::Warn(minutes as u32));
+                }
+            }
+        }
+
+        events
+    }
+}
+
+/// Sleeps until `deadline`, or forever if there is nothing scheduled.
+pub async fn sleep_until(deadline: Option<DateTime<Local>>) {
+    match deadline {
+        Some(deadline) => {
+            let duration = (deadline - Local::now())
+                .to_std()
+                .unwrap_or_else(|_| std::time::Duration::from_secs(0));
+            tokio::time::sleep(duration).await
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(warnings: Vec<u32>) -> Schedule {
+        Schedule::new(&ScheduleSettings {
+            warnings,
+            windows: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn warns_then_closes() {
+        let now = Local::now();
+        let mut schedule = schedule(vec![2, 5]);
+        schedule.close_at(now + Duration::minutes(10));
+
+        assert_eq!(schedule.next_deadline(), Some(now + Duration::minutes(5)));
+        assert!(schedule.poll(now).is_empty());
+        assert_eq!(
+            schedule.poll(now + Duration::minutes(5)),
+            vec![Event::Warn(5)]
+        );
+        assert_eq!(
+            schedule.poll(now + Duration::minutes(8)),
+            vec![Event::Warn(2)]
+        );
+        assert_eq!(
+            schedule.poll(now + Duration::minutes(10)),
+            vec![Event::Close]
+        );
+        assert_eq!(schedule.next_deadline(), None);
+    }
+
+    #[test]
+    fn late_poll_warns_once_with_minutes_left() {
+        let now = Local::now();
+        let mut schedule = schedule(vec![5, 2]);
+        schedule.close_at(now + Duration::minutes(10));
+
+        assert_eq!(
+            schedule.poll(now + Duration::minutes(9)),
+            vec![Event::Warn(1)]
+        );
+        assert_eq!(
+            schedule.poll(now + Duration::minutes(9) + Duration::seconds(30)),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn window_opens_queue() {
+        let mut schedule = Schedule::new(&ScheduleSettings {
+            warnings: Vec::new(),
+            windows: vec![WindowSettings {
+                cron: "0 * * * * * *".to_owned(),
+                minutes: 30,
+            }],
+        });
+        let next_open = schedule.next_deadline().unwrap();
+
+        let events = schedule.poll(next_open);
+        assert_eq!(
+            events,
+            vec![Event::Open {
+                until: next_open + Duration::minutes(30)
+            }]
+        );
+        assert_eq!(
+            schedule.closes_at(),
+            Some(next_open + Duration::minutes(30))
+        );
+    }
+}
@@ -1,94 +1,407 @@
-use oneshot::error::RecvError;
 use serde::Deserialize;
-use tokio::sync::oneshot;
 
-use crate::{StateCommand, StateRx, StateTx};
-
-async fn dispatch<T>(tx: StateTx, rx: StateRx<T>, command: StateCommand) -> Result<T, RecvError> {
-    tx.send(command).await.unwrap();
-    rx.await
-}
+use crate::{client::QueueClient, StateTx};
 
 #[derive(Debug, Deserialize)]
 pub struct NextQueryArg {
     count: Option<u16>,
 }
 
+/// Either keep the queue open for `open_for` minutes from now, or close it at `close_at` (`HH:MM`).
+#[derive(Debug, Deserialize)]
+pub struct ScheduleArg {
+    open_for: Option<u32>,
+    close_at: Option<String>,
+}
+
+/// The response for a custom command.
+#[derive(Debug, Deserialize)]
+pub struct CustomCommandArg {
+    response: String,
+}
+
+/// New log filter directives, in `RUST_LOG` syntax.
+#[derive(Debug, Deserialize)]
+pub struct LogLevelArg {
+    directives: String,
+}
+
 mod handlers {
-    use super::{dispatch, NextQueryArg};
+    use super::{CustomCommandArg, LogLevelArg, NextQueryArg, ScheduleArg};
     use crate::{
         chatbot::{self, Commands},
-        StateCommand, StateTx, Token,
+        client::{ClientError, QueueClient},
+        custom_commands::{CustomCommandError, WriteMode},
+        metrics,
+        ready_check::{self, ReadyCheckError},
+        settings::{AdminSettings, AnnouncementSettings, ReadyCheckSettings},
+        LogFilterError, Token,
     };
+    use chrono::{DateTime, Local, NaiveTime};
+    use std::collections::HashMap;
     use std::convert::Infallible;
     use tokio::sync::oneshot;
+    use uuid::Uuid;
+    use warp::http::StatusCode;
+    use warp::reply::{Json, WithStatus};
+    use warp::Reply;
 
-    pub async fn delete_user(user: String, tx: StateTx) -> Result<impl warp::Reply, Infallible> {
-        let (resp_tx, resp_rx) = oneshot::channel();
-        let removed_users = dispatch(tx, resp_rx, StateCommand::RemoveUser { user, tx: resp_tx })
-            .await
-            .unwrap();
-        Ok(warp::reply::json(&removed_users))
+    fn reply(body: &impl serde::Serialize, status: StatusCode) -> WithStatus<Json> {
+        warp::reply::with_status(warp::reply::json(body), status)
     }
 
-    pub async fn get_queue(tx: StateTx) -> Result<impl warp::Reply, Infallible> {
-        let (resp_tx, resp_rx) = oneshot::channel();
-        let queue_status = dispatch(tx, resp_rx, StateCommand::GetQueue(resp_tx))
-            .await
-            .unwrap();
-        Ok(warp::reply::json(&queue_status))
+    // The state task stopped or is stuck, so nothing can be answered
+    fn unavailable(e: ClientError) -> WithStatus<Json> {
+        tracing::error!("Could not reach the queue: {}", e);
+        reply(&e.to_string(), StatusCode::SERVICE_UNAVAILABLE)
+    }
+
+    fn json_or_unavailable<T: serde::Serialize>(
+        result: Result<T, ClientError>,
+    ) -> WithStatus<Json> {
+        match result {
+            Ok(body) => reply(&body, StatusCode::OK),
+            Err(e) => unavailable(e),
+        }
+    }
+
+    pub async fn delete_user(
+        user: String,
+        client: QueueClient,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(match client.remove(&user).await {
+            Ok(Some(removed)) => reply(&removed, StatusCode::OK),
+            Ok(None) => reply(&"No such user", StatusCode::NOT_FOUND),
+            Err(e) => unavailable(e),
+        })
+    }
+
+    pub async fn get_queue(client: QueueClient) -> Result<impl warp::Reply, Infallible> {
+        Ok(json_or_unavailable(client.queue().await))
     }
 
     pub async fn toggle_queue(
-        tx: StateTx,
+        client: QueueClient,
         chatbot_tx: chatbot::Tx,
     ) -> Result<impl warp::Reply, Infallible> {
-        let (resp_tx, resp_rx) = oneshot::channel();
-        let queue_status = dispatch(tx, resp_rx, StateCommand::ToggleQueue(resp_tx))
-            .await
-            .unwrap();
+        let templates = match client.templates(None).await {
+            Ok(templates) => templates,
+            Err(e) => return Ok(unavailable(e)),
+        };
+        let queue_status = match client.toggle().await {
+            Ok(queue_status) => queue_status,
+            Err(e) => return Ok(unavailable(e)),
+        };
+        let message = if queue_status {
+            templates.queue_opened
+        } else {
+            templates.queue_closed
+        };
         chatbot_tx
-            .send(Commands::SendMessage(format!(
-                "The queue is now {}.",
-                if queue_status { "open" } else { "closed" }
-            )))
+            .send(Commands::SendMessage(message))
             .await
             .unwrap();
-        Ok(warp::reply::json(&queue_status))
+        Ok(reply(&queue_status, StatusCode::OK))
     }
 
     pub async fn pop_queue(
         args: NextQueryArg,
-        tx: StateTx,
+        client: QueueClient,
         chatbot_tx: chatbot::Tx,
+        ready_check: ReadyCheckSettings,
     ) -> Result<impl warp::Reply, Infallible> {
-        let (resp_tx, resp_rx) = oneshot::channel();
-        tracing::debug!("Popping: {}", args.count.unwrap_or(4));
-        let popped_entries = dispatch(
-            tx,
-            resp_rx,
-            StateCommand::PopQueue {
-                count: args.count.unwrap_or(4),
-                tx: resp_tx,
-            },
-        )
-        .await
-        .unwrap();
+        let count = args.count.unwrap_or(4);
+        tracing::debug!("Popping: {}", count);
+        // A ready check can take minutes, so it runs on and announces the group itself
+        if ready_check.enabled {
+            return Ok(
+                match ready_check::start(count, &client, &chatbot_tx, &ready_check).await {
+                    Ok(()) => reply(&"Ready check started", StatusCode::ACCEPTED),
+                    Err(e @ ReadyCheckError::Running) => {
+                        reply(&e.to_string(), StatusCode::CONFLICT)
+                    }
+                    Err(ReadyCheckError::Client(e)) => unavailable(e),
+                },
+            );
+        }
+        let templates = match client.templates(None).await {
+            Ok(templates) => templates,
+            Err(e) => return Ok(unavailable(e)),
+        };
+        let popped_entries = match client.pop(count).await {
+            Ok(popped_entries) => popped_entries,
+            Err(e) => return Ok(unavailable(e)),
+        };
         if let Some(popped) = &popped_entries {
-            let temp_users = popped
-                .iter()
-                .map(|u| u.nickname.clone())
-                .collect::<Vec<String>>();
-            let names_message = temp_users.join(", @");
             chatbot_tx
-                .send(Commands::SendMessage(format!(
-                    "Up next: @{}. You can reach BK in game with the following message: @brittleknee Hi.",
-                    names_message
+                .send(Commands::SendMessage(crate::pop_message(
+                    &templates, popped,
                 )))
                 .await
                 .unwrap();
         }
-        Ok(warp::reply::json(&popped_entries))
+        Ok(reply(&popped_entries, StatusCode::OK))
+    }
+
+    pub async fn schedule_queue(
+        args: ScheduleArg,
+        client: QueueClient,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let closes_at = match (args.open_for, args.close_at) {
+            (Some(minutes), None) => client.open_for(minutes).await,
+            (None, Some(close_at)) => match next_occurrence(&close_at) {
+                Some(time) => client.close_at(time).await.map(|()| time),
+                None => {
+                    return Ok(reply(
+                        &"close_at must be formatted as HH:MM",
+                        StatusCode::BAD_REQUEST,
+                    ))
+                }
+            },
+            _ => {
+                return Ok(reply(
+                    &"Expected exactly one of open_for or close_at",
+                    StatusCode::BAD_REQUEST,
+                ))
+            }
+        };
+        Ok(json_or_unavailable(closes_at))
+    }
+
+    pub async fn cancel_schedule(client: QueueClient) -> Result<impl warp::Reply, Infallible> {
+        Ok(match client.cancel_schedule().await {
+            Ok(()) => warp::reply().into_response(),
+            Err(e) => unavailable(e).into_response(),
+        })
+    }
+
+    // The next time the local clock reads `time`, today or tomorrow
+    fn next_occurrence(time: &str) -> Option<DateTime<Local>> {
+        let time = NaiveTime::parse_from_str(time, "%H:%M").ok()?;
+        let now = Local::now();
+        let today = now.date().and_time(time)?;
+        if today > now {
+            Some(today)
+        } else {
+            (now.date() + chrono::Duration::days(1)).and_time(time)
+        }
+    }
+
+    pub async fn get_templates(
+        locale: Option<String>,
+        client: QueueClient,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(match client.locale(locale).await {
+            Ok(Some(templates)) => reply(&templates, StatusCode::OK),
+            Ok(None) => reply(&"No such locale", StatusCode::NOT_FOUND),
+            Err(e) => unavailable(e),
+        })
+    }
+
+    pub async fn set_templates(
+        locale: Option<String>,
+        overrides: HashMap<String, String>,
+        client: QueueClient,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(match client.set_templates(locale, overrides).await {
+            Ok(Ok(())) => reply(&(), StatusCode::OK),
+            Ok(Err(e)) => reply(&e.to_string(), StatusCode::BAD_REQUEST),
+            Err(e) => unavailable(e),
+        })
+    }
+
+    pub async fn get_commands(chatbot_tx: chatbot::Tx) -> Result<impl warp::Reply, Infallible> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        chatbot_tx
+            .send(Commands::GetCommands(resp_tx))
+            .await
+            .unwrap();
+        // The bot drops the request if it isn't running yet
+        Ok(match resp_rx.await {
+            Ok(commands) => reply(&commands, StatusCode::OK),
+            Err(_) => reply(
+                &"The chat bot isn't running",
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+        })
+    }
+
+    pub async fn get_custom_commands(client: QueueClient) -> Result<impl warp::Reply, Infallible> {
+        Ok(json_or_unavailable(client.custom_commands().await))
+    }
+
+    pub async fn set_custom_command(
+        name: String,
+        body: CustomCommandArg,
+        client: QueueClient,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let result = client
+            .set_custom_command(name, body.response, WriteMode::Any)
+            .await;
+        Ok(custom_command_reply(result))
+    }
+
+    pub async fn delete_custom_command(
+        name: String,
+        client: QueueClient,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(custom_command_reply(
+            client.delete_custom_command(name).await,
+        ))
+    }
+
+    fn custom_command_reply(
+        result: Result<Result<(), CustomCommandError>, ClientError>,
+    ) -> WithStatus<Json> {
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => return unavailable(e),
+        };
+        let status = match &result {
+            Ok(()) => StatusCode::OK,
+            Err(CustomCommandError::NotFound(_)) => StatusCode::NOT_FOUND,
+            Err(CustomCommandError::Exists(_)) => StatusCode::CONFLICT,
+            Err(_) => StatusCode::BAD_REQUEST,
+        };
+        match result {
+            Ok(()) => reply(&(), status),
+            Err(e) => reply(&e.to_string(), status),
+        }
+    }
+
+    pub async fn get_announcements(client: QueueClient) -> Result<impl warp::Reply, Infallible> {
+        Ok(json_or_unavailable(client.announcements().await))
+    }
+
+    /// Adds an announcement, or replaces the one with `id`.
+    pub async fn set_announcement(
+        id: Option<Uuid>,
+        announcement: AnnouncementSettings,
+        client: QueueClient,
+    ) -> Result<impl warp::Reply, Infallible> {
+        if announcement.message.trim().is_empty() {
+            return Ok(reply(
+                &"An announcement needs a message",
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+        Ok(match client.set_announcement(id, announcement).await {
+            Ok(Some(id)) => reply(&serde_json::json!({ "id": id }), StatusCode::OK),
+            Ok(None) => reply(&"No such announcement", StatusCode::NOT_FOUND),
+            Err(e) => unavailable(e),
+        })
+    }
+
+    pub async fn delete_announcement(
+        id: Uuid,
+        client: QueueClient,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(match client.delete_announcement(id).await {
+            Ok(true) => reply(&(), StatusCode::OK),
+            Ok(false) => reply(&"No such announcement", StatusCode::NOT_FOUND),
+            Err(e) => unavailable(e),
+        })
+    }
+
+    /// Reads the queue for the gauges before rendering, but still answers when the state task
+    /// doesn't, as that's when the other metrics matter most.
+    pub async fn get_metrics(client: QueueClient) -> Result<impl warp::Reply, Infallible> {
+        // The state task keeps the queue metrics up to date, so there's no need to wait on it
+        metrics::set_state_backlog(client.backlog());
+        Ok(warp::reply::with_header(
+            metrics::render(),
+            "content-type",
+            "text/plain; version=0.0.4",
+        ))
+    }
+
+    // Admin requests have to send `Authorization: Bearer <token>` with the configured token
+    fn check_admin(
+        authorization: Option<String>,
+        admin: &AdminSettings,
+    ) -> Result<(), WithStatus<Json>> {
+        let token = match admin.token.as_deref().filter(|token| !token.is_empty()) {
+            Some(token) => token,
+            None => return Err(reply(&"The admin API is off", StatusCode::FORBIDDEN)),
+        };
+        let given = authorization
+            .as_deref()
+            .and_then(|header| header.strip_prefix("Bearer "));
+        match given {
+            Some(given) if same_token(given, token) => Ok(()),
+            _ => Err(reply(&"Not authorized", StatusCode::UNAUTHORIZED)),
+        }
+    }
+
+    // Compares every byte so the time taken doesn't give away how much of the token was right
+    fn same_token(given: &str, token: &str) -> bool {
+        given.len() == token.len()
+            && given
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    fn log_filter_reply(result: Result<String, LogFilterError>) -> WithStatus<Json> {
+        match result {
+            Ok(directives) => reply(&directives, StatusCode::OK),
+            Err(e @ LogFilterError::Invalid(_)) => reply(&e.to_string(), StatusCode::BAD_REQUEST),
+            Err(e @ LogFilterError::NoSubscriber) => {
+                reply(&e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+
+    pub async fn get_log_level(
+        authorization: Option<String>,
+        admin: AdminSettings,
+    ) -> Result<impl warp::Reply, Infallible> {
+        if let Err(denied) = check_admin(authorization, &admin) {
+            return Ok(denied);
+        }
+        Ok(log_filter_reply(crate::log_filter()))
+    }
+
+    pub async fn set_log_level(
+        body: LogLevelArg,
+        authorization: Option<String>,
+        admin: AdminSettings,
+    ) -> Result<impl warp::Reply, Infallible> {
+        if let Err(denied) = check_admin(authorization, &admin) {
+            return Ok(denied);
+        }
+        let result = crate::set_log_filter(&body.directives).and_then(|()| crate::log_filter());
+        if let Ok(directives) = &result {
+            tracing::info!("Log filter changed to {}", directives);
+        }
+        Ok(log_filter_reply(result))
+    }
+
+    pub async fn get_stats(client: QueueClient) -> Result<impl warp::Reply, Infallible> {
+        Ok(json_or_unavailable(client.stats().await))
+    }
+
+    pub async fn get_bans(client: QueueClient) -> Result<impl warp::Reply, Infallible> {
+        Ok(json_or_unavailable(client.bans().await))
+    }
+
+    pub async fn ban_user(
+        user: String,
+        client: QueueClient,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(json_or_unavailable(client.ban(&user).await))
+    }
+
+    pub async fn unban_user(
+        user: String,
+        client: QueueClient,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(match client.unban(&user).await {
+            Ok(true) => reply(&(), StatusCode::OK),
+            Ok(false) => reply(&"No such ban", StatusCode::NOT_FOUND),
+            Err(e) => unavailable(e),
+        })
     }
 
     pub async fn send_token(token: Token, tx: chatbot::Tx) -> Result<impl warp::Reply, Infallible> {
@@ -99,25 +412,80 @@ mod handlers {
 }
 
 pub mod endpoints {
-    use super::{handlers, NextQueryArg, StateTx};
-    use crate::chatbot;
+    use super::{
+        handlers, CustomCommandArg, LogLevelArg, NextQueryArg, QueueClient, ScheduleArg, StateTx,
+    };
+    use crate::{
+        chatbot, metrics,
+        settings::{AdminSettings, ReadyCheckSettings, Settings},
+    };
 
+    use uuid::Uuid;
     use warp::Filter;
 
     pub fn queue(
         tx: StateTx,
         chatbot_tx: chatbot::Tx,
+        settings: Settings,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         queue_get(tx.clone())
-            .or(queue_pop(tx.clone(), chatbot_tx.clone()))
+            .or(queue_pop(
+                tx.clone(),
+                chatbot_tx.clone(),
+                settings.ready_check,
+            ))
             .or(queue_toggle(tx.clone(), chatbot_tx.clone()))
+            .or(queue_schedule(tx.clone()))
+            .or(schedule_cancel(tx.clone()))
+            .or(templates_get(tx.clone()))
+            .or(templates_put(tx.clone()))
+            .or(commands_get(chatbot_tx.clone()))
+            .or(custom_commands_get(tx.clone()))
+            .or(custom_command_put(tx.clone()))
+            .or(custom_command_delete(tx.clone()))
+            .or(announcements_get(tx.clone()))
+            .or(announcement_post(tx.clone()))
+            .or(announcement_put(tx.clone()))
+            .or(announcement_delete(tx.clone()))
+            .or(stats_get(tx.clone()))
+            .or(bans_get(tx.clone()))
+            .or(ban_put(tx.clone()))
+            .or(ban_delete(tx.clone()))
             .or(token(chatbot_tx))
-            .or(user_delete(tx))
+            .or(user_delete(tx.clone()))
             .or(health())
+            .or(metrics_get(tx))
+            .or(log_level_get(settings.admin.clone()))
+            .or(log_level_put(settings.admin))
             .or(warp::fs::dir("./www/dist/"))
             .with(warp::trace(
                 |info| tracing::info_span!("API request", method = %info.method(), path = %info.path(), id = %uuid::Uuid::new_v4().to_hyphenated()),
             ))
+            .with(warp::log::custom(|info| {
+                metrics::http_request(
+                    info.method().as_str(),
+                    route(info.path()),
+                    info.status().as_u16(),
+                    info.elapsed(),
+                )
+            }))
+    }
+
+    // The API a request was for, without names or ids, so metrics have few labels
+    fn route(path: &str) -> &'static str {
+        let root = path.trim_start_matches('/').split('/').next();
+        match root {
+            Some("queue") => "queue",
+            Some("templates") => "templates",
+            Some("commands") => "commands",
+            Some("announcements") => "announcements",
+            Some("bans") => "bans",
+            Some("stats") => "stats",
+            Some("health") => "health",
+            Some("metrics") => "metrics",
+            Some("admin") => "admin",
+            _ => "static",
+        }
     }
 
     fn with_tx<T>(
@@ -129,13 +497,20 @@ pub mod endpoints {
         warp::any().map(move || tx.clone())
     }
 
+    fn with_client(
+        tx: StateTx,
+    ) -> impl Filter<Extract = (QueueClient,), Error = std::convert::Infallible> + Clone {
+        let client = QueueClient::new(tx);
+        warp::any().map(move || client.clone())
+    }
+
     // DELETE /queue/:name
     pub fn user_delete(
         tx: StateTx,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::path!("queue" / String)
             .and(warp::delete())
-            .and(with_tx(tx))
+            .and(with_client(tx))
             .and_then(handlers::delete_user)
             .with(warp::trace::named("user"))
     }
@@ -146,7 +521,7 @@ pub mod endpoints {
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::path!("queue")
             .and(warp::get())
-            .and(with_tx(tx))
+            .and(with_client(tx))
             .and_then(handlers::get_queue)
             .with(warp::trace::named("queue"))
     }
@@ -158,25 +533,253 @@ pub mod endpoints {
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::path!("queue" / "toggle")
             .and(warp::get())
-            .and(with_tx(tx))
+            .and(with_client(tx))
             .and(with_tx(chatbot_tx))
             .and_then(handlers::toggle_queue)
             .with(warp::trace::named("toggle"))
     }
+
+    // POST /queue/schedule
+    pub fn queue_schedule(
+        tx: StateTx,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("queue" / "schedule")
+            .and(warp::post())
+            .and(warp::body::json::<ScheduleArg>())
+            .and(with_client(tx))
+            .and_then(handlers::schedule_queue)
+            .with(warp::trace::named("schedule"))
+    }
+
+    // DELETE /queue/schedule
+    pub fn schedule_cancel(
+        tx: StateTx,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("queue" / "schedule")
+            .and(warp::delete())
+            .and(with_client(tx))
+            .and_then(handlers::cancel_schedule)
+            .with(warp::trace::named("schedule"))
+    }
+
     // GET /queue/pop?:u16
     pub fn queue_pop(
         tx: StateTx,
         chatbot_tx: chatbot::Tx,
+        ready_check: ReadyCheckSettings,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::path!("queue" / "pop")
             .and(warp::get())
             .and(warp::query::<NextQueryArg>())
-            .and(with_tx(tx))
+            .and(with_client(tx))
             .and(with_tx(chatbot_tx))
+            .and(warp::any().map(move || ready_check.clone()))
             .and_then(handlers::pop_queue)
             .with(warp::trace::named("pop"))
     }
 
+    // GET /templates/:locale, or the default locale's templates without one
+    pub fn templates_get(
+        tx: StateTx,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        with_locale()
+            .and(warp::get())
+            .and(with_client(tx))
+            .and_then(handlers::get_templates)
+            .with(warp::trace::named("templates"))
+    }
+
+    // PUT /templates/:locale, or the default locale's templates without one
+    pub fn templates_put(
+        tx: StateTx,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        with_locale()
+            .and(warp::put())
+            .and(warp::body::json())
+            .and(with_client(tx))
+            .and_then(handlers::set_templates)
+            .with(warp::trace::named("templates"))
+    }
+
+    fn with_locale() -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+        warp::path!("templates" / String)
+            .map(Some)
+            .or(warp::path!("templates").map(|| None))
+            .unify()
+    }
+
+    // GET /commands
+    pub fn commands_get(
+        chatbot_tx: chatbot::Tx,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("commands")
+            .and(warp::get())
+            .and(with_tx(chatbot_tx))
+            .and_then(handlers::get_commands)
+            .with(warp::trace::named("commands"))
+    }
+
+    // GET /commands/custom
+    pub fn custom_commands_get(
+        tx: StateTx,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("commands" / "custom")
+            .and(warp::get())
+            .and(with_client(tx))
+            .and_then(handlers::get_custom_commands)
+            .with(warp::trace::named("custom_commands"))
+    }
+
+    // PUT /commands/custom/:name
+    pub fn custom_command_put(
+        tx: StateTx,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("commands" / "custom" / String)
+            .and(warp::put())
+            .and(warp::body::json::<CustomCommandArg>())
+            .and(with_client(tx))
+            .and_then(handlers::set_custom_command)
+            .with(warp::trace::named("custom_commands"))
+    }
+
+    // DELETE /commands/custom/:name
+    pub fn custom_command_delete(
+        tx: StateTx,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("commands" / "custom" / String)
+            .and(warp::delete())
+            .and(with_client(tx))
+            .and_then(handlers::delete_custom_command)
+            .with(warp::trace::named("custom_commands"))
+    }
+
+    // GET /announcements
+    pub fn announcements_get(
+        tx: StateTx,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("announcements")
+            .and(warp::get())
+            .and(with_client(tx))
+            .and_then(handlers::get_announcements)
+            .with(warp::trace::named("announcements"))
+    }
+
+    // POST /announcements
+    pub fn announcement_post(
+        tx: StateTx,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("announcements")
+            .and(warp::post())
+            .map(|| None)
+            .and(warp::body::json())
+            .and(with_client(tx))
+            .and_then(handlers::set_announcement)
+            .with(warp::trace::named("announcements"))
+    }
+
+    // PUT /announcements/:id
+    pub fn announcement_put(
+        tx: StateTx,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("announcements" / Uuid)
+            .and(warp::put())
+            .map(Some)
+            .and(warp::body::json())
+            .and(with_client(tx))
+            .and_then(handlers::set_announcement)
+            .with(warp::trace::named("announcements"))
+    }
+
+    // DELETE /announcements/:id
+    pub fn announcement_delete(
+        tx: StateTx,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("announcements" / Uuid)
+            .and(warp::delete())
+            .and(with_client(tx))
+            .and_then(handlers::delete_announcement)
+            .with(warp::trace::named("announcements"))
+    }
+
+    // GET /stats
+    pub fn stats_get(
+        tx: StateTx,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("stats")
+            .and(warp::get())
+            .and(with_client(tx))
+            .and_then(handlers::get_stats)
+            .with(warp::trace::named("stats"))
+    }
+
+    // GET /bans
+    pub fn bans_get(
+        tx: StateTx,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("bans")
+            .and(warp::get())
+            .and(with_client(tx))
+            .and_then(handlers::get_bans)
+            .with(warp::trace::named("bans"))
+    }
+
+    // PUT /bans/:name
+    pub fn ban_put(
+        tx: StateTx,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("bans" / String)
+            .and(warp::put())
+            .and(with_client(tx))
+            .and_then(handlers::ban_user)
+            .with(warp::trace::named("bans"))
+    }
+
+    // DELETE /bans/:name
+    pub fn ban_delete(
+        tx: StateTx,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("bans" / String)
+            .and(warp::delete())
+            .and(with_client(tx))
+            .and_then(handlers::unban_user)
+            .with(warp::trace::named("bans"))
+    }
+
+    // GET /metrics
+    pub fn metrics_get(
+        tx: StateTx,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("metrics")
+            .and(warp::get())
+            .and(with_client(tx))
+            .and_then(handlers::get_metrics)
+    }
+
+    // GET /admin/log-level
+    pub fn log_level_get(
+        admin: AdminSettings,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("admin" / "log-level")
+            .and(warp::get())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::any().map(move || admin.clone()))
+            .and_then(handlers::get_log_level)
+            .with(warp::trace::named("log level"))
+    }
+
+    // PUT /admin/log-level
+    pub fn log_level_put(
+        admin: AdminSettings,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("admin" / "log-level")
+            .and(warp::put())
+            .and(warp::body::json::<LogLevelArg>())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::any().map(move || admin.clone()))
+            .and_then(handlers::set_log_level)
+            .with(warp::trace::named("log level"))
+    }
+
     // GET /health
     pub fn health() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::path!("health").and(warp::get()).map(warp::reply)
@@ -199,25 +802,29 @@ pub mod endpoints {
 #[cfg(test)]
 mod tests {
     use crate::{
+        client::QueueClient,
         init_state,
-        {chatbot, server::endpoints, StateCommand},
+        settings::{AdminSettings, QueueSettings, ReadyCheckSettings, Settings, TelemetrySettings},
+        subscriber_init,
+        {chatbot, server::endpoints, StateMessage},
     };
+    use warp::Filter;
 
     struct Txs {
-        state_tx: tokio::sync::mpsc::Sender<StateCommand>,
-        state_rx: tokio::sync::mpsc::Receiver<StateCommand>,
+        state_tx: tokio::sync::mpsc::Sender<StateMessage>,
+        state_rx: tokio::sync::mpsc::Receiver<StateMessage>,
         chat_tx: chatbot::Tx,
-        _chat_rx: chatbot::Rx,
+        chat_rx: chatbot::Rx,
     }
 
     fn init_tx() -> Txs {
         let (state_tx, state_rx) = tokio::sync::mpsc::channel(1);
-        let (chat_tx, _chat_rx) = tokio::sync::mpsc::channel(1);
+        let (chat_tx, chat_rx) = tokio::sync::mpsc::channel(1);
         Txs {
             state_tx,
             state_rx,
             chat_tx,
-            _chat_rx,
+            chat_rx,
         }
     }
 
@@ -246,4 +853,382 @@ mod tests {
 
         assert_eq!(value.status(), 405);
     }
+
+    #[tokio::test]
+    async fn log_level_needs_the_admin_token() {
+        let _subscriber = subscriber_init(&TelemetrySettings::default());
+        let admin = AdminSettings {
+            token: Some("secret".to_owned()),
+        };
+        let filter = endpoints::log_level_put(admin.clone()).or(endpoints::log_level_get(admin));
+        let put = |body: &str, authorization: &str| {
+            warp::test::request()
+                .method("PUT")
+                .path("/admin/log-level")
+                .header("authorization", authorization)
+                .body(format!(r#"{{"directives":"{}"}}"#, body))
+        };
+
+        let off = warp::test::request()
+            .path("/admin/log-level")
+            .header("authorization", "Bearer secret")
+            .reply(&endpoints::log_level_get(AdminSettings::default()))
+            .await;
+        assert_eq!(off.status(), 403);
+        let value = warp::test::request()
+            .path("/admin/log-level")
+            .reply(&filter)
+            .await;
+        assert_eq!(value.status(), 401);
+        let value = put("trace", "Bearer secrex").reply(&filter).await;
+        assert_eq!(value.status(), 401);
+
+        let value = put("irc=nonsense", "Bearer secret").reply(&filter).await;
+        assert_eq!(value.status(), 400);
+        let value = put("info,irc=trace", "Bearer secret").reply(&filter).await;
+        assert_eq!(value.status(), 200);
+        let value = warp::test::request()
+            .path("/admin/log-level")
+            .header("authorization", "Bearer secret")
+            .reply(&filter)
+            .await;
+        assert_eq!(value.status(), 200);
+        assert!(String::from_utf8_lossy(value.body()).contains("irc=trace"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn get_metrics_reports_queue_and_requests() {
+        let txs = init_tx();
+        let _state = init_state(txs.state_rx, txs.chat_tx.clone(), Settings::default()).await;
+        let filter = endpoints::queue(txs.state_tx, txs.chat_tx, Settings::default());
+
+        let value = warp::test::request().path("/health").reply(&filter).await;
+        assert_eq!(value.status(), 200);
+        let value = warp::test::request().path("/metrics").reply(&filter).await;
+
+        assert_eq!(value.status(), 200);
+        let body = String::from_utf8(value.body().to_vec()).unwrap();
+        // Every test's state task sets the same gauges, so only check they're reported
+        assert!(body.contains("brittlq_queue_length "), "{}", body);
+        assert!(body.contains("brittlq_queue_open "), "{}", body);
+        assert!(
+            body.contains(
+                r#"brittlq_http_requests_total{method="GET",route="health",status="200"}"#
+            ),
+            "{}",
+            body
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn get_queue_reports_capacity() {
+        let txs = init_tx();
+        let _state = init_state(
+            txs.state_rx,
+            txs.chat_tx.clone(),
+            Settings {
+                queue: QueueSettings {
+                    capacity: Some(10),
+                    reopen_below: None,
+                },
+                ..Default::default()
+            },
+        )
+        .await;
+        let queue_filter = endpoints::queue_get(txs.state_tx);
+
+        let value = warp::test::request()
+            .path("/queue")
+            .reply(&queue_filter)
+            .await;
+
+        assert_eq!(value.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(value.body()).unwrap();
+        assert_eq!(body["capacity"], 10);
+        assert_eq!(body["is_open"], false);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn schedule_opens_and_cancels() {
+        let mut txs = init_tx();
+        let _state = init_state(txs.state_rx, txs.chat_tx.clone(), Settings::default()).await;
+        let filter = endpoints::queue_schedule(txs.state_tx.clone())
+            .or(endpoints::schedule_cancel(txs.state_tx.clone()))
+            .or(endpoints::queue_get(txs.state_tx));
+        let get_queue = || async {
+            let value = warp::test::request().path("/queue").reply(&filter).await;
+            serde_json::from_slice::<serde_json::Value>(value.body()).unwrap()
+        };
+
+        let value = warp::test::request()
+            .path("/queue/schedule")
+            .method("POST")
+            .json(&serde_json::json!({ "open_for": 30 }))
+            .reply(&filter)
+            .await;
+        assert_eq!(value.status(), 200);
+        let closes_at: serde_json::Value = serde_json::from_slice(value.body()).unwrap();
+        assert!(txs.chat_rx.recv().await.is_some());
+        let queue = get_queue().await;
+        assert_eq!(queue["is_open"], true);
+        assert_eq!(queue["closes_at"], closes_at);
+
+        let value = warp::test::request()
+            .path("/queue/schedule")
+            .method("DELETE")
+            .reply(&filter)
+            .await;
+        assert_eq!(value.status(), 200);
+        let queue = get_queue().await;
+        assert_eq!(queue["is_open"], true);
+        assert_eq!(queue["closes_at"], serde_json::Value::Null);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn schedule_needs_one_valid_time() {
+        let txs = init_tx();
+        let _state = init_state(txs.state_rx, txs.chat_tx.clone(), Settings::default()).await;
+        let filter = endpoints::queue_schedule(txs.state_tx);
+
+        for body in &[
+            serde_json::json!({}),
+            serde_json::json!({ "open_for": 30, "close_at": "21:30" }),
+            serde_json::json!({ "close_at": "half past nine" }),
+        ] {
+            let value = warp::test::request()
+                .path("/queue/schedule")
+                .method("POST")
+                .json(body)
+                .reply(&filter)
+                .await;
+            assert_eq!(value.status(), 400, "{}", body);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn pop_during_ready_check_409() {
+        let mut txs = init_tx();
+        let settings = Settings {
+            ready_check: ReadyCheckSettings {
+                enabled: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let _state = init_state(txs.state_rx, txs.chat_tx.clone(), settings.clone()).await;
+        let client = QueueClient::new(txs.state_tx.clone());
+        client.toggle().await.unwrap();
+        client.add_user("a", None, None).await.unwrap().unwrap();
+        let filter = endpoints::queue_pop(txs.state_tx, txs.chat_tx, settings.ready_check);
+        let pop = || {
+            warp::test::request()
+                .path("/queue/pop?count=1")
+                .reply(&filter)
+        };
+
+        assert_eq!(pop().await.status(), 202);
+        // The ping has gone out
+        assert!(txs.chat_rx.recv().await.is_some());
+        assert_eq!(pop().await.status(), 409);
+
+        assert!(client.mark_here("a").await.unwrap());
+        match txs.chat_rx.recv().await {
+            Some(chatbot::Commands::SendMessage(message)) => {
+                assert!(message.contains("@a"), "{}", message)
+            }
+            other => panic!("expected the group to be called up, got {:?}", other),
+        }
+        assert_eq!(client.queue_length().await.unwrap(), 0);
+        assert_eq!(pop().await.status(), 202);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn ready_check_ends_without_a_chat_bot() {
+        let txs = init_tx();
+        let settings = Settings {
+            ready_check: ReadyCheckSettings {
+                enabled: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let _state = init_state(txs.state_rx, txs.chat_tx.clone(), settings.clone()).await;
+        let client = QueueClient::new(txs.state_tx.clone());
+        client.toggle().await.unwrap();
+        client.add_user("a", None, None).await.unwrap().unwrap();
+        drop(txs.chat_rx);
+        let filter = endpoints::queue_pop(txs.state_tx, txs.chat_tx, settings.ready_check);
+        let pop = || {
+            warp::test::request()
+                .path("/queue/pop?count=1")
+                .reply(&filter)
+        };
+
+        assert_eq!(pop().await.status(), 202);
+        // The ping can't be sent, so the check finishes rather than blocking later pops
+        let mut status = pop().await.status();
+        for _ in 0..50 {
+            if status != 409 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            status = pop().await.status();
+        }
+        assert_eq!(status, 202);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn put_invalid_templates_400() {
+        let txs = init_tx();
+        let _state = init_state(txs.state_rx, txs.chat_tx.clone(), Settings::default()).await;
+        let templates_filter = endpoints::templates_put(txs.state_tx);
+
+        let value = warp::test::request()
+            .path("/templates")
+            .method("PUT")
+            .json(&serde_json::json!({ "leave": "{user} left {nowhere}" }))
+            .reply(&templates_filter)
+            .await;
+
+        assert_eq!(value.status(), 400);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn get_commands_without_bot_503() {
+        let (chat_tx, mut chat_rx) = tokio::sync::mpsc::channel(4);
+        // Stands in for main before the bot has started, dropping what it gets
+        tokio::spawn(async move { while chat_rx.recv().await.is_some() {} });
+        let commands_filter = endpoints::commands_get(chat_tx);
+
+        let value = warp::test::request()
+            .path("/commands")
+            .reply(&commands_filter)
+            .await;
+
+        assert_eq!(value.status(), 503);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn custom_commands_crud() {
+        let mut txs = init_tx();
+        let _state = init_state(txs.state_rx, txs.chat_tx.clone(), Settings::default()).await;
+        let filter = endpoints::custom_command_put(txs.state_tx.clone())
+            .or(endpoints::custom_command_delete(txs.state_tx.clone()))
+            .or(endpoints::custom_commands_get(txs.state_tx));
+
+        let value = warp::test::request()
+            .path("/commands/custom/discord")
+            .method("PUT")
+            .json(&serde_json::json!({ "response": "discord.gg/x" }))
+            .reply(&filter)
+            .await;
+        assert_eq!(value.status(), 200);
+        assert!(matches!(
+            txs.chat_rx.recv().await,
+            Some(chatbot::Commands::CustomCommand {
+                response: Some(_),
+                ..
+            })
+        ));
+
+        let value = warp::test::request()
+            .path("/commands/custom")
+            .reply(&filter)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(value.body()).unwrap();
+        assert_eq!(body, serde_json::json!({ "discord": "discord.gg/x" }));
+
+        let value = warp::test::request()
+            .path("/commands/custom/discord")
+            .method("DELETE")
+            .reply(&filter)
+            .await;
+        assert_eq!(value.status(), 200);
+        let value = warp::test::request()
+            .path("/commands/custom/discord")
+            .method("DELETE")
+            .reply(&filter)
+            .await;
+        assert_eq!(value.status(), 404);
+
+        // Built-in commands can't be replaced
+        let value = warp::test::request()
+            .path("/commands/custom/join")
+            .method("PUT")
+            .json(&serde_json::json!({ "response": "nope" }))
+            .reply(&filter)
+            .await;
+        assert_eq!(value.status(), 409);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn manage_announcements() {
+        let txs = init_tx();
+        let _state = init_state(txs.state_rx, txs.chat_tx.clone(), Settings::default()).await;
+        let filter = endpoints::announcement_post(txs.state_tx.clone())
+            .or(endpoints::announcement_put(txs.state_tx.clone()))
+            .or(endpoints::announcement_delete(txs.state_tx.clone()))
+            .or(endpoints::announcements_get(txs.state_tx));
+
+        let value = warp::test::request()
+            .path("/announcements")
+            .method("POST")
+            .json(&serde_json::json!({ "message": "Type !join", "only_while_open": true }))
+            .reply(&filter)
+            .await;
+        assert_eq!(value.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(value.body()).unwrap();
+        let path = format!("/announcements/{}", body["id"].as_str().unwrap());
+
+        let value = warp::test::request()
+            .path(&path)
+            .method("PUT")
+            .json(&serde_json::json!({ "message": "Type !join now", "interval_mins": 5 }))
+            .reply(&filter)
+            .await;
+        assert_eq!(value.status(), 200);
+
+        let value = warp::test::request()
+            .path("/announcements")
+            .reply(&filter)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(value.body()).unwrap();
+        assert_eq!(body[0]["message"], "Type !join now");
+        assert_eq!(body[0]["interval_mins"], 5);
+
+        let value = warp::test::request()
+            .path(&path)
+            .method("DELETE")
+            .reply(&filter)
+            .await;
+        assert_eq!(value.status(), 200);
+        let value = warp::test::request()
+            .path(&path)
+            .method("DELETE")
+            .reply(&filter)
+            .await;
+        assert_eq!(value.status(), 404);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn delete_user_returns_the_entry() {
+        let txs = init_tx();
+        let _state = init_state(txs.state_rx, txs.chat_tx.clone(), Settings::default()).await;
+        let client = QueueClient::new(txs.state_tx.clone());
+        assert!(client.toggle().await.unwrap());
+        assert_eq!(client.add_user("alice", None, None).await.unwrap(), Ok(0));
+        let filter = endpoints::user_delete(txs.state_tx);
+        let delete = || {
+            warp::test::request()
+                .path("/queue/alice")
+                .method("DELETE")
+                .reply(&filter)
+        };
+
+        let value = delete().await;
+        assert_eq!(value.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(value.body()).unwrap();
+        assert_eq!(body["nickname"], "alice");
+        assert_eq!(delete().await.status(), 404);
+    }
 }
@@ -0,0 +1,385 @@
+use crate::locale::Locales;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Everything read from `Settings.toml` and `TWITCH_*` environment variables.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Settings {
+    pub name: Option<String>,
+    pub channel: Option<String>,
+    #[serde(default)]
+    pub queue: QueueSettings,
+    #[serde(default)]
+    pub storage: StorageSettings,
+    #[serde(default)]
+    pub telemetry: TelemetrySettings,
+    #[serde(default)]
+    pub admin: AdminSettings,
+    #[serde(default)]
+    pub schedule: ScheduleSettings,
+    #[serde(default)]
+    pub ready_check: ReadyCheckSettings,
+    #[serde(default)]
+    pub presence: PresenceSettings,
+    #[serde(default)]
+    pub commands: CommandSettings,
+    #[serde(default)]
+    pub announcements: Vec<AnnouncementSettings>,
+    /// Runs the bot in a Discord channel as well, sharing the queue with Twitch chat.
+    pub discord: Option<DiscordSettings>,
+    /// The locale used for channels not listed in `channel_locales`.
+    #[serde(default = "default_locale")]
+    pub locale: String,
+    #[serde(default)]
+    pub channel_locales: HashMap<String, String>,
+    /// Templates to change, by locale and template name.
+    #[serde(default)]
+    pub locales: HashMap<String, HashMap<String, String>>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            name: None,
+            channel: None,
+            queue: QueueSettings::default(),
+            storage: StorageSettings::default(),
+            telemetry: TelemetrySettings::default(),
+            admin: AdminSettings::default(),
+            schedule: ScheduleSettings::default(),
+            ready_check: ReadyCheckSettings::default(),
+            presence: PresenceSettings::default(),
+            commands: CommandSettings::default(),
+            announcements: Vec::new(),
+            discord: None,
+            locale: default_locale(),
+            channel_locales: HashMap::new(),
+            locales: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DiscordSettings {
+    /// The bot's token from the Discord developer portal.
+    pub token: String,
+    /// The channel the bot takes commands in and posts announcements to.
+    pub channel_id: String,
+    /// Members with any of these roles are treated as moderators. In slash commands, so are members
+    /// who can manage messages, as only those come with the member's permissions.
+    #[serde(default)]
+    pub moderator_roles: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct CommandSettings {
+    /// What chat messages must start with to be treated as commands.
+    pub prefixes: Vec<String>,
+    /// Extra names for commands, by the command's built-in name.
+    pub aliases: HashMap<String, Vec<String>>,
+    /// Built-in commands the bot should not respond to.
+    pub disabled: Vec<String>,
+    /// How often each command may be used, by the command's built-in name. Moderators and the
+    /// broadcaster aren't held to these.
+    pub cooldowns: HashMap<String, CooldownSettings>,
+    /// How each command responds, by the command's built-in name. Commands not listed respond in
+    /// the channel.
+    pub responses: HashMap<String, ResponseMode>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseMode {
+    /// A message in the channel.
+    Channel,
+    /// A reply in the thread of the message that used the command.
+    Reply,
+    /// A whisper to whoever used the command.
+    Whisper,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CooldownSettings {
+    /// Seconds after a command is used before anyone can use it again.
+    pub global_secs: u64,
+    /// Seconds after a user uses a command before that user can use it again.
+    pub user_secs: u64,
+}
+
+impl CommandSettings {
+    pub fn is_disabled(&self, name: &str) -> bool {
+        self.disabled
+            .iter()
+            .any(|disabled| disabled.eq_ignore_ascii_case(name))
+    }
+
+    /// Checks that commands can be used at all.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.prefixes.iter().all(|prefix| prefix.is_empty()) {
+            return Err("`prefixes` needs at least one prefix".to_owned());
+        }
+        Ok(())
+    }
+}
+
+impl Default for CommandSettings {
+    fn default() -> Self {
+        CommandSettings {
+            prefixes: vec!["!".to_owned()],
+            aliases: HashMap::new(),
+            disabled: Vec::new(),
+            cooldowns: HashMap::new(),
+            responses: HashMap::new(),
+        }
+    }
+}
+
+/// A message posted to chat every so often.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AnnouncementSettings {
+    pub message: String,
+    /// Minutes between posts.
+    #[serde(default = "default_interval")]
+    pub interval_mins: u32,
+    /// How many chat messages must have been sent since the last post before posting again.
+    #[serde(default)]
+    pub min_lines: u32,
+    /// Only post while the queue is open.
+    #[serde(default)]
+    pub only_while_open: bool,
+}
+
+fn default_interval() -> u32 {
+    15
+}
+
+fn default_locale() -> String {
+    "en".to_owned()
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct QueueSettings {
+    /// Maximum number of users allowed in the queue. Unbounded when unset.
+    pub capacity: Option<usize>,
+    /// When the queue closes itself because it hit `capacity`, reopen it once its length drops
+    /// below this value. The queue stays closed until toggled when unset.
+    pub reopen_below: Option<usize>,
+}
+
+impl QueueSettings {
+    /// Checks that the queue can hold someone and that a full queue can reopen.
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.capacity, self.reopen_below) {
+            (Some(0), _) => Err("`capacity` must be at least 1".to_owned()),
+            (_, Some(0)) => Err("`reopen_below` must be at least 1".to_owned()),
+            (Some(capacity), Some(reopen_below)) if reopen_below > capacity => Err(format!(
+                "`reopen_below` ({}) can't be more than `capacity` ({})",
+                reopen_below, capacity
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct StorageSettings {
+    pub backend: StorageBackend,
+    /// The database file, for the `sqlite` backend.
+    pub path: PathBuf,
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        StorageSettings {
+            backend: StorageBackend::Memory,
+            path: PathBuf::from("brittlq.db"),
+        }
+    }
+}
+
+/// Where the queue, its history, bans and settings are kept.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// In memory, so everything is lost when the bot stops.
+    Memory,
+    /// In a SQLite database, so the queue survives restarts.
+    Sqlite,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AdminSettings {
+    /// The bearer token `/admin` requests must send in their `Authorization` header. The admin
+    /// API is off when this isn't set.
+    pub token: Option<String>,
+}
+
+/// Where traces are sent, besides the logs on stdout.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct TelemetrySettings {
+    /// An OpenTelemetry collector to export spans to over OTLP, like `http://localhost:4317`.
+    /// Nothing is exported when this isn't set.
+    pub otlp_endpoint: Option<String>,
+    /// The name traces are reported under.
+    pub service_name: String,
+}
+
+impl Default for TelemetrySettings {
+    fn default() -> Self {
+        TelemetrySettings {
+            otlp_endpoint: None,
+            service_name: "brittlq".to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ScheduleSettings {
+    /// Minutes before a scheduled close at which a countdown is posted in chat.
+    #[serde(default = "default_warnings")]
+    pub warnings: Vec<u32>,
+    /// Recurring windows during which the queue opens by itself.
+    #[serde(default)]
+    pub windows: Vec<WindowSettings>,
+}
+
+impl Default for ScheduleSettings {
+    fn default() -> Self {
+        ScheduleSettings {
+            warnings: default_warnings(),
+            windows: Vec::new(),
+        }
+    }
+}
+
+fn default_warnings() -> Vec<u32> {
+    vec![5, 2, 1]
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WindowSettings {
+    /// When to open the queue, as `sec min hour day-of-month month day-of-week [year]`.
+    pub cron: String,
+    /// How long the queue stays open, in minutes.
+    pub minutes: u32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ReadyCheckSettings {
+    /// Ask users to confirm with `!here` before their group is popped.
+    pub enabled: bool,
+    /// How long users have to answer, in seconds.
+    pub timeout_secs: u64,
+    /// How many times to ping replacements for users who didn't answer before giving up.
+    pub rounds: u32,
+    pub on_timeout: OnTimeout,
+}
+
+impl Default for ReadyCheckSettings {
+    fn default() -> Self {
+        ReadyCheckSettings {
+            enabled: false,
+            timeout_secs: 60,
+            rounds: 3,
+            on_timeout: OnTimeout::Back,
+        }
+    }
+}
+
+/// What happens to users who don't answer a ready check.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnTimeout {
+    /// Move them to the back of the queue.
+    Back,
+    /// Remove them from the queue.
+    Remove,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct PresenceSettings {
+    /// How long a queued user may be gone from the channel before `absent` applies, in seconds.
+    pub grace_secs: u64,
+    pub absent: AbsentAction,
+}
+
+impl Default for PresenceSettings {
+    fn default() -> Self {
+        PresenceSettings {
+            grace_secs: 300,
+            absent: AbsentAction::Mark,
+        }
+    }
+}
+
+/// What happens to queued users who left the channel once their grace period runs out.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AbsentAction {
+    /// Keep them in the queue, flagged as not present.
+    Mark,
+    /// Remove them from the queue.
+    Remove,
+}
+
+pub fn load() -> Settings {
+    let mut settings = config::Config::default();
+    settings
+        .merge(config::File::with_name("Settings").required(false))
+        .unwrap()
+        .merge(config::Environment::with_prefix("TWITCH"))
+        .unwrap();
+    let settings = settings.try_into::<Settings>().unwrap();
+    if let Err(e) = settings.queue.validate() {
+        panic!("Invalid [queue] in Settings.toml: {}", e);
+    }
+    if let Err(e) = settings.commands.validate() {
+        panic!("Invalid [commands] in Settings.toml: {}", e);
+    }
+    if let Err(e) = Locales::new(&settings) {
+        panic!("Invalid template in Settings.toml: {}", e);
+    }
+    settings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(capacity: Option<usize>, reopen_below: Option<usize>) -> QueueSettings {
+        QueueSettings {
+            capacity,
+            reopen_below,
+        }
+    }
+
+    #[test]
+    fn queue_settings_must_allow_reopening() {
+        assert!(queue(None, None).validate().is_ok());
+        assert!(queue(Some(5), Some(5)).validate().is_ok());
+        assert!(queue(Some(5), Some(2)).validate().is_ok());
+        assert!(queue(Some(0), None).validate().is_err());
+        assert!(queue(Some(5), Some(0)).validate().is_err());
+        assert!(queue(Some(5), Some(6)).validate().is_err());
+    }
+
+    #[test]
+    fn commands_need_a_prefix() {
+        let commands = |prefixes: &[&str]| CommandSettings {
+            prefixes: prefixes.iter().map(|&prefix| prefix.to_owned()).collect(),
+            ..CommandSettings::default()
+        };
+        assert!(commands(&["!"]).validate().is_ok());
+        assert!(commands(&["", "?"]).validate().is_ok());
+        assert!(commands(&[]).validate().is_err());
+        assert!(commands(&[""]).validate().is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use crate::UserEntry;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::collections::HashSet;
+
+/// How the queue has been used since it was last opened, counted by the state task as it goes.
+#[derive(Debug)]
+pub struct Stats {
+    started_at: DateTime<Local>,
+    joins: usize,
+    users: HashSet<String>,
+    /// Seconds each popped user waited, from joining to being popped.
+    waits: Vec<i64>,
+    abandoned: usize,
+    peak_length: usize,
+}
+
+/// A snapshot of [`Stats`], as `GET /stats` returns it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StatsReport {
+    pub started_at: DateTime<Local>,
+    pub total_joins: usize,
+    pub unique_users: usize,
+    pub popped: usize,
+    /// Users who left or were removed before being popped. Banned users aren't counted.
+    pub abandoned: usize,
+    /// The share of users who left the queue without being popped, from 0 to 1.
+    pub abandonment_rate: f64,
+    pub average_wait_secs: Option<i64>,
+    pub median_wait_secs: Option<i64>,
+    pub max_wait_secs: Option<i64>,
+    pub peak_queue_length: usize,
+    pub joins_per_minute: f64,
+}
+
+impl Stats {
+    /// Starts counting from `now`, with `queue_length` users already queued.
+    pub fn new(now: DateTime<Local>, queue_length: usize) -> Stats {
+        Stats {
+            started_at: now,
+            joins: 0,
+            users: HashSet::new(),
+            waits: Vec::new(),
+            abandoned: 0,
+            peak_length: queue_length,
+        }
+    }
+
+    /// Starts counting again from `now`, as the queue opens for a new stream.
+    pub fn opened(&mut self, now: DateTime<Local>, queue_length: usize) {
+        *self = Stats::new(now, queue_length);
+    }
+
+    /// Records that `user` joined, leaving `queue_length` users in the queue.
+    pub fn joined(&mut self, user: &str, queue_length: usize) {
+        self.joins += 1;
+        self.users.insert(user.to_owned());
+        self.peak_length = self.peak_length.max(queue_length);
+    }
+
+    pub fn popped(&mut self, entries: &[UserEntry], now: DateTime<Local>) {
+        self.waits.extend(
+            entries
+                .iter()
+                .map(|entry| (now - entry.time_joined).num_seconds().max(0)),
+        );
+    }
+
+    /// Records that `count` users left the queue without being popped.
+    pub fn left(&mut self, count: usize) {
+        self.abandoned += count;
+    }
+
+    pub fn report(&self, now: DateTime<Local>) -> StatsReport {
+        let mut waits = self.waits.clone();
+        waits.sort_unstable();
+        let median = match waits.len() {
+            0 => None,
+            n if n % 2 == 0 => Some((waits[n / 2 - 1] + waits[n / 2]) / 2),
+            n => Some(waits[n / 2]),
+        };
+        let left = waits.len() + self.abandoned;
+        let minutes = (now - self.started_at).num_seconds() as f64 / 60.0;
+
+        StatsReport {
+            started_at: self.started_at,
+            total_joins: self.joins,
+            unique_users: self.users.len(),
+            popped: waits.len(),
+            abandoned: self.abandoned,
+            abandonment_rate: if left == 0 {
+                0.0
+            } else {
+                self.abandoned as f64 / left as f64
+            },
+            average_wait_secs: (!waits.is_empty())
+                .then(|| waits.iter().sum::<i64>() / waits.len() as i64),
+            median_wait_secs: median,
+            max_wait_secs: waits.last().copied(),
+            peak_queue_length: self.peak_length,
+            joins_per_minute: if minutes > 0.0 {
+                self.joins as f64 / minutes
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn joined_at(name: &str, time: DateTime<Local>) -> UserEntry {
+        UserEntry {
+            time_joined: time,
+            ..UserEntry::new(name.to_owned())
+        }
+    }
+
+    #[test]
+    fn counts_joins_waits_and_abandonment() {
+        let start = Local::now();
+        let mut stats = Stats::new(start, 1);
+
+        for (length, user) in ["a", "b", "c", "a", "d"].iter().enumerate() {
+            stats.joined(user, length + 1);
+        }
+        let later = start + Duration::minutes(10);
+        stats.popped(
+            &[
+                joined_at("a", later - Duration::seconds(60)),
+                joined_at("b", later - Duration::seconds(180)),
+                joined_at("c", later - Duration::seconds(120)),
+            ],
+            later,
+        );
+        stats.left(1);
+
+        let report = stats.report(later);
+        assert_eq!(report.total_joins, 5);
+        assert_eq!(report.unique_users, 4);
+        assert_eq!(report.popped, 3);
+        assert_eq!(report.abandonment_rate, 0.25);
+        assert_eq!(report.average_wait_secs, Some(120));
+        assert_eq!(report.median_wait_secs, Some(120));
+        assert_eq!(report.max_wait_secs, Some(180));
+        assert_eq!(report.peak_queue_length, 5);
+        assert_eq!(report.joins_per_minute, 0.5);
+    }
+
+    #[test]
+    fn empty_stats_have_no_waits() {
+        let start = Local::now();
+        let report = Stats::new(start, 3).report(start);
+        assert_eq!(report.average_wait_secs, None);
+        assert_eq!(report.median_wait_secs, None);
+        assert_eq!(report.abandonment_rate, 0.0);
+        assert_eq!(report.joins_per_minute, 0.0);
+        assert_eq!(report.peak_queue_length, 3);
+    }
+}
@@ -0,0 +1,319 @@
+use crate::{
+    settings::{StorageBackend, StorageSettings},
+    UserEntry,
+};
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+pub mod sqlite;
+
+pub use sqlite::SqliteStore;
+
+/// Someone who was popped off the queue, kept so the streamer can see who they've played with.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HistoryEntry {
+    pub nickname: String,
+    pub ign: Option<String>,
+    pub time_joined: DateTime<Local>,
+    pub time_popped: DateTime<Local>,
+}
+
+impl HistoryEntry {
+    fn popped(entry: &UserEntry, time_popped: DateTime<Local>) -> HistoryEntry {
+        HistoryEntry {
+            nickname: entry.nickname.clone(),
+            ign: entry.ign.clone(),
+            time_joined: entry.time_joined,
+            time_popped,
+        }
+    }
+}
+
+/// Where the queue and everything kept alongside it lives. Only the state task touches it, so
+/// nothing here is async, and backends that can fail to save log it and carry on rather than
+/// lose the queue.
+pub trait QueueStore: Send {
+    /// Everyone in the queue, front first.
+    fn entries(&self) -> &VecDeque<UserEntry>;
+
+    /// Adds `entry` to the back of the queue.
+    fn push(&mut self, entry: UserEntry);
+
+    /// Changes the entry at `index`. Does nothing if there isn't one.
+    fn update(&mut self, index: usize, change: &mut dyn FnMut(&mut UserEntry));
+
+    /// Takes up to `count` entries that `pick` accepts off the queue, front first, and adds them
+    /// to the history.
+    fn pop_where(
+        &mut self,
+        count: usize,
+        pick: &mut dyn FnMut(&UserEntry) -> bool,
+    ) -> Vec<UserEntry>;
+
+    /// Removes every entry `pick` accepts, without adding them to the history.
+    fn remove_where(&mut self, pick: &mut dyn FnMut(&UserEntry) -> bool) -> Vec<UserEntry>;
+
+    /// The last `limit` users popped, most recent first.
+    fn history(&self, limit: usize) -> Vec<HistoryEntry>;
+
+    /// Everyone who may not join, in alphabetical order.
+    fn bans(&self) -> Vec<String>;
+
+    fn is_banned(&self, user: &str) -> bool;
+
+    /// Returns false if `user` was already banned.
+    fn ban(&mut self, user: &str) -> bool;
+
+    /// Returns false if `user` wasn't banned.
+    fn unban(&mut self, user: &str) -> bool;
+
+    fn setting(&self, key: &str) -> Option<String>;
+
+    fn set_setting(&mut self, key: &str, value: &str);
+
+    fn len(&self) -> usize {
+        self.entries().len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries().is_empty()
+    }
+
+    /// Where `nickname` is in the queue, if they're in it.
+    fn find(&self, nickname: &str) -> Option<usize> {
+        self.entries()
+            .iter()
+            .position(|entry| entry.nickname == nickname)
+    }
+
+    /// Takes the first `count` entries off the queue.
+    fn pop(&mut self, count: usize) -> Vec<UserEntry> {
+        self.pop_where(count, &mut |_| true)
+    }
+
+    /// Removes `nickname` from the queue, returning their entry if they were in it.
+    fn remove(&mut self, nickname: &str) -> Option<UserEntry> {
+        self.remove_where(&mut |entry| entry.nickname == nickname)
+            .pop()
+    }
+}
+
+/// Opens the store chosen in the settings. A database that was asked for but can't be opened is
+/// an error rather than a queue that is silently lost on restart.
+pub fn open(settings: &StorageSettings) -> anyhow::Result<Box<dyn QueueStore>> {
+    Ok(match settings.backend {
+        StorageBackend::Memory => Box::new(MemoryStore::default()),
+        StorageBackend::Sqlite => Box::new(SqliteStore::open(&settings.path)?),
+    })
+}
+
+/// Keeps everything in memory, so it's gone when the bot stops.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    entries: VecDeque<UserEntry>,
+    /// Oldest first.
+    history: Vec<HistoryEntry>,
+    bans: BTreeSet<String>,
+    settings: HashMap<String, String>,
+}
+
+impl QueueStore for MemoryStore {
+    fn entries(&self) -> &VecDeque<UserEntry> {
+        &self.entries
+    }
+
+    fn push(&mut self, entry: UserEntry) {
+        self.entries.push_back(entry);
+    }
+
+    fn update(&mut self, index: usize, change: &mut dyn FnMut(&mut UserEntry)) {
+        if let Some(entry) = self.entries.get_mut(index) {
+            change(entry);
+        }
+    }
+
+    fn pop_where(
+        &mut self,
+        count: usize,
+        pick: &mut dyn FnMut(&UserEntry) -> bool,
+    ) -> Vec<UserEntry> {
+        let mut popped = Vec::new();
+        let mut kept = VecDeque::with_capacity(self.entries.len());
+        for entry in self.entries.drain(..) {
+            if popped.len() < count && pick(&entry) {
+                popped.push(entry);
+            } else {
+                kept.push_back(entry);
+            }
+        }
+        self.entries = kept;
+
+        let now = Local::now();
+        self.history
+            .extend(popped.iter().map(|entry| HistoryEntry::popped(entry, now)));
+        popped
+    }
+
+    fn remove_where(&mut self, pick: &mut dyn FnMut(&UserEntry) -> bool) -> Vec<UserEntry> {
+        let (removed, kept) = self.entries.drain(..).partition(|entry| pick(entry));
+        self.entries = kept;
+        removed.into_iter().collect()
+    }
+
+    fn history(&self, limit: usize) -> Vec<HistoryEntry> {
+        self.history.iter().rev().take(limit).cloned().collect()
+    }
+
+    fn bans(&self) -> Vec<String> {
+        self.bans.iter().cloned().collect()
+    }
+
+    fn is_banned(&self, user: &str) -> bool {
+        self.bans.contains(&user.to_lowercase())
+    }
+
+    fn ban(&mut self, user: &str) -> bool {
+        self.bans.insert(user.to_lowercase())
+    }
+
+    fn unban(&mut self, user: &str) -> bool {
+        self.bans.remove(&user.to_lowercase())
+    }
+
+    fn setting(&self, key: &str) -> Option<String> {
+        self.settings.get(key).cloned()
+    }
+
+    fn set_setting(&mut self, key: &str, value: &str) {
+        self.settings.insert(key.to_owned(), value.to_owned());
+    }
+}
+
+/// Tests every backend has to pass, run once per backend by `store_tests!`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nicknames(entries: &[UserEntry]) -> Vec<&str> {
+        entries.iter().map(|u| u.nickname.as_str()).collect()
+    }
+
+    fn queued(store: &dyn QueueStore) -> Vec<&str> {
+        store
+            .entries()
+            .iter()
+            .map(|u| u.nickname.as_str())
+            .collect()
+    }
+
+    fn push(store: &mut dyn QueueStore, names: &[&str]) {
+        for name in names {
+            store.push(UserEntry::new(name.to_string()));
+        }
+    }
+
+    fn entries_keep_their_order(store: &mut dyn QueueStore) {
+        push(store, &["a", "b", "c"]);
+        assert_eq!(store.find("b"), Some(1));
+        assert_eq!(store.find("d"), None);
+
+        assert_eq!(nicknames(&store.pop(2)), ["a", "b"]);
+        push(store, &["a"]);
+        assert_eq!(queued(store), ["c", "a"]);
+        assert_eq!(store.len(), 2);
+
+        store.pop(5);
+        assert!(store.is_empty());
+        assert!(store.pop(1).is_empty());
+    }
+
+    fn entries_can_be_changed_and_removed(store: &mut dyn QueueStore) {
+        push(store, &["a", "b", "c", "d"]);
+        store.update(1, &mut |entry| entry.ign = Some("Bee".to_owned()));
+        store.update(9, &mut |entry| entry.ign = Some("Nobody".to_owned()));
+        assert_eq!(store.entries()[1].ign.as_deref(), Some("Bee"));
+
+        assert_eq!(
+            store.remove("a").map(|entry| entry.nickname),
+            Some("a".to_owned())
+        );
+        assert!(store.remove("a").is_none());
+        let removed = store.remove_where(&mut |entry| entry.nickname != "c");
+        assert_eq!(nicknames(&removed), ["b", "d"]);
+        assert_eq!(queued(store), ["c"]);
+    }
+
+    fn popped_users_are_remembered(store: &mut dyn QueueStore) {
+        push(store, &["a", "b", "c", "d"]);
+        store.update(2, &mut |entry| entry.ign = Some("Sea".to_owned()));
+        store.remove("a");
+        store.pop(1);
+        assert_eq!(
+            nicknames(&store.pop_where(1, &mut |u| u.nickname == "c")),
+            ["c"]
+        );
+
+        let history = store.history(10);
+        let names: Vec<_> = history.iter().map(|h| h.nickname.as_str()).collect();
+        assert_eq!(names, ["c", "b"]);
+        assert_eq!(history[0].ign.as_deref(), Some("Sea"));
+        assert!(history[0].time_popped >= history[0].time_joined);
+        assert_eq!(store.history(1).len(), 1);
+    }
+
+    fn bans_ignore_case(store: &mut dyn QueueStore) {
+        assert!(store.ban("Bob"));
+        assert!(!store.ban("bob"));
+        assert!(store.ban("alice"));
+        assert!(store.is_banned("BOB"));
+        assert_eq!(store.bans(), ["alice", "bob"]);
+
+        assert!(store.unban("bob"));
+        assert!(!store.unban("bob"));
+        assert!(!store.is_banned("bob"));
+    }
+
+    fn settings_are_replaced(store: &mut dyn QueueStore) {
+        assert_eq!(store.setting("is_open"), None);
+        store.set_setting("is_open", "true");
+        store.set_setting("is_open", "false");
+        assert_eq!(store.setting("is_open").as_deref(), Some("false"));
+    }
+
+    macro_rules! store_tests {
+        ($backend:ident, $new:expr) => {
+            mod $backend {
+                use super::super::*;
+
+                store_tests!(@tests $new;
+                    entries_keep_their_order,
+                    entries_can_be_changed_and_removed,
+                    popped_users_are_remembered,
+                    bans_ignore_case,
+                    settings_are_replaced
+                );
+            }
+        };
+        (@tests $new:expr; $($test:ident),*) => {$(
+            #[test]
+            fn $test() {
+                super::$test(&mut $new);
+            }
+        )*};
+    }
+
+    store_tests!(memory, MemoryStore::default());
+    store_tests!(sqlite, SqliteStore::open_in_memory().unwrap());
+
+    #[test]
+    fn a_database_that_cannot_be_opened_is_an_error() {
+        let settings = StorageSettings {
+            backend: StorageBackend::Sqlite,
+            path: std::env::temp_dir()
+                .join(uuid::Uuid::new_v4().to_string())
+                .join("brittlq.db"),
+        };
+        assert!(open(&settings).is_err());
+    }
+}
@@ -0,0 +1,318 @@
+use super::{HistoryEntry, MemoryStore, QueueStore};
+use crate::UserEntry;
+use chrono::{DateTime, Local};
+use rusqlite::{params, Connection, Transaction, NO_PARAMS};
+use std::collections::VecDeque;
+use std::path::Path;
+use uuid::Uuid;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS entries (
+        position INTEGER PRIMARY KEY,
+        id TEXT NOT NULL UNIQUE,
+        nickname TEXT NOT NULL,
+        display_name TEXT,
+        time_joined TEXT NOT NULL,
+        ign TEXT,
+        note TEXT,
+        present INTEGER NOT NULL,
+        parted_at TEXT
+    );
+    CREATE TABLE IF NOT EXISTS history (
+        id INTEGER PRIMARY KEY,
+        nickname TEXT NOT NULL,
+        ign TEXT,
+        time_joined TEXT NOT NULL,
+        time_popped TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS bans (
+        nickname TEXT PRIMARY KEY
+    );
+    CREATE TABLE IF NOT EXISTS settings (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+";
+
+/// Keeps the queue in a SQLite database so it survives restarts. Everything is loaded when the
+/// database is opened and read from memory after that; changes are written through as they
+/// happen.
+pub struct SqliteStore {
+    conn: Connection,
+    cache: MemoryStore,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> anyhow::Result<SqliteStore> {
+        SqliteStore::load(Connection::open(path)?)
+    }
+
+    /// A database that is gone when the store is dropped, for tests.
+    pub fn open_in_memory() -> anyhow::Result<SqliteStore> {
+        SqliteStore::load(Connection::open_in_memory()?)
+    }
+
+    fn load(conn: Connection) -> anyhow::Result<SqliteStore> {
+        conn.execute_batch(SCHEMA)?;
+        let cache = read(&conn)?;
+        Ok(SqliteStore { conn, cache })
+    }
+
+    // The change has already been made in memory, so a failed write is logged rather than undone
+    fn write(&mut self, change: impl FnOnce(&Transaction) -> rusqlite::Result<()>) {
+        let result = self.conn.transaction().and_then(|tx| {
+            change(&tx)?;
+            tx.commit()
+        });
+        if let Err(e) = result {
+            tracing::error!("Could not save the queue: {}", e);
+        }
+    }
+}
+
+// Everything saved in the database
+fn read(conn: &Connection) -> anyhow::Result<MemoryStore> {
+    let mut cache = MemoryStore::default();
+
+    let mut query = conn.prepare(
+        "SELECT id, nickname, display_name, time_joined, ign, note, present, parted_at
+         FROM entries ORDER BY position",
+    )?;
+    let rows = query.query_map(NO_PARAMS, |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, Option<String>>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, Option<String>>(4)?,
+            row.get::<_, Option<String>>(5)?,
+            row.get::<_, bool>(6)?,
+            row.get::<_, Option<String>>(7)?,
+        ))
+    })?;
+    for row in rows {
+        let (id, nickname, display_name, time_joined, ign, note, present, parted_at) = row?;
+        cache.entries.push_back(UserEntry {
+            nickname,
+            display_name,
+            time_joined: parse_time(&time_joined)?,
+            id: Uuid::parse_str(&id)?,
+            ign,
+            note,
+            present,
+            parted_at: parted_at.as_deref().map(parse_time).transpose()?,
+        });
+    }
+
+    let mut query =
+        conn.prepare("SELECT nickname, ign, time_joined, time_popped FROM history ORDER BY id")?;
+    let rows = query.query_map(NO_PARAMS, |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, Option<String>>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+        ))
+    })?;
+    for row in rows {
+        let (nickname, ign, time_joined, time_popped) = row?;
+        cache.history.push(HistoryEntry {
+            nickname,
+            ign,
+            time_joined: parse_time(&time_joined)?,
+            time_popped: parse_time(&time_popped)?,
+        });
+    }
+
+    let mut query = conn.prepare("SELECT nickname FROM bans")?;
+    for nickname in query.query_map(NO_PARAMS, |row| row.get::<_, String>(0))? {
+        cache.bans.insert(nickname?);
+    }
+
+    let mut query = conn.prepare("SELECT key, value FROM settings")?;
+    let rows = query.query_map(NO_PARAMS, |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+    for row in rows {
+        let (key, value) = row?;
+        cache.settings.insert(key, value);
+    }
+    Ok(cache)
+}
+
+fn parse_time(time: &str) -> anyhow::Result<DateTime<Local>> {
+    Ok(DateTime::parse_from_rfc3339(time)?.with_timezone(&Local))
+}
+
+fn insert_entry(tx: &Transaction, entry: &UserEntry) -> rusqlite::Result<()> {
+    tx.execute(
+        "INSERT INTO entries (id, nickname, display_name, time_joined, ign, note, present, parted_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        params![
+            entry.id.to_string(),
+            entry.nickname,
+            entry.display_name,
+            entry.time_joined.to_rfc3339(),
+            entry.ign,
+            entry.note,
+            entry.present,
+            entry.parted_at.map(|time| time.to_rfc3339()),
+        ],
+    )?;
+    Ok(())
+}
+
+fn delete_entries(tx: &Transaction, entries: &[UserEntry]) -> rusqlite::Result<()> {
+    for entry in entries {
+        tx.execute(
+            "DELETE FROM entries WHERE id = ?",
+            params![entry.id.to_string()],
+        )?;
+    }
+    Ok(())
+}
+
+impl QueueStore for SqliteStore {
+    fn entries(&self) -> &VecDeque<UserEntry> {
+        self.cache.entries()
+    }
+
+    fn push(&mut self, entry: UserEntry) {
+        self.write(|tx| insert_entry(tx, &entry));
+        self.cache.push(entry);
+    }
+
+    fn update(&mut self, index: usize, change: &mut dyn FnMut(&mut UserEntry)) {
+        self.cache.update(index, change);
+        let entry = match self.cache.entries().get(index) {
+            Some(entry) => entry.clone(),
+            None => return,
+        };
+        self.write(|tx| {
+            tx.execute(
+                "UPDATE entries SET ign = ?, note = ?, present = ?, parted_at = ? WHERE id = ?",
+                params![
+                    entry.ign,
+                    entry.note,
+                    entry.present,
+                    entry.parted_at.map(|time| time.to_rfc3339()),
+                    entry.id.to_string(),
+                ],
+            )?;
+            Ok(())
+        });
+    }
+
+    fn pop_where(
+        &mut self,
+        count: usize,
+        pick: &mut dyn FnMut(&UserEntry) -> bool,
+    ) -> Vec<UserEntry> {
+        let popped = self.cache.pop_where(count, pick);
+        let history = self.cache.history(popped.len());
+        self.write(|tx| {
+            delete_entries(tx, &popped)?;
+            for entry in history.iter().rev() {
+                tx.execute(
+                    "INSERT INTO history (nickname, ign, time_joined, time_popped)
+                     VALUES (?, ?, ?, ?)",
+                    params![
+                        entry.nickname,
+                        entry.ign,
+                        entry.time_joined.to_rfc3339(),
+                        entry.time_popped.to_rfc3339(),
+                    ],
+                )?;
+            }
+            Ok(())
+        });
+        popped
+    }
+
+    fn remove_where(&mut self, pick: &mut dyn FnMut(&UserEntry) -> bool) -> Vec<UserEntry> {
+        let removed = self.cache.remove_where(pick);
+        self.write(|tx| delete_entries(tx, &removed));
+        removed
+    }
+
+    fn history(&self, limit: usize) -> Vec<HistoryEntry> {
+        self.cache.history(limit)
+    }
+
+    fn bans(&self) -> Vec<String> {
+        self.cache.bans()
+    }
+
+    fn is_banned(&self, user: &str) -> bool {
+        self.cache.is_banned(user)
+    }
+
+    fn ban(&mut self, user: &str) -> bool {
+        let banned = self.cache.ban(user);
+        let user = user.to_lowercase();
+        self.write(|tx| {
+            tx.execute(
+                "INSERT OR IGNORE INTO bans (nickname) VALUES (?)",
+                params![user],
+            )?;
+            Ok(())
+        });
+        banned
+    }
+
+    fn unban(&mut self, user: &str) -> bool {
+        let unbanned = self.cache.unban(user);
+        let user = user.to_lowercase();
+        self.write(|tx| {
+            tx.execute("DELETE FROM bans WHERE nickname = ?", params![user])?;
+            Ok(())
+        });
+        unbanned
+    }
+
+    fn setting(&self, key: &str) -> Option<String> {
+        self.cache.setting(key)
+    }
+
+    fn set_setting(&mut self, key: &str, value: &str) {
+        self.cache.set_setting(key, value);
+        self.write(|tx| {
+            tx.execute(
+                "INSERT OR REPLACE INTO settings (key, value) VALUES (?, ?)",
+                params![key, value],
+            )?;
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_everything_across_restarts() {
+        let path = std::env::temp_dir().join(format!("brittlq-{}.db", Uuid::new_v4()));
+
+        let mut store = SqliteStore::open(&path).unwrap();
+        for name in &["a", "b", "c"] {
+            store.push(UserEntry::new(name.to_string()));
+        }
+        store.update(2, &mut |entry| entry.note = Some("hi".to_owned()));
+        store.pop(1);
+        store.ban("mallory");
+        store.set_setting("is_open", "true");
+        let entries = store.entries().clone();
+        let history = store.history(10);
+        drop(store);
+
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.entries(), &entries);
+        assert_eq!(store.history(10), history);
+        assert!(store.is_banned("mallory"));
+        assert_eq!(store.setting("is_open").as_deref(), Some("true"));
+
+        drop(store);
+        std::fs::remove_file(path).unwrap();
+    }
+}
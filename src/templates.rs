@@ -0,0 +1,455 @@
+use crate::locale;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Everything the bot says in chat, in one language. Placeholders like `{user}` are filled in when
+/// a message is sent, and `{{` and `}}` produce literal braces. A placeholder holding a number can
+/// pick a plural form with `{groups:# group|# groups}`, where `#` is replaced with the number and
+/// the forms are listed in the order given by [`locale::plural_forms`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Templates {
+    /// The language these templates are written in, which decides how plurals are chosen.
+    pub locale: String,
+    pub place: String,
+    pub on_deck: String,
+    pub groups_ahead: String,
+    pub not_in_queue: String,
+    pub queue_full: String,
+    pub leave: String,
+    pub peek: String,
+    pub peek_empty: String,
+    pub info_updated: String,
+    pub info_too_long: String,
+    pub field_ign: String,
+    pub field_note: String,
+    pub queue_opened: String,
+    pub queue_closed: String,
+    pub queue_filled: String,
+    pub queue_reopened: String,
+    pub scheduled_open: String,
+    pub closing_warning: String,
+    pub pop: String,
+    pub pop_member: String,
+    pub pop_member_ign: String,
+    pub ready_check: String,
+    pub ready_check_moved: String,
+    pub ready_check_removed: String,
+    pub help: String,
+    pub help_command: String,
+    pub help_unknown: String,
+    pub command_added: String,
+    pub command_updated: String,
+    pub command_deleted: String,
+    pub command_exists: String,
+    pub command_invalid: String,
+    pub stats: String,
+    pub wrong_channel: String,
+    /// What each built-in command does, for `!help`.
+    pub about_join: String,
+    pub about_next: String,
+    pub about_place: String,
+    pub about_leave: String,
+    pub about_setign: String,
+    pub about_note: String,
+    pub about_here: String,
+    pub about_help: String,
+    pub about_addcmd: String,
+    pub about_editcmd: String,
+    pub about_delcmd: String,
+    pub about_qstats: String,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Templates {
+            locale: "en".to_owned(),
+            place: "{user} is #{position}. {ahead}, wait time is approximately {wait}-{wait_max} minutes".to_owned(),
+            on_deck: "You're on deck".to_owned(),
+            groups_ahead: "There {groups:is # group|are # groups} ahead of you".to_owned(),
+            not_in_queue: "{user} is not in the queue".to_owned(),
+            queue_full: "Sorry {user}, the queue is full.".to_owned(),
+            leave: "{user} has been removed from the queue.".to_owned(),
+            peek: "{users}".to_owned(),
+            peek_empty: "The queue is empty".to_owned(),
+            info_updated: "{user}, your {field} has been updated.".to_owned(),
+            info_too_long: "{user}, your {field} can be at most {max} characters.".to_owned(),
+            field_ign: "in-game name".to_owned(),
+            field_note: "note".to_owned(),
+            queue_opened: "The queue is now open.".to_owned(),
+            queue_closed: "The queue is now closed.".to_owned(),
+            queue_filled: "The queue is full and is now closed.".to_owned(),
+            queue_reopened: "The queue has room again and is now open.".to_owned(),
+            scheduled_open: "The queue is now open until {time}. Type !join to join!".to_owned(),
+            closing_warning: "The queue closes in {minutes:# minute|# minutes}.".to_owned(),
+            pop: "Up next: {group}. You can reach BK in game with the following message: @brittleknee Hi.".to_owned(),
+            pop_member: "@{user}".to_owned(),
+            pop_member_ign: "@{user} (IGN: {ign})".to_owned(),
+            ready_check: "{users} you're up next! Type !here within {timeout} seconds to keep your spot.".to_owned(),
+            ready_check_moved: "Moved to the back of the queue for not answering in time: {users}".to_owned(),
+            ready_check_removed: "Removed from the queue for not answering in time: {users}".to_owned(),
+            help: "Commands: {commands}".to_owned(),
+            help_command: "{usage} - {description}".to_owned(),
+            help_unknown: "There is no {command} command.".to_owned(),
+            command_added: "{command} has been added.".to_owned(),
+            command_updated: "{command} has been updated.".to_owned(),
+            command_deleted: "{command} has been deleted.".to_owned(),
+            command_exists: "There is already a {command} command.".to_owned(),
+            command_invalid: "{command} can't be saved: {error}".to_owned(),
+            stats: "{joins:# join|# joins} from {users:# user|# users} ({rate} per minute). Wait: {average} min average, {median} min median, {max} min longest. {abandoned}% left before their turn. Longest queue: {peak}.".to_owned(),
+            wrong_channel: "Commands only work in {channel}.".to_owned(),
+            about_join: "Join the queue".to_owned(),
+            about_next: "See who is up next".to_owned(),
+            about_place: "See your place in the queue".to_owned(),
+            about_leave: "Leave the queue".to_owned(),
+            about_setign: "Set your in-game name".to_owned(),
+            about_note: "Attach a note to your queue entry".to_owned(),
+            about_here: "Confirm you're still here when a ready check calls you".to_owned(),
+            about_help: "List the commands, or explain one".to_owned(),
+            about_addcmd: "Add a command that replies with the given response".to_owned(),
+            about_editcmd: "Change the response of a command added with addcmd".to_owned(),
+            about_delcmd: "Delete a command added with addcmd".to_owned(),
+            about_qstats: "Show how the queue has been used this stream".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TemplateError {
+    /// A `{` without a matching `}`, or a stray `}`.
+    Unbalanced { template: &'static str },
+    UnknownPlaceholder {
+        template: &'static str,
+        placeholder: String,
+    },
+    /// A plural placeholder with a different number of forms than the locale uses.
+    PluralForms {
+        template: &'static str,
+        expected: usize,
+    },
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Unbalanced { template } => {
+                write!(f, "`{}` has an unmatched brace", template)
+            }
+            TemplateError::UnknownPlaceholder {
+                template,
+                placeholder,
+            } => write!(
+                f,
+                "`{}` has unknown placeholder {{{}}}",
+                template, placeholder
+            ),
+            TemplateError::PluralForms { template, expected } => write!(
+                f,
+                "`{}` must give {} plural forms separated by `|`",
+                template, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl Templates {
+    // Every template along with the placeholders it may use
+    fn fields(&self) -> Vec<(&'static str, &str, &'static [&'static str])> {
+        vec![
+            (
+                "place",
+                &self.place,
+                &["user", "position", "ahead", "wait", "wait_max"],
+            ),
+            ("on_deck", &self.on_deck, &["user"]),
+            ("groups_ahead", &self.groups_ahead, &["user", "groups"]),
+            ("not_in_queue", &self.not_in_queue, &["user"]),
+            ("queue_full", &self.queue_full, &["user"]),
+            ("leave", &self.leave, &["user"]),
+            ("peek", &self.peek, &["users", "count"]),
+            ("peek_empty", &self.peek_empty, &[]),
+            ("info_updated", &self.info_updated, &["user", "field"]),
+            (
+                "info_too_long",
+                &self.info_too_long,
+                &["user", "field", "max"],
+            ),
+            ("field_ign", &self.field_ign, &[]),
+            ("field_note", &self.field_note, &[]),
+            ("queue_opened", &self.queue_opened, &[]),
+            ("queue_closed", &self.queue_closed, &[]),
+            ("queue_filled", &self.queue_filled, &[]),
+            ("queue_reopened", &self.queue_reopened, &[]),
+            ("scheduled_open", &self.scheduled_open, &["time"]),
+            ("closing_warning", &self.closing_warning, &["minutes"]),
+            ("pop", &self.pop, &["group", "count"]),
+            ("pop_member", &self.pop_member, &["user", "note"]),
+            (
+                "pop_member_ign",
+                &self.pop_member_ign,
+                &["user", "ign", "note"],
+            ),
+            ("ready_check", &self.ready_check, &["users", "timeout"]),
+            ("ready_check_moved", &self.ready_check_moved, &["users"]),
+            ("ready_check_removed", &self.ready_check_removed, &["users"]),
+            ("help", &self.help, &["commands"]),
+            (
+                "help_command",
+                &self.help_command,
+                &["usage", "description"],
+            ),
+            ("help_unknown", &self.help_unknown, &["command"]),
+            ("command_added", &self.command_added, &["command"]),
+            ("command_updated", &self.command_updated, &["command"]),
+            ("command_deleted", &self.command_deleted, &["command"]),
+            ("command_exists", &self.command_exists, &["command"]),
+            (
+                "command_invalid",
+                &self.command_invalid,
+                &["command", "error"],
+            ),
+            (
+                "stats",
+                &self.stats,
+                &[
+                    "joins",
+                    "users",
+                    "rate",
+                    "average",
+                    "median",
+                    "max",
+                    "abandoned",
+                    "peak",
+                ],
+            ),
+            ("wrong_channel", &self.wrong_channel, &["channel"]),
+            ("about_join", &self.about_join, &[]),
+            ("about_next", &self.about_next, &[]),
+            ("about_place", &self.about_place, &[]),
+            ("about_leave", &self.about_leave, &[]),
+            ("about_setign", &self.about_setign, &[]),
+            ("about_note", &self.about_note, &[]),
+            ("about_here", &self.about_here, &[]),
+            ("about_help", &self.about_help, &[]),
+            ("about_addcmd", &self.about_addcmd, &[]),
+            ("about_editcmd", &self.about_editcmd, &[]),
+            ("about_delcmd", &self.about_delcmd, &[]),
+            ("about_qstats", &self.about_qstats, &[]),
+        ]
+    }
+
+    /// Checks that every template is well formed and only uses placeholders that will be filled.
+    pub fn validate(&self) -> Result<(), TemplateError> {
+        for (name, template, allowed) in self.fields() {
+            check(name, template, allowed, self.plural_locale(template))?;
+        }
+        Ok(())
+    }
+
+    /// The locale whose plural rules `template`, one of these templates, follows. A locale without
+    /// its own bundle starts from the English templates, which keep their English plurals until
+    /// they're translated.
+    fn plural_locale(&self, template: &str) -> &str {
+        let english = Templates::default();
+        let untranslated = self
+            .fields()
+            .into_iter()
+            .zip(english.fields())
+            .any(|((_, own, _), (_, default, _))| own == template && own == default);
+        if untranslated {
+            "en"
+        } else {
+            &self.locale
+        }
+    }
+
+    /// Fills in the placeholders of `template`, one of these templates, from `values`.
+    /// Placeholders without a value are left as is.
+    pub fn render(&self, template: &str, values: &[(&str, &(dyn fmt::Display + Sync))]) -> String {
+        let pieces = match parse(template) {
+            Ok(pieces) => pieces,
+            Err(()) => return template.to_owned(),
+        };
+        let value = |name| {
+            values
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        };
+
+        let mut out = String::with_capacity(template.len());
+        for piece in pieces {
+            match piece {
+                Piece::Text(text) => out.push_str(text),
+                Piece::Placeholder(name) => match value(name) {
+                    Some(value) => out.push_str(&value),
+                    None => {
+                        out.push('{');
+                        out.push_str(name);
+                        out.push('}');
+                    }
+                },
+                Piece::Plural(name, forms) => {
+                    let value = value(name).unwrap_or_default();
+                    let rules = self.plural_locale(template);
+                    let form = match value.parse::<u64>() {
+                        Ok(n) => forms.get(locale::plural_index(rules, n)),
+                        Err(_) => forms.last(),
+                    };
+                    out.push_str(&form.unwrap_or(&"").replace('#', &value));
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Checks that `template`, called `name` in errors, is well formed and only uses the `allowed`
+/// placeholders, with as many plural forms as `locale` needs.
+pub fn check(
+    name: &'static str,
+    template: &str,
+    allowed: &[&str],
+    locale: &str,
+) -> Result<(), TemplateError> {
+    let forms = locale::plural_forms(locale);
+    let pieces = parse(template).map_err(|_| TemplateError::Unbalanced { template: name })?;
+    for piece in pieces {
+        let placeholder = match piece {
+            Piece::Text(_) => continue,
+            Piece::Placeholder(placeholder) => placeholder,
+            Piece::Plural(placeholder, plurals) => {
+                if plurals.len() != forms {
+                    return Err(TemplateError::PluralForms {
+                        template: name,
+                        expected: forms,
+                    });
+                }
+                placeholder
+            }
+        };
+        if !allowed.contains(&placeholder) {
+            return Err(TemplateError::UnknownPlaceholder {
+                template: name,
+                placeholder: placeholder.to_owned(),
+            });
+        }
+    }
+    Ok(())
+}
+
+enum Piece<'a> {
+    Text(&'a str),
+    Placeholder(&'a str),
+    Plural(&'a str, Vec<&'a str>),
+}
+
+fn parse(template: &str) -> Result<Vec<Piece<'_>>, ()> {
+    let mut pieces = Vec::new();
+    let mut rest = template;
+    while let Some(index) = rest.find(['{', '}']) {
+        pieces.push(Piece::Text(&rest[..index]));
+        let tail = &rest[index..];
+        if let Some(after) = tail.strip_prefix("{{") {
+            pieces.push(Piece::Text("{"));
+            rest = after;
+        } else if let Some(after) = tail.strip_prefix("}}") {
+            pieces.push(Piece::Text("}"));
+            rest = after;
+        } else if let Some(after) = tail.strip_prefix('{') {
+            let end = after.find('}').ok_or(())?;
+            let inner = &after[..end];
+            if inner.contains('{') {
+                return Err(());
+            }
+            match inner.split_once(':') {
+                Some((name, forms)) => {
+                    pieces.push(Piece::Plural(name.trim(), forms.split('|').collect()))
+                }
+                None => pieces.push(Piece::Placeholder(inner.trim())),
+            }
+            rest = &after[end + 1..];
+        } else {
+            return Err(());
+        }
+    }
+    pieces.push(Piece::Text(rest));
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_placeholders() {
+        let templates = Templates::default();
+        assert_eq!(
+            templates.render(
+                "{user} is #{position}",
+                &[("user", &"foo"), ("position", &3)]
+            ),
+            "foo is #3"
+        );
+        assert_eq!(templates.render("{{literal}} {x}", &[]), "{literal} {x}");
+    }
+
+    #[test]
+    fn renders_plurals() {
+        let templates = Templates::default();
+        let render = |n: usize| templates.render(&templates.groups_ahead, &[("groups", &n)]);
+        assert_eq!(render(1), "There is 1 group ahead of you");
+        assert_eq!(render(2), "There are 2 groups ahead of you");
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(Templates::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        let templates = Templates {
+            leave: "{user} left after {minutes} minutes".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            templates.validate(),
+            Err(TemplateError::UnknownPlaceholder {
+                template: "leave",
+                placeholder: "minutes".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_number_of_plural_forms() {
+        let templates = Templates {
+            closing_warning: "Closing in {minutes:# minutes}".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            templates.validate(),
+            Err(TemplateError::PluralForms {
+                template: "closing_warning",
+                expected: 2
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_unbalanced_braces() {
+        for leave in &["{user", "user}", "{{user}"] {
+            let templates = Templates {
+                leave: leave.to_string(),
+                ..Default::default()
+            };
+            assert_eq!(
+                templates.validate(),
+                Err(TemplateError::Unbalanced { template: "leave" })
+            );
+        }
+    }
+}
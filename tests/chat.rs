@@ -0,0 +1,188 @@
+//! The bot against a mock Twitch chat, from connecting to answering commands.
+
+mod common;
+
+use brittlq::{
+    chatbot::{self, twitch::TwitchTransport, Bot},
+    client::QueueClient,
+    init_state,
+    settings::{CommandSettings, Settings},
+};
+use common::MockIrc;
+
+/// Starts the state and a bot connected to `irc`, with the queue open.
+async fn start_bot(irc: &mut MockIrc) -> QueueClient {
+    let (state_tx, state_rx) = tokio::sync::mpsc::channel(8);
+    let (chat_tx, chat_rx) = tokio::sync::mpsc::channel(8);
+    init_state(state_rx, chat_tx, Settings::default()).await;
+    let client = QueueClient::new(state_tx);
+    assert!(client.toggle().await.unwrap());
+
+    let transport = TwitchTransport::new(irc.config("bot")).await.unwrap();
+    let mut bot = Bot::new(transport, chat_rx);
+    chatbot::build_bot(&mut bot, &CommandSettings::default());
+    let bot_client = client.clone();
+    tokio::spawn(async move { bot.run(bot_client).await });
+
+    irc.expect_join().await;
+    client
+}
+
+#[tokio::test]
+async fn join_place_and_leave() {
+    let mut irc = MockIrc::start("#chan").await;
+    let _state = start_bot(&mut irc).await;
+
+    irc.say("alice", "!join");
+    let reply = irc.next_message().await;
+    assert!(reply.starts_with("alice is #1."), "{}", reply);
+
+    irc.say("bob", "!join");
+    let reply = irc.next_message().await;
+    assert!(reply.starts_with("bob is #2."), "{}", reply);
+
+    irc.say("alice", "!place");
+    let reply = irc.next_message().await;
+    assert!(reply.starts_with("alice is #1."), "{}", reply);
+
+    irc.say("alice", "!leave");
+    assert_eq!(
+        irc.next_message().await,
+        "alice has been removed from the queue."
+    );
+
+    irc.say("bob", "!place");
+    let reply = irc.next_message().await;
+    assert!(reply.starts_with("bob is #1."), "{}", reply);
+}
+
+#[tokio::test]
+async fn next_shows_upcoming_group() {
+    let mut irc = MockIrc::start("#chan").await;
+    let _state = start_bot(&mut irc).await;
+
+    irc.say("carol", "!next");
+    assert_eq!(irc.next_message().await, "The queue is empty");
+
+    for user in &["alice", "bob"] {
+        irc.say(user, "!join");
+        irc.next_message().await;
+    }
+    irc.say("carol", "!next");
+    assert_eq!(irc.next_message().await, "alice, bob");
+}
+
+#[tokio::test]
+async fn moderator_commands_need_moderator_badges() {
+    let mut irc = MockIrc::start("#chan").await;
+    let _state = start_bot(&mut irc).await;
+
+    irc.say("alice", "!addcmd discord nope");
+    irc.say_as_moderator("mod", "!addcmd discord Join us on Discord");
+    assert_eq!(irc.next_message().await, "!discord has been added.");
+
+    irc.say("alice", "!discord");
+    assert_eq!(irc.next_message().await, "Join us on Discord");
+}
+
+#[tokio::test]
+async fn reconnects_and_keeps_the_queue() {
+    let mut irc = MockIrc::start("#chan").await;
+    let _state = start_bot(&mut irc).await;
+
+    irc.say("alice", "!join");
+    irc.next_message().await;
+
+    irc.disconnect();
+    irc.expect_join().await;
+
+    irc.say("alice", "!place");
+    let reply = irc.next_message().await;
+    assert!(reply.starts_with("alice is #1."), "{}", reply);
+}
+
+#[tokio::test]
+async fn missing_arguments_get_usage() {
+    let mut irc = MockIrc::start("#chan").await;
+    let _state = start_bot(&mut irc).await;
+
+    irc.say("alice", "!setign");
+    assert_eq!(
+        irc.next_message().await,
+        "!setign <in-game name> - Set your in-game name"
+    );
+}
+
+#[tokio::test]
+async fn users_set_their_in_game_name_and_note() {
+    let mut irc = MockIrc::start("#chan").await;
+    let state = start_bot(&mut irc).await;
+    let too_long = "x".repeat(65);
+
+    irc.say("alice", "!setign Alice#123");
+    assert_eq!(irc.next_message().await, "alice is not in the queue");
+    irc.say("alice", &format!("!join {}", too_long));
+    assert_eq!(
+        irc.next_message().await,
+        "alice, your in-game name can be at most 64 characters."
+    );
+    assert_eq!(state.queue_length().await.unwrap(), 0);
+
+    irc.say("alice", "!join");
+    irc.next_message().await;
+    irc.say("alice", "!setign Alice#123");
+    assert_eq!(
+        irc.next_message().await,
+        "alice, your in-game name has been updated."
+    );
+    irc.say("alice", &format!("!note {}", too_long));
+    assert_eq!(
+        irc.next_message().await,
+        "alice, your note can be at most 64 characters."
+    );
+    irc.say("alice", "!note back in 5");
+    assert_eq!(
+        irc.next_message().await,
+        "alice, your note has been updated."
+    );
+
+    let entry = &state.peek(1).await.unwrap()[0];
+    assert_eq!(entry.ign.as_deref(), Some("Alice#123"));
+    assert_eq!(entry.note.as_deref(), Some("back in 5"));
+}
+
+#[tokio::test]
+async fn moderators_can_see_queue_stats() {
+    let mut irc = MockIrc::start("#chan").await;
+    let state = start_bot(&mut irc).await;
+
+    for user in &["alice", "bob", "alice"] {
+        irc.say(user, "!join");
+        irc.next_message().await;
+    }
+    state.pop(1).await.unwrap();
+
+    irc.say_as_moderator("mod", "!qstats");
+    let reply = irc.next_message().await;
+    assert!(reply.starts_with("2 joins from 2 users ("), "{}", reply);
+    assert!(reply.contains("Wait: 0 min average"), "{}", reply);
+    assert!(
+        reply.ends_with("0% left before their turn. Longest queue: 2."),
+        "{}",
+        reply
+    );
+}
+
+#[tokio::test]
+async fn answers_sent_while_disconnected_arrive_after_reconnecting() {
+    let mut irc = MockIrc::start("#chan").await;
+    let _state = start_bot(&mut irc).await;
+
+    // The answer is ready before the bot is back in the channel
+    irc.say("alice", "!join");
+    irc.disconnect();
+    irc.expect_join().await;
+
+    let reply = irc.next_message().await;
+    assert!(reply.starts_with("alice is #1."), "{}", reply);
+}
@@ -0,0 +1,193 @@
+//! A local stand-in for Twitch chat, speaking just enough IRC for the bot to log in, join its
+//! channel and talk. Tests script what viewers say and read back what the bot sends.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+
+/// How long tests wait for the bot before failing.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+enum Outgoing {
+    Line(String),
+    Close,
+}
+
+pub struct MockIrc {
+    addr: SocketAddr,
+    channel: String,
+    /// Every line the bot sent, across connections.
+    received: mpsc::UnboundedReceiver<String>,
+    /// Writes to the bot's current connection.
+    connection: Arc<Mutex<Option<mpsc::UnboundedSender<Outgoing>>>>,
+    next_id: usize,
+}
+
+impl MockIrc {
+    pub async fn start(channel: &str) -> MockIrc {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (received_tx, received) = mpsc::unbounded_channel();
+        let connection = Arc::new(Mutex::new(None));
+
+        let current = connection.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let (tx, rx) = mpsc::unbounded_channel();
+                *current.lock().unwrap() = Some(tx.clone());
+                tokio::spawn(serve(socket, tx, rx, received_tx.clone()));
+            }
+        });
+
+        MockIrc {
+            addr,
+            channel: channel.to_owned(),
+            received,
+            connection,
+            next_id: 0,
+        }
+    }
+
+    /// A config for connecting the bot to this server, without TLS.
+    pub fn config(&self, nickname: &str) -> irc::client::prelude::Config {
+        irc::client::prelude::Config {
+            nickname: Some(nickname.to_owned()),
+            password: Some("oauth:test".to_owned()),
+            server: Some(self.addr.ip().to_string()),
+            port: Some(self.addr.port()),
+            use_tls: Some(false),
+            channels: vec![self.channel.clone()],
+            ..Default::default()
+        }
+    }
+
+    fn write(&self, line: String) {
+        let connection = self.connection.lock().unwrap();
+        let connection = connection.as_ref().expect("the bot hasn't connected");
+        let _ = connection.send(Outgoing::Line(line));
+    }
+
+    /// Sends a chat message from `user`, with the tags Twitch adds.
+    pub fn say(&mut self, user: &str, text: &str) {
+        self.say_with_badges(user, "", text);
+    }
+
+    pub fn say_as_moderator(&mut self, user: &str, text: &str) {
+        self.say_with_badges(user, "moderator/1", text);
+    }
+
+    fn say_with_badges(&mut self, user: &str, badges: &str, text: &str) {
+        self.next_id += 1;
+        let moderator = if badges.starts_with("moderator") {
+            1
+        } else {
+            0
+        };
+        self.write(format!(
+            "@badges={};id=msg-{};mod={};user-id=id-{} :{user}!{user}@{user}.tmi.twitch.tv PRIVMSG {} :{}",
+            badges,
+            self.next_id,
+            moderator,
+            user,
+            self.channel,
+            text,
+            user = user
+        ));
+    }
+
+    /// Drops the bot's connection, as Twitch does now and then.
+    pub fn disconnect(&self) {
+        if let Some(connection) = self.connection.lock().unwrap().take() {
+            let _ = connection.send(Outgoing::Close);
+        }
+    }
+
+    /// The next line the bot sent starting with `prefix`, skipping others.
+    pub async fn expect(&mut self, prefix: &str) -> String {
+        let received = &mut self.received;
+        timeout(TIMEOUT, async {
+            loop {
+                let line = received.recv().await.expect("the server stopped");
+                if line.starts_with(prefix) {
+                    return line;
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("the bot never sent {:?}", prefix))
+    }
+
+    /// Waits for the bot to join its channel.
+    pub async fn expect_join(&mut self) {
+        let join = format!("JOIN {}", self.channel);
+        self.expect(&join).await;
+    }
+
+    /// The text of the next message the bot says in its channel.
+    pub async fn next_message(&mut self) -> String {
+        let privmsg = format!("PRIVMSG {} :", self.channel);
+        let line = self.expect(&privmsg).await;
+        line[privmsg.len()..].to_owned()
+    }
+}
+
+// One connection from the bot: answers the handshake, passes on what the bot says and writes
+// whatever the test sends
+async fn serve(
+    socket: TcpStream,
+    tx: mpsc::UnboundedSender<Outgoing>,
+    mut rx: mpsc::UnboundedReceiver<Outgoing>,
+    received: mpsc::UnboundedSender<String>,
+) {
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let line = match line {
+                    Ok(Some(line)) => line,
+                    _ => return,
+                };
+                if let Some(reply) = respond(&line) {
+                    let _ = tx.send(Outgoing::Line(reply));
+                }
+                let _ = received.send(line);
+            }
+            outgoing = rx.recv() => match outgoing {
+                Some(Outgoing::Line(line)) => {
+                    if writer.write_all(format!("{}\r\n", line).as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Outgoing::Close) | None => return,
+            },
+        }
+    }
+}
+
+/// What Twitch answers to a line from the bot.
+fn respond(line: &str) -> Option<String> {
+    let mut words = line.split(' ');
+    match words.next()? {
+        "NICK" => {
+            let nick = words.next()?;
+            Some(format!(
+                ":tmi.twitch.tv 001 {nick} :Welcome, GLHF!\r\n:tmi.twitch.tv 376 {nick} :>",
+                nick = nick
+            ))
+        }
+        "CAP" if words.next()? == "REQ" => {
+            let (_, caps) = line.split_once(':')?;
+            Some(format!(":tmi.twitch.tv CAP * ACK :{}", caps))
+        }
+        "JOIN" => {
+            let channel = words.next()?;
+            Some(format!(":bot!bot@bot.tmi.twitch.tv JOIN {}", channel))
+        }
+        "PING" => Some(format!("PONG {}", words.collect::<Vec<_>>().join(" "))),
+        _ => None,
+    }
+}